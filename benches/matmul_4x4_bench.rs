@@ -103,7 +103,7 @@ fn bench_matmul_4x4(c: &mut Criterion) {
         let mut result = vec![0.0; 4 * 4];
         bencher.iter(|| {
             let _ = unsafe {
-                black_box(ll_matmul_jit_with_template_entry.func.call(
+                black_box(ll_matmul_jit_with_template_entry.call(
                     black_box(a_col_major.as_ptr()),
                     black_box(b_col_major.as_ptr()),
                     black_box(result.as_mut_ptr()),
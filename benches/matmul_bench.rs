@@ -1,12 +1,24 @@
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::measurement::WallTime;
+use criterion::{
+    BenchmarkGroup, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main,
+};
+use faer::linalg::matmul::matmul;
 use faer::prelude::*;
+use faer::{Accum, Par};
+use llvm_intrinsic_with_rust::common::{
+    TILED_IR_TEMPLATE_JIT_CPU, UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU, UNROLLED_IR_TEMPLATE_JIT_CPU,
+};
 use llvm_intrinsic_with_rust::{
-    col_major_to_row_major, common::generate_random_matrix, compile_matmul_jit_with_template,
-    row_major_to_col_major,
+    BatchLayout, JitOptions, Specialization, col_major_to_row_major,
+    common::generate_random_matrix, compile_matmul_jit_with_template, ll_matmul_jit_batched,
+    ll_matmul_jit_into, ll_matmul_jit_into_with, ll_matmul_jit_with_options,
+    ll_matmul_jit_with_template, row_major_to_col_major,
 };
 use matrixmultiply::sgemm;
 use ndarray::Array2;
 use std::hint::black_box;
+use std::sync::Barrier;
+use std::time::Instant;
 
 #[cfg(feature = "gpu")]
 use llvm_intrinsic_with_rust::ll_matmul_gpu_compiled;
@@ -20,7 +32,7 @@ fn bench_matmul_small(c: &mut Criterion) {
     let n = 32;
     let k = 32;
 
-    let a_vec = generate_random_matrix(m, k, SEED);
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, SEED);
     let b_vec = generate_random_matrix(k, n, SEED);
 
     let a_ndarray = Array2::from_shape_vec((m, k), a_vec.clone()).unwrap();
@@ -78,7 +90,7 @@ fn bench_matmul_small(c: &mut Criterion) {
         let mut result = vec![0.0; m * n];
         bencher.iter(|| {
             let _ = unsafe {
-                black_box(ll_matmul_jit_with_template_entry.func.call(
+                black_box(ll_matmul_jit_with_template_entry.call(
                     black_box(a_col_major.as_ptr()),
                     black_box(b_col_major.as_ptr()),
                     black_box(result.as_mut_ptr()),
@@ -118,12 +130,250 @@ fn bench_matmul_small(c: &mut Criterion) {
     group.finish();
 }
 
+// many medium matmuls in a hot loop: the kernel is cached either way,
+// what differs is the per-call allocation and layout copies
+fn bench_matmul_into(c: &mut Criterion) {
+    let m = 32;
+    let n = 32;
+    let k = 32;
+
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, SEED);
+    let b_vec = generate_random_matrix(k, n, SEED);
+
+    let mut group = c.benchmark_group("matmul_into_32x32");
+
+    group.bench_function("ll_matmul_jit_with_template", |bencher| {
+        bencher.iter(|| {
+            let _ = black_box(unsafe {
+                ll_matmul_jit_with_template(
+                    black_box(&a_vec),
+                    (m, k),
+                    black_box(&b_vec),
+                    (k, n),
+                    None,
+                )
+            });
+        })
+    });
+
+    let mut result = vec![0.0f32; m * n];
+    group.bench_function("ll_matmul_jit_into", |bencher| {
+        bencher.iter(|| {
+            unsafe {
+                ll_matmul_jit_into(
+                    black_box(&a_vec),
+                    (m, k),
+                    black_box(&b_vec),
+                    (k, n),
+                    black_box(&mut result),
+                )
+            };
+        })
+    });
+
+    group.finish();
+}
+
+// what an unspecialized shape pays per call: both kernels are cached,
+// the shape-generic one loops over runtime bounds
+fn bench_matmul_dynamic(c: &mut Criterion) {
+    let m = 32;
+    let n = 32;
+    let k = 32;
+
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, SEED);
+    let b_vec = generate_random_matrix(k, n, SEED);
+    let mut result = vec![0.0f32; m * n];
+
+    let mut group = c.benchmark_group("matmul_dynamic_32x32");
+
+    for (name, policy) in [
+        ("specialized", Specialization::Always),
+        ("shape_generic", Specialization::Never),
+    ] {
+        group.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                unsafe {
+                    ll_matmul_jit_into_with(
+                        black_box(&a_vec),
+                        (m, k),
+                        black_box(&b_vec),
+                        (k, n),
+                        black_box(&mut result),
+                        policy,
+                    )
+                };
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// cache hits from 1..N threads on a tiny shape, where the lookup costs about as much as the kernel:
+// every thread multiplies its own 8x8 pair, throughput is calls over all threads
+fn bench_jit_cache_contention(c: &mut Criterion) {
+    let n = 8;
+    let a_vec: Vec<f32> = generate_random_matrix(n, n, SEED);
+    let b_vec = generate_random_matrix(n, n, SEED);
+    let max_threads = std::thread::available_parallelism().map_or(4, |p| p.get());
+
+    // compiled once, every iteration below is a hit
+    let mut result = vec![0.0f32; n * n];
+    unsafe { ll_matmul_jit_into(&a_vec, (n, n), &b_vec, (n, n), &mut result) };
+
+    let mut group = c.benchmark_group("jit_cache_contention_8x8");
+
+    let mut threads = 1;
+    while threads <= max_threads {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(
+            BenchmarkId::new("ll_matmul_jit_into", threads),
+            &threads,
+            |bencher, &threads| {
+                bencher.iter_custom(|iters| {
+                    let barrier = Barrier::new(threads);
+                    let elapsed = std::thread::scope(|scope| {
+                        let workers: Vec<_> = (0..threads)
+                            .map(|_| {
+                                let (a_vec, b_vec, barrier) = (&a_vec, &b_vec, &barrier);
+                                scope.spawn(move || {
+                                    let mut result = vec![0.0f32; n * n];
+                                    barrier.wait();
+                                    let start = Instant::now();
+                                    for _ in 0..iters {
+                                        unsafe {
+                                            ll_matmul_jit_into(
+                                                black_box(a_vec),
+                                                (n, n),
+                                                black_box(b_vec),
+                                                (n, n),
+                                                black_box(&mut result),
+                                            )
+                                        };
+                                    }
+                                    start.elapsed()
+                                })
+                            })
+                            .collect();
+                        workers.into_iter().map(|w| w.join().unwrap()).max()
+                    });
+                    elapsed.unwrap_or_default()
+                })
+            },
+        );
+        threads *= 2;
+    }
+
+    group.finish();
+}
+
+// many small same-shaped pairs: one call looping in the kernel vs. one call per pair,
+// throughput is per pair
+fn bench_matmul_batched(c: &mut Criterion) {
+    let m = 64;
+    let n = 64;
+    let k = 64;
+    let batch = 256;
+
+    let a_vec: Vec<f32> = generate_random_matrix(batch * m, k, SEED);
+    let b_vec = generate_random_matrix(batch * k, n, SEED);
+    let mut out = vec![0.0f32; batch * m * n];
+
+    let mut group = c.benchmark_group("matmul_batched_256x64x64");
+    group.throughput(Throughput::Elements(batch as u64));
+
+    group.bench_function("loop_ll_matmul_jit_with_template", |bencher| {
+        bencher.iter(|| {
+            for i in 0..batch {
+                let _ = black_box(unsafe {
+                    ll_matmul_jit_with_template(
+                        black_box(&a_vec[i * m * k..(i + 1) * m * k]),
+                        (m, k),
+                        black_box(&b_vec[i * k * n..(i + 1) * k * n]),
+                        (k, n),
+                        Some(UNROLLED_IR_TEMPLATE_JIT_CPU),
+                    )
+                });
+            }
+        })
+    });
+
+    group.bench_function("ll_matmul_jit_batched", |bencher| {
+        bencher.iter(|| {
+            unsafe {
+                ll_matmul_jit_batched(
+                    black_box(&a_vec),
+                    black_box(&b_vec),
+                    batch,
+                    ((m, k), (k, n)),
+                    black_box(&mut out),
+                    BatchLayout::Contiguous,
+                    Some(UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU),
+                )
+            };
+        })
+    });
+
+    group.finish();
+}
+
+/// The tiled JIT kernel on one thread and on all of them, against faer sequential and parallel.
+fn bench_threads(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    a_vec: &[f32],
+    b_vec: &[f32],
+    a_faer: &Mat<f32>,
+    b_faer: &Mat<f32>,
+    (m, n, k): (usize, usize, usize),
+) {
+    let options = JitOptions::new().template(TILED_IR_TEMPLATE_JIT_CPU);
+    for (name, options) in [
+        ("ll_matmul_jit_tiled_1_thread", options.clone().threads(1)),
+        ("ll_matmul_jit_tiled_threads", options),
+    ] {
+        group.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                let _ = black_box(unsafe {
+                    ll_matmul_jit_with_options(
+                        black_box(a_vec),
+                        (m, k),
+                        black_box(b_vec),
+                        (k, n),
+                        &options,
+                    )
+                });
+            })
+        });
+    }
+
+    let mut c_faer = Mat::<f32>::zeros(m, n);
+    for (name, par) in [
+        ("faer_matmul_seq", Par::Seq),
+        ("faer_matmul_par", Par::rayon(0)),
+    ] {
+        group.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                matmul(
+                    c_faer.as_mut(),
+                    Accum::Replace,
+                    black_box(a_faer).as_ref(),
+                    black_box(b_faer).as_ref(),
+                    1.0,
+                    par,
+                );
+                black_box(&c_faer);
+            })
+        });
+    }
+}
+
 fn bench_matmul_mid(c: &mut Criterion) {
     let m = 512;
     let n = 512;
     let k = 512;
 
-    let a_vec = generate_random_matrix(m, k, SEED);
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, SEED);
     let b_vec = generate_random_matrix(k, n, SEED);
 
     let a_ndarray = Array2::from_shape_vec((m, k), a_vec.clone()).unwrap();
@@ -169,6 +419,23 @@ fn bench_matmul_mid(c: &mut Criterion) {
         })
     });
 
+    // the naive template OOMs at this size, the tiled one compiles in bounded memory
+    group.bench_function("ll_matmul_jit_tiled", |bencher| {
+        bencher.iter(|| {
+            let _ = black_box(unsafe {
+                ll_matmul_jit_with_template(
+                    black_box(&a_vec),
+                    (m, k),
+                    black_box(&b_vec),
+                    (k, n),
+                    Some(TILED_IR_TEMPLATE_JIT_CPU),
+                )
+            });
+        })
+    });
+
+    bench_threads(&mut group, &a_vec, &b_vec, &a_faer, &b_faer, (m, n, k));
+
     #[cfg(feature = "gpu")]
     group.bench_function("ll_matmul_gpu_jit", |bencher| {
         bencher.iter(|| {
@@ -205,7 +472,7 @@ fn bench_matmul_big(c: &mut Criterion) {
     let n = 1024;
     let k = 1024;
 
-    let a_vec = generate_random_matrix(m, k, SEED);
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, SEED);
     let b_vec = generate_random_matrix(k, n, SEED);
 
     let a_ndarray = Array2::from_shape_vec((m, k), a_vec.clone()).unwrap();
@@ -252,6 +519,23 @@ fn bench_matmul_big(c: &mut Criterion) {
         })
     });
 
+    // the naive template OOMs at this size, the tiled one compiles in bounded memory
+    group.bench_function("ll_matmul_jit_tiled", |bencher| {
+        bencher.iter(|| {
+            let _ = black_box(unsafe {
+                ll_matmul_jit_with_template(
+                    black_box(&a_vec),
+                    (m, k),
+                    black_box(&b_vec),
+                    (k, n),
+                    Some(TILED_IR_TEMPLATE_JIT_CPU),
+                )
+            });
+        })
+    });
+
+    bench_threads(&mut group, &a_vec, &b_vec, &a_faer, &b_faer, (m, n, k));
+
     #[cfg(feature = "gpu")]
     group.bench_function("ll_matmul_gpu_jit", |bencher| {
         bencher.iter(|| {
@@ -286,6 +570,10 @@ fn bench_matmul_big(c: &mut Criterion) {
 criterion_group!(
     benches,
     bench_matmul_small,
+    bench_matmul_into,
+    bench_matmul_dynamic,
+    bench_jit_cache_contention,
+    bench_matmul_batched,
     bench_matmul_mid,
     bench_matmul_big
 );
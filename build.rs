@@ -1,220 +1,497 @@
-use std::env;
-use std::path::PathBuf;
-use std::process::Command;
-
-#[cfg(feature = "gpu")]
-fn getsmarch() -> String {
-    let output = Command::new("nvidia-smi")
-        .args(&["--query-gpu=compute_cap", "--format=csv,noheader"])
-        .output()
-        .expect("nvidia-smi failed");
-
-    let cap_str = String::from_utf8_lossy(&output.stdout);
-    let cap: Vec<&str> = cap_str.trim().split('.').collect();
-    format!("{}{}", cap[0], cap[1]) //75
-}
-
-#[cfg(feature = "gpu")]
-fn compile_llvm_ir_for_gpu(ll_file: &PathBuf, obj_file: &PathBuf) {
-    println!("cargo:rerun-if-changed=src/llvm/{}", ll_file.display());
-    let bc_file = obj_file.with_extension("bc");
-    let ptx_file = obj_file.with_extension("ptx");
-
-    //compile .ll to .bc
-    let mut llvm_as_command = Command::new("llvm-as");
-    llvm_as_command.arg(ll_file).arg("-o").arg(&bc_file);
-    let status = llvm_as_command
-        .status()
-        .expect("Failed to execute llvm-as. Make sure LLVM llvm-as is installed and in PATH.");
-    if !status.success() {
-        panic!("llvm-as failed to compile LLVM IR file: {:?}", ll_file);
-    }
-
-    // compile .bc to .ptx
-    // llc needs to have the capacity to target NVPTX (check llvm build flags)
-    let mut llc_command = Command::new("llc");
-    //eprintln!("=====> sm_arch is {}", getsmarch());
-    llc_command
-        .arg("-march=nvptx64")
-        .arg(format!("-mcpu=sm_{}", getsmarch()))
-        .arg("-o")
-        .arg(&ptx_file)
-        .arg(&bc_file);
-    let status = llc_command
-        .status()
-        .expect("Failed to execute llc. Make sure LLVM llc is installed and in PATH.");
-    if !status.success() {
-        panic!("llc failed to compile LLVM IR for GPU, file: {:?}", ll_file);
-    }
-
-    // compile .ptx to .bin (fatcubin)
-    // FIXME : --generate-code is not working, this is the way to force SASS (Streaming "mutliprocessor"? ASSembler)
-    // the FATBIN contains SASS for the current GPU, it is loaded directly.
-    // this is the most compiled form possible.
-    // check https://docs.nvidia.com/cuda/cuda-compiler-driver-nvcc/index.html
-    // for now we can check if the .fatbin have a SASS section.
-    // cuobjdump --dump-sass "target/debug/build/llvm-intrinsic-with-rust-*/out/matmul_for_gpu.fatbin"
-    let mut fatbin = Command::new("nvcc");
-    fatbin
-        //.arg(format!(
-        //     "--generate-code arch={}{},code={}{}",
-        //     "compute_",
-        //     getsmarch(),
-        //     "sm_",
-        //     getsmarch()
-        // ))
-        .arg("--fatbin")
-        .arg(&ptx_file)
-        .arg("-o")
-        .arg(&obj_file);
-    eprintln!("nvcc command: {:?}", fatbin);
-    let status = fatbin.status().expect("nvcc fatbin failed");
-    if !status.success() {
-        panic!("nvcc failed to generate fatbin for {:?}", ll_file);
-    }
-}
-fn compile_llvm_ir_for_cpu(ll_file: &PathBuf, obj_file: &PathBuf, is_debug: bool) {
-    println!("cargo:rerun-if-changed=src/llvm/{}", ll_file.display());
-
-    // .ll needs to be lowered (vectorized ?) by opt
-    // cause guess what, llc is a piece of junk !
-    let lowered_ll_file = obj_file.with_extension("lowered.ll");
-    let mut opt_command = Command::new("opt");
-    opt_command
-        .arg("-passes=lower-matrix-intrinsics")
-        .arg("-S")
-        .arg("-o")
-        .arg(&lowered_ll_file)
-        .arg(ll_file);
-    if is_debug {
-        opt_command.arg("--debug-entry-values");
-        opt_command.arg("-print-after=lower-matrix-intrinsics");
-    } else {
-        opt_command.arg("--thinlto-bc");
-    }
-
-    let status = opt_command
-        .status()
-        .expect("Failed to execute opt. Make sure LLVM opt is installed and in PATH.");
-
-    if !status.success() {
-        panic!(
-            "opt failed to lower matrix intrinsics in LLVM IR for CPU, file: {:?}",
-            ll_file
-        );
-    }
-
-    // fine, there, you have a IR that
-    // even my grandma can execute.
-    let mut llc_command = Command::new("llc");
-    llc_command
-        .arg("-mattr=+avx2,+fma")
-        .arg("-mcpu=native")
-        .arg("--relocation-model=pic")
-        .arg("-filetype=obj")
-        /* pass FP optimization flags (-fp-contract=fast and --enable-unsafe-fp-math) to llc.
-        without clear instruction to break ieee754 rules for fp rounding, llvm cannot optimize the fmul + fadd generated by the intrinsic into an FMA.
-           in simpler terms, without this, the generated code will be suboptimal, unrolled version will be 2x faster.
-         https://en.wikipedia.org/wiki/Multiply%E2%80%93accumulate_operation
-         https://llvm.org/docs/LangRef.html#llvm-fma-intrinsic
-         be aware of precision : irony is that you generally GAIN precision, not lose it even we are breaking ieee754 rules.
-         */
-        .arg("-fp-contract=fast")
-        /* this param have effect only on manualy generated/unrolled IR,
-            in other terms, it will not affect api call @llvm.matrix.column.major.load/store
-            unrolled(4x4)	Row (row-major)	        5.933 ns	inherent row-major access pattern and thus the generated code is faster.
-            unrolled(4x4)	Column (column-major)	16.393 ns	conflicts with the unrolled code with row-major access pattern.
-        */
-        .arg("--matrix-default-layout=row-major") // row-major
-        .arg("-o")
-        .arg(obj_file)
-        .arg(&lowered_ll_file);
-    if is_debug {
-        llc_command.arg("--asm-verbose");
-        llc_command.arg("--debug-entry-values");
-        llc_command.arg("--debugger-tune=gdb");
-        // TODO : switch all to row major
-        //llc_command.arg("--matrix-default-layout=row-major"); // row-major
-
-        //llc_command.arg("-print-asm-code");
-        //llc_command.arg("-time-passes");
-    } else {
-        llc_command.arg("-O3");
-    }
-
-    let status = llc_command
-        .status()
-        .expect("Failed to execute llc. Make sure LLVM llc is installed and in PATH.");
-
-    if !status.success() {
-        panic!("llc failed to compile LLVM IR for CPU, file: {:?}", ll_file);
-    }
-}
-
-fn main() {
-    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
-
-    let is_debug = env::var("PROFILE").unwrap_or_else(|_| "debug".to_string()) == "debug";
-
-    // matmul_4x4.ll file contains :
-    // - the llvm ir for the matmul with transpose operation
-    // - the llvm ir for the matmul with unrolled operation
-
-    // the transpose one need lowering with opt
-    // LLVM matrix intrinsics (like llvm.matrix.transpose, llvm.matrix.multiply, llvm.matrix.column.major.load/store)
-    // require lowering (i.e., transformation from high-level matrix intrinsic IR to something the backend
-    // can actually JIT/compile))
-
-    // the unrolled one I don't think it needs lowering
-    // FIXME: move unrolled one to a different file
-    // and avoid opting it (later for benchmark might have an impact)
-
-    let matmul_4x4_ll = manifest_dir.join("src/llvm/matmul_4x4.ll");
-    let matmul_4x4_obj = out_dir.join("matmul_4x4.o");
-    compile_llvm_ir_for_cpu(&matmul_4x4_ll, &matmul_4x4_obj, is_debug);
-
-    // link with all .o files
-    println!("cargo:rustc-link-arg={}", matmul_4x4_obj.display());
-
-    #[cfg(feature = "gpu")]
-    {
-        let matmul_file = manifest_dir.join("src/llvm/gpu/matmul_for_gpu.ll");
-        let matmul_fatbin = out_dir.join("matmul_for_gpu.fatbin");
-        compile_llvm_ir_for_gpu(&matmul_file, &matmul_fatbin);
-        // Link against CUDA Driver API (libcuda.so)
-        // Check common CUDA installation paths
-        let common_cuda_lib_paths = vec![
-            //"/usr/local/cuda/lib64",
-            //"/usr/lib/x86_64-linux-gnu",
-            //"/usr/lib64",
-            //"/opt/cuda/lib64",
-            "",
-        ];
-
-        for path in common_cuda_lib_paths {
-            if std::path::Path::new(path).exists() {
-                println!("cargo:rustc-link-search=native={}", path);
-            }
-        }
-
-        // Also check CUDA_PATH environment variable if set
-        if let Ok(cuda_path) = env::var("CUDA_PATH") {
-            for path in [
-                format!("{}", cuda_path),
-                format!("{}/lib64", cuda_path),
-                format!("{}/lib", cuda_path),
-            ] {
-                if std::path::Path::new(&path).exists() {
-                    println!("cargo:rustc-link-search=native={}", path);
-                }
-            }
-        }
-
-        // link with all .o files
-        //println!("cargo:rustc-link-arg={}", matmul_fatbin.display());
-
-        // Tell cargo to link against libcuda
-        println!("cargo:rustc-link-lib=dylib=cuda");
-    }
-}
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// the same instantiation code the JIT runs
+#[allow(dead_code)]
+#[path = "src/llvm/template.rs"]
+mod template;
+use template::{
+    Bindings, IrType, MATMUL_ABI, NAIVE_TEMPLATE_MAX_DIM, fnv1a, instantiate, parse_header,
+};
+
+const AOT_MANIFEST_ENV: &str = "LL_MATMUL_AOT_MANIFEST";
+const DEFAULT_AOT_MANIFEST: &str = "aot_kernels.txt";
+// entry point of the matmul templates without an `entry:` header (DEFAULT_FUNCTION_NAME_JIT_CPU),
+// renamed per kernel so they can be linked together
+const TEMPLATE_FUNCTION_NAME: &str = "ll_matmul_cpu_jit";
+// same as TARGET_CPU_ENV / TARGET_FEATURES_ENV in src/common.rs
+const TARGET_CPU_ENV: &str = "LL_MATMUL_TARGET_CPU";
+const TARGET_FEATURES_ENV: &str = "LL_MATMUL_TARGET_FEATURES";
+
+#[cfg(feature = "gpu")]
+fn getsmarch() -> String {
+    let output = Command::new("nvidia-smi")
+        .args(&["--query-gpu=compute_cap", "--format=csv,noheader"])
+        .output()
+        .expect("nvidia-smi failed");
+
+    let cap_str = String::from_utf8_lossy(&output.stdout);
+    let cap: Vec<&str> = cap_str.trim().split('.').collect();
+    format!("{}{}", cap[0], cap[1]) //75
+}
+
+#[cfg(feature = "gpu")]
+fn compile_llvm_ir_for_gpu(ll_file: &PathBuf, obj_file: &PathBuf) {
+    println!("cargo:rerun-if-changed=src/llvm/{}", ll_file.display());
+    let bc_file = obj_file.with_extension("bc");
+    let ptx_file = obj_file.with_extension("ptx");
+
+    //compile .ll to .bc
+    let mut llvm_as_command = Command::new("llvm-as");
+    llvm_as_command.arg(ll_file).arg("-o").arg(&bc_file);
+    let status = llvm_as_command
+        .status()
+        .expect("Failed to execute llvm-as. Make sure LLVM llvm-as is installed and in PATH.");
+    if !status.success() {
+        panic!("llvm-as failed to compile LLVM IR file: {:?}", ll_file);
+    }
+
+    // compile .bc to .ptx
+    // llc needs to have the capacity to target NVPTX (check llvm build flags)
+    let mut llc_command = Command::new("llc");
+    //eprintln!("=====> sm_arch is {}", getsmarch());
+    llc_command
+        .arg("-march=nvptx64")
+        .arg(format!("-mcpu=sm_{}", getsmarch()))
+        .arg("-o")
+        .arg(&ptx_file)
+        .arg(&bc_file);
+    let status = llc_command
+        .status()
+        .expect("Failed to execute llc. Make sure LLVM llc is installed and in PATH.");
+    if !status.success() {
+        panic!("llc failed to compile LLVM IR for GPU, file: {:?}", ll_file);
+    }
+
+    // compile .ptx to .bin (fatcubin)
+    // FIXME : --generate-code is not working, this is the way to force SASS (Streaming "mutliprocessor"? ASSembler)
+    // the FATBIN contains SASS for the current GPU, it is loaded directly.
+    // this is the most compiled form possible.
+    // check https://docs.nvidia.com/cuda/cuda-compiler-driver-nvcc/index.html
+    // for now we can check if the .fatbin have a SASS section.
+    // cuobjdump --dump-sass "target/debug/build/llvm-intrinsic-with-rust-*/out/matmul_for_gpu.fatbin"
+    let mut fatbin = Command::new("nvcc");
+    fatbin
+        //.arg(format!(
+        //     "--generate-code arch={}{},code={}{}",
+        //     "compute_",
+        //     getsmarch(),
+        //     "sm_",
+        //     getsmarch()
+        // ))
+        .arg("--fatbin")
+        .arg(&ptx_file)
+        .arg("-o")
+        .arg(&obj_file);
+    eprintln!("nvcc command: {:?}", fatbin);
+    let status = fatbin.status().expect("nvcc fatbin failed");
+    if !status.success() {
+        panic!("nvcc failed to generate fatbin for {:?}", ll_file);
+    }
+}
+/// `-mcpu` and `-mattr` of llc: the env vars, else what rustc compiles the crate for
+/// (`-C target-cpu` / `-C target-feature` in RUSTFLAGS). Nothing given means the host
+/// CPU (`native`, llc detects its features) for a native build, llc's generic CPU for
+/// a cross build.
+fn llc_target() -> (Option<String>, Option<String>) {
+    let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let mut flags = rustflags.split('\x1f');
+    let mut cpu = None;
+    let mut features = Vec::new();
+    while let Some(flag) = flags.next() {
+        // `-C target-cpu=x`, `-Ctarget-cpu=x`
+        let codegen = match flag.strip_prefix("-C") {
+            Some("") => flags.next().unwrap_or_default(),
+            Some(codegen) => codegen,
+            None => continue,
+        };
+        if let Some(value) = codegen.strip_prefix("target-cpu=") {
+            cpu = Some(value.to_string());
+        } else if let Some(value) = codegen.strip_prefix("target-feature=") {
+            features.push(value.to_string());
+        }
+    }
+    let features = (!features.is_empty()).then(|| features.join(","));
+    let native = env::var("HOST").ok() == env::var("TARGET").ok();
+    let cpu = env::var(TARGET_CPU_ENV).ok().or(cpu);
+    let features = env::var(TARGET_FEATURES_ENV).ok().or(features);
+    // `native` would add the host's features on top of the ones asked for
+    let cpu = match (cpu, &features) {
+        (None, None) if native => Some("native".to_string()),
+        (cpu, _) => cpu,
+    };
+    (cpu, features)
+}
+
+fn compile_llvm_ir_for_cpu(ll_file: &PathBuf, obj_file: &PathBuf, is_debug: bool) {
+    println!("cargo:rerun-if-changed=src/llvm/{}", ll_file.display());
+
+    // .ll needs to be lowered (vectorized ?) by opt
+    // cause guess what, llc is a piece of junk !
+    let lowered_ll_file = obj_file.with_extension("lowered.ll");
+    let mut opt_command = Command::new("opt");
+    opt_command
+        .arg("-passes=lower-matrix-intrinsics")
+        .arg("-S")
+        .arg("-o")
+        .arg(&lowered_ll_file)
+        .arg(ll_file);
+    if is_debug {
+        opt_command.arg("--debug-entry-values");
+        opt_command.arg("-print-after=lower-matrix-intrinsics");
+    } else {
+        opt_command.arg("--thinlto-bc");
+    }
+
+    let status = opt_command
+        .status()
+        .expect("Failed to execute opt. Make sure LLVM opt is installed and in PATH.");
+
+    if !status.success() {
+        panic!(
+            "opt failed to lower matrix intrinsics in LLVM IR for CPU, file: {:?}",
+            ll_file
+        );
+    }
+
+    // fine, there, you have a IR that
+    // even my grandma can execute.
+    let mut llc_command = Command::new("llc");
+    let (cpu, features) = llc_target();
+    if let Some(cpu) = cpu {
+        llc_command.arg(format!("-mcpu={}", cpu));
+    }
+    if let Some(features) = features {
+        llc_command.arg(format!("-mattr={}", features));
+    }
+    llc_command
+        .arg("--relocation-model=pic")
+        .arg("-filetype=obj")
+        /* pass FP optimization flags (-fp-contract=fast and --enable-unsafe-fp-math) to llc.
+        without clear instruction to break ieee754 rules for fp rounding, llvm cannot optimize the fmul + fadd generated by the intrinsic into an FMA.
+           in simpler terms, without this, the generated code will be suboptimal, unrolled version will be 2x faster.
+         https://en.wikipedia.org/wiki/Multiply%E2%80%93accumulate_operation
+         https://llvm.org/docs/LangRef.html#llvm-fma-intrinsic
+         be aware of precision : irony is that you generally GAIN precision, not lose it even we are breaking ieee754 rules.
+         */
+        .arg("-fp-contract=fast")
+        /* this param have effect only on manualy generated/unrolled IR,
+            in other terms, it will not affect api call @llvm.matrix.column.major.load/store
+            unrolled(4x4)	Row (row-major)	        5.933 ns	inherent row-major access pattern and thus the generated code is faster.
+            unrolled(4x4)	Column (column-major)	16.393 ns	conflicts with the unrolled code with row-major access pattern.
+        */
+        .arg("--matrix-default-layout=row-major") // row-major
+        .arg("-o")
+        .arg(obj_file)
+        .arg(&lowered_ll_file);
+    if is_debug {
+        llc_command.arg("--asm-verbose");
+        llc_command.arg("--debug-entry-values");
+        llc_command.arg("--debugger-tune=gdb");
+        // TODO : switch all to row major
+        //llc_command.arg("--matrix-default-layout=row-major"); // row-major
+
+        //llc_command.arg("-print-asm-code");
+        //llc_command.arg("-time-passes");
+    } else {
+        llc_command.arg("-O3");
+    }
+
+    let status = llc_command
+        .status()
+        .expect("Failed to execute llc. Make sure LLVM llc is installed and in PATH.");
+
+    if !status.success() {
+        panic!("llc failed to compile LLVM IR for CPU, file: {:?}", ll_file);
+    }
+}
+
+/// A kernel of the AOT manifest: `m k n elem [template]`.
+struct AotKernel {
+    line: usize,
+    shape: (usize, usize, usize),
+    elem: IrType,
+    rust_ty: &'static str,
+    template_path: PathBuf,
+}
+
+fn bad_manifest_line(manifest: &Path, line: usize, reason: &str) -> ! {
+    panic!(
+        "{}:{}: {} (expected `m k n elem [template]`)",
+        manifest.display(),
+        line,
+        reason
+    )
+}
+
+// one kernel per line, `#` starts a comment
+fn read_aot_manifest(manifest: &Path, manifest_dir: &Path) -> Vec<AotKernel> {
+    let content = fs::read_to_string(manifest)
+        .unwrap_or_else(|e| panic!("Failed to read AOT manifest {:?}: {}", manifest, e));
+    let mut kernels = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line_no = i + 1;
+        let fields: Vec<&str> = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() < 4 || fields.len() > 5 {
+            bad_manifest_line(manifest, line_no, "wrong number of fields");
+        }
+        let dim = |f: &str| match f.parse::<usize>() {
+            Ok(d) if d > 0 => d,
+            _ => bad_manifest_line(manifest, line_no, &format!("`{}` isn't a dimension", f)),
+        };
+        let (m, k, n) = (dim(fields[0]), dim(fields[1]), dim(fields[2]));
+        let (elem, rust_ty) = match fields[3] {
+            "f32" => (
+                IrType {
+                    ty: "float",
+                    suffix: "f32",
+                    bytes: 4,
+                },
+                "f32",
+            ),
+            "f64" => (
+                IrType {
+                    ty: "double",
+                    suffix: "f64",
+                    bytes: 8,
+                },
+                "f64",
+            ),
+            other => bad_manifest_line(
+                manifest,
+                line_no,
+                &format!("unsupported element type `{}`", other),
+            ),
+        };
+        // same default as the JIT: naive up to NAIVE_TEMPLATE_MAX_DIM, tiled past it
+        let template_path = match fields.get(4) {
+            Some(path) => manifest_dir.join(path),
+            None if m.max(n).max(k) > NAIVE_TEMPLATE_MAX_DIM => {
+                manifest_dir.join("src/llvm/matmul_tiled.tmpl")
+            }
+            None => manifest_dir.join("src/llvm/matmul_intrinsic_naive.tmpl"),
+        };
+        kernels.push(AotKernel {
+            line: line_no,
+            shape: (m, n, k),
+            elem,
+            rust_ty,
+            template_path,
+        });
+    }
+    kernels
+}
+
+// instantiates, lowers and compiles each kernel, returns the objects to link
+// and writes the dispatch table `src/llvm/compiled.rs` includes
+fn compile_aot_kernels(kernels: &[AotKernel], out_dir: &Path, is_debug: bool) -> Vec<PathBuf> {
+    let mut objects = Vec::new();
+    let mut externs = String::new();
+    let mut tables = [("f32", String::new()), ("f64", String::new())];
+    let mut symbols = HashSet::new();
+
+    for kernel in kernels {
+        println!("cargo:rerun-if-changed={}", kernel.template_path.display());
+        let template = fs::read_to_string(&kernel.template_path).unwrap_or_else(|e| {
+            panic!(
+                "AOT kernel line {}: failed to read {:?}: {}",
+                kernel.line, kernel.template_path, e
+            )
+        });
+        // the dispatch matches on the template too, so a shape can have one kernel per template
+        let template_hash = fnv1a(template.as_bytes());
+        let (m, n, k) = kernel.shape;
+        let symbol = format!(
+            "ll_matmul_aot_{}_{}x{}x{}_{:08x}",
+            kernel.rust_ty, m, k, n, template_hash as u32
+        );
+        if !symbols.insert(symbol.clone()) {
+            panic!("AOT kernel line {}: duplicate kernel", kernel.line);
+        }
+
+        let template_error = |e: template::TemplateError| -> ! {
+            panic!(
+                "AOT kernel line {}: {:?}: {}",
+                kernel.line, kernel.template_path, e
+            )
+        };
+        let header = parse_header(&template).unwrap_or_else(|e| template_error(e));
+        if !header.supports_abi(MATMUL_ABI) || !header.supports_elem(kernel.elem.ty) {
+            panic!(
+                "AOT kernel line {}: {:?} isn't a {} matmul template",
+                kernel.line, kernel.template_path, kernel.rust_ty
+            );
+        }
+        // row major in and out, like the JIT: C^T(n×m) = B^T(n×k) * A^T(k×m)
+        let bindings = Bindings::plain((n, m, k), kernel.elem);
+        let instantiated = instantiate(&template, &bindings).unwrap_or_else(|e| template_error(e));
+        // no fallback here, the manifest asks for this very kernel
+        if let Some(requirement) = header
+            .unmet(&bindings)
+            .unwrap_or_else(|e| template_error(e))
+        {
+            panic!(
+                "AOT kernel line {}: {:?} requires {}",
+                kernel.line, kernel.template_path, requirement
+            );
+        }
+        let unused = instantiated.unused(&["M", "N", "K"]);
+        if !unused.is_empty() {
+            panic!(
+                "AOT kernel line {}: {:?} never uses the {:?} placeholder(s)",
+                kernel.line, kernel.template_path, unused
+            );
+        }
+        if kernel.rust_ty != "f32" && !instantiated.uses("ELEM_TY") {
+            panic!(
+                "AOT kernel line {}: {:?} has no {{ELEM_TY}} placeholder, it can't be compiled for {}",
+                kernel.line, kernel.template_path, kernel.rust_ty
+            );
+        }
+        let entry = header.entry.as_deref().unwrap_or(TEMPLATE_FUNCTION_NAME);
+        let ir = instantiated
+            .ir
+            .replace(&format!("@{}(", entry), &format!("@{}(", symbol));
+        if !ir.contains(&format!("@{}(", symbol)) {
+            panic!(
+                "AOT kernel line {}: {:?} doesn't define @{}",
+                kernel.line, kernel.template_path, entry
+            );
+        }
+
+        let ll_file = out_dir.join(format!("{}.ll", symbol));
+        let obj_file = out_dir.join(format!("{}.o", symbol));
+        fs::write(&ll_file, ir).expect("Failed to write the instantiated AOT kernel");
+        compile_llvm_ir_for_cpu(&ll_file, &obj_file, is_debug);
+        objects.push(obj_file);
+
+        let ty = kernel.rust_ty;
+        writeln!(
+            externs,
+            "    fn {symbol}(a: *const {ty}, b: *const {ty}, result: *mut {ty});"
+        )
+        .unwrap();
+        let table = &mut tables.iter_mut().find(|(t, _)| *t == ty).unwrap().1;
+        writeln!(
+            table,
+            "    AotKernel {{ shape: ({m}, {n}, {k}), template_hash: {template_hash:#018x}, align: {align}, func: {symbol} }},",
+            align = header.align.unwrap_or(1)
+        )
+        .unwrap();
+    }
+
+    let mut dispatch = String::from("// generated by build.rs from the AOT manifest\n");
+    writeln!(dispatch, "unsafe extern \"C\" {{\n{}}}", externs).unwrap();
+    for (ty, table) in &tables {
+        writeln!(
+            dispatch,
+            "static AOT_KERNELS_{}: &[AotKernel<{}>] = &[\n{}];",
+            ty.to_uppercase(),
+            ty,
+            table
+        )
+        .unwrap();
+    }
+    fs::write(out_dir.join("aot_kernels.rs"), dispatch)
+        .expect("Failed to write the AOT dispatch table");
+    objects
+}
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    let is_debug = env::var("PROFILE").unwrap_or_else(|_| "debug".to_string()) == "debug";
+
+    // matmul_4x4.ll file contains :
+    // - the llvm ir for the matmul with transpose operation
+    // - the llvm ir for the matmul with unrolled operation
+    // - the llvm ir for the f64 matmul with transpose operation
+
+    // the transpose one need lowering with opt
+    // LLVM matrix intrinsics (like llvm.matrix.transpose, llvm.matrix.multiply, llvm.matrix.column.major.load/store)
+    // require lowering (i.e., transformation from high-level matrix intrinsic IR to something the backend
+    // can actually JIT/compile))
+
+    // the unrolled one I don't think it needs lowering
+    // FIXME: move unrolled one to a different file
+    // and avoid opting it (later for benchmark might have an impact)
+
+    let matmul_4x4_ll = manifest_dir.join("src/llvm/matmul_4x4.ll");
+    let matmul_4x4_obj = out_dir.join("matmul_4x4.o");
+    compile_llvm_ir_for_cpu(&matmul_4x4_ll, &matmul_4x4_obj, is_debug);
+
+    // kernels listed in the AOT manifest, none if there's no manifest
+    println!("cargo:rerun-if-env-changed={}", AOT_MANIFEST_ENV);
+    println!("cargo:rerun-if-env-changed={}", TARGET_CPU_ENV);
+    println!("cargo:rerun-if-env-changed={}", TARGET_FEATURES_ENV);
+    let manifest = env::var_os(AOT_MANIFEST_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| manifest_dir.join(DEFAULT_AOT_MANIFEST));
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    let aot_kernels = if manifest.exists() {
+        read_aot_manifest(&manifest, &manifest_dir)
+    } else {
+        Vec::new()
+    };
+    let aot_objects = compile_aot_kernels(&aot_kernels, &out_dir, is_debug);
+
+    // link with all .o files
+    println!("cargo:rustc-link-arg={}", matmul_4x4_obj.display());
+    for obj in &aot_objects {
+        println!("cargo:rustc-link-arg={}", obj.display());
+    }
+
+    #[cfg(feature = "gpu")]
+    {
+        let matmul_file = manifest_dir.join("src/llvm/gpu/matmul_for_gpu.ll");
+        let matmul_fatbin = out_dir.join("matmul_for_gpu.fatbin");
+        compile_llvm_ir_for_gpu(&matmul_file, &matmul_fatbin);
+        // Link against CUDA Driver API (libcuda.so)
+        // Check common CUDA installation paths
+        let common_cuda_lib_paths = vec![
+            //"/usr/local/cuda/lib64",
+            //"/usr/lib/x86_64-linux-gnu",
+            //"/usr/lib64",
+            //"/opt/cuda/lib64",
+            "",
+        ];
+
+        for path in common_cuda_lib_paths {
+            if std::path::Path::new(path).exists() {
+                println!("cargo:rustc-link-search=native={}", path);
+            }
+        }
+
+        // Also check CUDA_PATH environment variable if set
+        if let Ok(cuda_path) = env::var("CUDA_PATH") {
+            for path in [
+                format!("{}", cuda_path),
+                format!("{}/lib64", cuda_path),
+                format!("{}/lib", cuda_path),
+            ] {
+                if std::path::Path::new(&path).exists() {
+                    println!("cargo:rustc-link-search=native={}", path);
+                }
+            }
+        }
+
+        // link with all .o files
+        //println!("cargo:rustc-link-arg={}", matmul_fatbin.display());
+
+        // Tell cargo to link against libcuda
+        println!("cargo:rustc-link-lib=dylib=cuda");
+    }
+}
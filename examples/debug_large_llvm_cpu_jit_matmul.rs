@@ -1,13 +1,16 @@
 use llvm_intrinsic_with_rust::{
-    common::{assert_vec_eq, generate_random_matrix, native_matmul},
+    common::{
+        DEFAULT_IR_TEMPLATE_JIT_CPU, NAIVE_TEMPLATE_MAX_DIM, assert_vec_eq, generate_random_matrix,
+        native_matmul,
+    },
     ll_matmul_jit_with_template,
 };
 use std::time::Instant;
 
-fn run_matmul(m: usize, n: usize, k: usize) {
+fn run_matmul(m: usize, n: usize, k: usize, ir_template: Option<&str>) {
     println!("Running {}x{} * {}x{} matmul...", m, k, k, n);
     let seed = 42;
-    let a_vec = generate_random_matrix(m, k, seed);
+    let a_vec: Vec<f32> = generate_random_matrix(m, k, seed);
     let b_vec = generate_random_matrix(k, n, seed);
 
     let start = Instant::now();
@@ -17,16 +20,15 @@ fn run_matmul(m: usize, n: usize, k: usize) {
             (m, k),
             &b_vec,
             (k, n),
-            None, /* N.B: using default ir template, size matters, see comments*/
+            ir_template, /* N.B: the naive ir template, size matters, see comments*/
         )
     };
     let duration = start.elapsed();
     assert!(result.len() == m * n);
-    assert_vec_eq(
-        &result,
-        &native_matmul(&a_vec, (m, k), &b_vec, (k, n)),
-        0.0001,
-    );
+    let expected = native_matmul(&a_vec, (m, k), &b_vec, (k, n));
+    // the sums grow with k, so does their rounding error
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    assert_vec_eq(&result, &expected, max * 1e-5);
     println!("Completed in {:?}", duration);
     println!(
         "Result sample (first 10 elements): {:?}",
@@ -41,12 +43,15 @@ fn run_matmul(m: usize, n: usize, k: usize) {
 // and lead to oom
 // so we need to have a threshold
 // up to you to check which value is best for you
-const THRESHOLD: usize = 32;
+// past it the default template is the tiled one (loop nest over small tiles),
+// pass `naive` as second argument to force the naive template anyway
+const THRESHOLD: usize = NAIVE_TEMPLATE_MAX_DIM;
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    let naive = args.get(2).is_some_and(|a| a == "naive");
     let size: usize = if args.len() > 1 {
         let size = args[1].parse().expect("Invalid size argument");
-        if size > THRESHOLD {
+        if size > THRESHOLD && naive {
             eprintln!(
                 "\n ===> Danger zone: size might cause OOM (check comments above) (threshold: {}) <=== \n",
                 THRESHOLD
@@ -60,7 +65,8 @@ fn main() {
 
     println!("Starting debug_large_matmul example with size {}", size);
 
-    run_matmul(size, size, size);
+    let ir_template = naive.then_some(DEFAULT_IR_TEMPLATE_JIT_CPU);
+    run_matmul(size, size, size, ir_template);
 
     println!("Finished all matrix multiplications");
 }
@@ -1,12 +1,50 @@
 use rand::Rng;
 use rand::SeedableRng;
 use rand::rngs::StdRng;
+use std::fmt::{Debug, Display};
+use std::ops::{Add, Mul};
 
 pub const DEFAULT_IR_4X4_CPU: &str = include_str!("llvm/matmul_4x4.ll");
 pub const TEMPLATE_JIT_CPU_ENV: &str = "LL_MATMUL_TEMPLATE";
 pub const TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME: &str = "LL_MATMUL_TEMPLATE_FUNCTION_NAME";
+/// Directory lowered kernels are persisted to across runs, unset = no disk cache.
+pub const JIT_CACHE_DIR_ENV: &str = "LL_MATMUL_CACHE_DIR";
+/// LLVM CPU name / feature string of the kernels instead of the host's, see `JitOptions`.
+/// build.rs reads them too, for the kernels it compiles.
+pub const TARGET_CPU_ENV: &str = "LL_MATMUL_TARGET_CPU";
+pub const TARGET_FEATURES_ENV: &str = "LL_MATMUL_TARGET_FEATURES";
 pub const DEFAULT_IR_TEMPLATE_JIT_CPU: &str = include_str!("llvm/matmul_intrinsic_naive.tmpl");
+pub const UNROLLED_IR_TEMPLATE_JIT_CPU: &str = include_str!("llvm/matmul_unrolled.tmpl");
+pub const TILED_IR_TEMPLATE_JIT_CPU: &str = include_str!("llvm/matmul_tiled.tmpl");
+// shared with build.rs, see src/llvm/template.rs
+pub use crate::llvm::template::NAIVE_TEMPLATE_MAX_DIM;
 pub const DEFAULT_FUNCTION_NAME_JIT_CPU: &str = "ll_matmul_cpu_jit";
+pub const TEMPLATE_GEMM_JIT_CPU_ENV: &str = "LL_GEMM_TEMPLATE";
+pub const TEMPLATE_GEMM_JIT_CPU_ENV_FUNCTION_NAME: &str = "LL_GEMM_TEMPLATE_FUNCTION_NAME";
+pub const DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU: &str = include_str!("llvm/gemm_intrinsic_naive.tmpl");
+pub const UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU: &str = include_str!("llvm/gemm_unrolled.tmpl");
+pub const DEFAULT_FUNCTION_NAME_GEMM_JIT_CPU: &str = "ll_gemm_cpu_jit";
+pub const TEMPLATE_HALF_JIT_CPU_ENV: &str = "LL_HALF_MATMUL_TEMPLATE";
+pub const TEMPLATE_HALF_JIT_CPU_ENV_FUNCTION_NAME: &str = "LL_HALF_MATMUL_TEMPLATE_FUNCTION_NAME";
+pub const DEFAULT_IR_TEMPLATE_HALF_JIT_CPU: &str = include_str!("llvm/matmul_half_naive.tmpl");
+pub const UNROLLED_IR_TEMPLATE_HALF_JIT_CPU: &str = include_str!("llvm/matmul_half_unrolled.tmpl");
+pub const DEFAULT_FUNCTION_NAME_HALF_JIT_CPU: &str = "ll_matmul_half_cpu_jit";
+pub const TEMPLATE_INT_JIT_CPU_ENV: &str = "LL_INT_MATMUL_TEMPLATE";
+pub const TEMPLATE_INT_JIT_CPU_ENV_FUNCTION_NAME: &str = "LL_INT_MATMUL_TEMPLATE_FUNCTION_NAME";
+pub const DEFAULT_IR_TEMPLATE_INT_JIT_CPU: &str = include_str!("llvm/matmul_int_naive.tmpl");
+pub const DEFAULT_FUNCTION_NAME_INT_JIT_CPU: &str = "ll_matmul_int_cpu_jit";
+pub const TEMPLATE_BATCHED_JIT_CPU_ENV: &str = "LL_BATCHED_MATMUL_TEMPLATE";
+pub const TEMPLATE_BATCHED_JIT_CPU_ENV_FUNCTION_NAME: &str =
+    "LL_BATCHED_MATMUL_TEMPLATE_FUNCTION_NAME";
+pub const DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU: &str =
+    include_str!("llvm/matmul_batched_naive.tmpl");
+pub const UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU: &str =
+    include_str!("llvm/matmul_batched_unrolled.tmpl");
+pub const DEFAULT_FUNCTION_NAME_BATCHED_JIT_CPU: &str = "ll_matmul_batched_cpu_jit";
+pub const TEMPLATE_DYN_JIT_CPU_ENV: &str = "LL_DYN_MATMUL_TEMPLATE";
+pub const TEMPLATE_DYN_JIT_CPU_ENV_FUNCTION_NAME: &str = "LL_DYN_MATMUL_TEMPLATE_FUNCTION_NAME";
+pub const DEFAULT_IR_TEMPLATE_DYN_JIT_CPU: &str = include_str!("llvm/matmul_dynamic.tmpl");
+pub const DEFAULT_FUNCTION_NAME_DYN_JIT_CPU: &str = "ll_matmul_dyn_cpu_jit";
 pub const DEFAULT_IR_TEMPLATE_GPU: &str = include_str!("llvm/gpu/matmul_for_gpu.ll");
 pub const DEFAULT_FUNCTION_NAME_GPU: &str = "ll_matmul_gpu";
 #[cfg(feature = "gpu")]
@@ -19,30 +57,241 @@ pub static GPU_PTX_PAYLOAD: &str = include_str!(concat!(env!("OUT_DIR"), "/matmu
 #[cfg(feature = "gpu")]
 const _: () = assert!(GPU_PTX_PAYLOAD.len() > 0);
 
-pub fn generate_random_matrix(rows: usize, cols: usize, seed: u64) -> Vec<f32> {
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for f32 {}
+    impl Sealed for f64 {}
+    impl Sealed for u16 {}
+    impl Sealed for i8 {}
+    impl Sealed for u8 {}
+    impl Sealed for i32 {}
+    impl Sealed for i64 {}
+}
+
+/// Element types the kernels can be instantiated for.
+///
+/// Sealed: a new element type needs templates (and AOT kernels) that handle it,
+/// so it can't be added from outside the crate.
+pub trait Scalar:
+    sealed::Sealed
+    + Copy
+    + Default
+    + PartialEq
+    + Debug
+    + Display
+    + Add<Output = Self>
+    + Mul<Output = Self>
+    + Send
+    + Sync
+    + 'static
+{
+    /// LLVM IR type, substituted for `{ELEM_TY}`.
+    const IR_TYPE: &'static str;
+    /// Intrinsic name suffix (`llvm.fmuladd.v8f32`), substituted for `{ELEM_SUFFIX}`.
+    const IR_SUFFIX: &'static str;
+    /// Size in bytes, substituted for `{ELEM_BYTES}` (scalar alignment).
+    const BYTES: usize;
+    const ZERO: Self;
+
+    fn from_f64(v: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Scalar for f32 {
+    const IR_TYPE: &'static str = "float";
+    const IR_SUFFIX: &'static str = "f32";
+    const BYTES: usize = 4;
+    const ZERO: Self = 0.0;
+
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Scalar for f64 {
+    const IR_TYPE: &'static str = "double";
+    const IR_SUFFIX: &'static str = "f64";
+    const BYTES: usize = 8;
+    const ZERO: Self = 0.0;
+
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Storage format of the `u16` bit patterns taken by the half-precision kernels.
+/// Either way the products are accumulated in `f32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HalfFormat {
+    /// IEEE 754 binary16 (`half` in LLVM IR).
+    F16,
+    /// bfloat16, the upper half of an `f32` (`bfloat` in LLVM IR).
+    Bf16,
+}
+
+impl HalfFormat {
+    /// LLVM IR type, substituted for `{ELEM_TY}`.
+    pub const fn ir_type(self) -> &'static str {
+        match self {
+            HalfFormat::F16 => "half",
+            HalfFormat::Bf16 => "bfloat",
+        }
+    }
+
+    /// Intrinsic name suffix, substituted for `{ELEM_SUFFIX}`.
+    pub const fn ir_suffix(self) -> &'static str {
+        match self {
+            HalfFormat::F16 => "f16",
+            HalfFormat::Bf16 => "bf16",
+        }
+    }
+}
+
+/// Result element of the half-precision kernels:
+/// `f32` stores the accumulator as is, `u16` truncates it back to the input [`HalfFormat`].
+pub trait HalfOutput: sealed::Sealed + Copy + Debug + Send + Sync + 'static {
+    /// `true` when the result keeps the `f32` accumulator.
+    const WIDENED: bool;
+    const ZERO: Self;
+}
+
+impl HalfOutput for f32 {
+    const WIDENED: bool = true;
+    const ZERO: Self = 0.0;
+}
+
+impl HalfOutput for u16 {
+    const WIDENED: bool = false;
+    const ZERO: Self = 0;
+}
+
+/// What the integer kernels do when a product or a partial sum overflows the accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Overflow {
+    /// Two's complement wrap-around, like `i32::wrapping_add`.
+    #[default]
+    Wrapping,
+    /// The overflow is reported (`MatmulError::IntegerOverflow`).
+    Checked,
+}
+
+impl Overflow {
+    /// Substituted for `{CHECKED}`.
+    pub const fn as_ir(self) -> &'static str {
+        match self {
+            Overflow::Wrapping => "false",
+            Overflow::Checked => "true",
+        }
+    }
+}
+
+/// Accumulator (and result) type of the integer kernels.
+pub trait IntAccumulator:
+    sealed::Sealed + Copy + Default + PartialEq + Debug + Display + Send + Sync + 'static
+{
+    const IR_TYPE: &'static str;
+    const IR_SUFFIX: &'static str;
+    const BYTES: usize;
+    const ZERO: Self;
+
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+}
+
+macro_rules! impl_int_accumulator {
+    ($ty:ty, $ir:literal, $bytes:literal) => {
+        impl IntAccumulator for $ty {
+            const IR_TYPE: &'static str = $ir;
+            const IR_SUFFIX: &'static str = $ir;
+            const BYTES: usize = $bytes;
+            const ZERO: Self = 0;
+
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$ty>::wrapping_add(self, rhs)
+            }
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$ty>::wrapping_mul(self, rhs)
+            }
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                <$ty>::checked_add(self, rhs)
+            }
+            fn checked_mul(self, rhs: Self) -> Option<Self> {
+                <$ty>::checked_mul(self, rhs)
+            }
+        }
+    };
+}
+
+impl_int_accumulator!(i32, "i32", 4);
+impl_int_accumulator!(i64, "i64", 8);
+
+/// Element types of the integer kernels, `a` and `b` are widened to `Acc` before the multiply.
+pub trait IntScalar: sealed::Sealed + Copy + Debug + Send + Sync + 'static {
+    type Acc: IntAccumulator;
+    /// LLVM IR type, substituted for `{ELEM_TY}`. Signedness isn't part of it.
+    const IR_TYPE: &'static str;
+    const IR_SUFFIX: &'static str;
+    const BYTES: usize;
+    /// Widening instruction, substituted for `{ELEM_EXT}`
+    /// (`bitcast` when the element already is the accumulator).
+    const IR_EXT: &'static str;
+
+    fn widen(self) -> Self::Acc;
+}
+
+macro_rules! impl_int_scalar {
+    ($ty:ty, $acc:ty, $ir:literal, $bytes:literal, $ext:literal) => {
+        impl IntScalar for $ty {
+            type Acc = $acc;
+            const IR_TYPE: &'static str = $ir;
+            const IR_SUFFIX: &'static str = $ir;
+            const BYTES: usize = $bytes;
+            const IR_EXT: &'static str = $ext;
+
+            fn widen(self) -> $acc {
+                self as $acc
+            }
+        }
+    };
+}
+
+impl_int_scalar!(i8, i32, "i8", 1, "sext");
+impl_int_scalar!(u8, i32, "i8", 1, "zext");
+impl_int_scalar!(i32, i32, "i32", 4, "bitcast");
+impl_int_scalar!(i64, i64, "i64", 8, "bitcast");
+
+pub fn generate_random_matrix<T: Scalar>(rows: usize, cols: usize, seed: u64) -> Vec<T> {
     let mut rng = StdRng::seed_from_u64(seed);
     (0..rows * cols)
-        .map(|_| rng.random_range(1f32..255f32))
+        .map(|_| T::from_f64(rng.random_range(1f64..255f64)))
         .collect()
 }
 
 // TODO : make less naive
-pub fn native_matmul(
-    a: &[f32],
+pub fn native_matmul<T: Scalar>(
+    a: &[T],
     a_dims: (usize, usize),
-    b: &[f32],
+    b: &[T],
     b_dims: (usize, usize),
-) -> Vec<f32> {
+) -> Vec<T> {
     let (m, k) = a_dims;
     let (k2, n) = b_dims;
     assert_eq!(k, k2, "Matrix dimensions must agree");
 
-    let mut result = vec![0.0; m * n];
+    let mut result = vec![T::ZERO; m * n];
     for i in 0..m {
         for j in 0..n {
-            let mut sum = 0.0;
+            let mut sum = T::ZERO;
             for p in 0..k {
-                sum += a[i * k + p] * b[p * n + j];
+                sum = sum + a[i * k + p] * b[p * n + j];
             }
             result[i * n + j] = sum;
         }
@@ -50,7 +299,37 @@ pub fn native_matmul(
     result
 }
 
-pub fn assert_vec_eq(result: &[f32], expected: &[f32], epsilon: f32) {
+/// Integer counterpart of [`native_matmul`], summed in `I::Acc` in the same order.
+/// Returns `None` if a product or a partial sum overflows with [`Overflow::Checked`].
+pub fn native_matmul_int<I: IntScalar>(
+    a: &[I],
+    a_dims: (usize, usize),
+    b: &[I],
+    b_dims: (usize, usize),
+    overflow: Overflow,
+) -> Option<Vec<I::Acc>> {
+    let (m, k) = a_dims;
+    let (k2, n) = b_dims;
+    assert_eq!(k, k2, "Matrix dimensions must agree");
+
+    let mut result = vec![I::Acc::ZERO; m * n];
+    for i in 0..m {
+        for j in 0..n {
+            let mut sum = I::Acc::ZERO;
+            for p in 0..k {
+                let (x, y) = (a[i * k + p].widen(), b[p * n + j].widen());
+                sum = match overflow {
+                    Overflow::Wrapping => sum.wrapping_add(x.wrapping_mul(y)),
+                    Overflow::Checked => sum.checked_add(x.checked_mul(y)?)?,
+                };
+            }
+            result[i * n + j] = sum;
+        }
+    }
+    Some(result)
+}
+
+pub fn assert_vec_eq<T: Scalar>(result: &[T], expected: &[T], epsilon: T) {
     assert_eq!(
         result.len(),
         expected.len(),
@@ -60,7 +339,7 @@ pub fn assert_vec_eq(result: &[f32], expected: &[f32], epsilon: f32) {
     let mut buff = String::new();
     //dbg!(expected, result);
     for (i, (r, e)) in result.iter().zip(expected.iter()).enumerate() {
-        error = error || (r - e).abs() > epsilon;
+        error = error || (r.to_f64() - e.to_f64()).abs() > epsilon.to_f64();
         buff.push_str(&format!("diff at index {}: got {}, expected {}\n", i, r, e));
     }
     if error {
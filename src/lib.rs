@@ -1,5 +1,18 @@
 pub mod common;
+pub use common::DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU;
+pub use common::DEFAULT_IR_TEMPLATE_DYN_JIT_CPU;
+pub use common::DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU;
+pub use common::DEFAULT_IR_TEMPLATE_HALF_JIT_CPU;
+pub use common::DEFAULT_IR_TEMPLATE_INT_JIT_CPU;
 pub use common::DEFAULT_IR_TEMPLATE_JIT_CPU;
+pub use common::HalfFormat;
+pub use common::JIT_CACHE_DIR_ENV;
+pub use common::Overflow;
+pub use common::TEMPLATE_BATCHED_JIT_CPU_ENV;
+pub use common::TEMPLATE_DYN_JIT_CPU_ENV;
+pub use common::TEMPLATE_GEMM_JIT_CPU_ENV;
+pub use common::TEMPLATE_HALF_JIT_CPU_ENV;
+pub use common::TEMPLATE_INT_JIT_CPU_ENV;
 pub use common::TEMPLATE_JIT_CPU_ENV;
 pub use common::TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME;
 pub mod llvm;
@@ -11,9 +24,57 @@ pub use llvm::gpu::ll_matmul_gpu_compiled;
 #[cfg(feature = "gpu")]
 pub use llvm::gpu::ll_matmul_gpu_jit;
 
+pub use llvm::BatchLayout;
+pub use llvm::CodeModel;
+pub use llvm::JitCache;
+pub use llvm::JitCacheCapacity;
+pub use llvm::JitCacheStats;
+pub use llvm::JitOptions;
+pub use llvm::KernelInspection;
+pub use llvm::KernelStats;
+pub use llvm::MatmulError;
+pub use llvm::MatrixLayout;
+pub use llvm::OptimizationLevel;
+pub use llvm::RelocMode;
+pub use llvm::Specialization;
+pub use llvm::TemplateSource;
+pub use llvm::Trans;
 pub use llvm::col_major_to_row_major;
+pub use llvm::col_major_to_row_major_into;
+pub use llvm::compile_matmul_jit_dynamic;
+pub use llvm::compile_matmul_jit_half;
+pub use llvm::compile_matmul_jit_with_layout;
+pub use llvm::compile_matmul_jit_with_options;
 pub use llvm::compile_matmul_jit_with_template;
+pub use llvm::inspect_matmul_jit;
+pub use llvm::jit_cache;
+pub use llvm::ll_gemm_jit;
 pub use llvm::ll_matmul_4x4;
+pub use llvm::ll_matmul_4x4_f64;
 pub use llvm::ll_matmul_4x4_unrolled;
+pub use llvm::ll_matmul_jit_batched;
+pub use llvm::ll_matmul_jit_half;
+pub use llvm::ll_matmul_jit_int;
+pub use llvm::ll_matmul_jit_into;
+pub use llvm::ll_matmul_jit_into_with;
+pub use llvm::ll_matmul_jit_with_options;
 pub use llvm::ll_matmul_jit_with_template;
 pub use llvm::row_major_to_col_major;
+pub use llvm::row_major_to_col_major_into;
+pub use llvm::set_jit_cache_capacity;
+pub use llvm::set_jit_cache_dir;
+pub use llvm::set_jit_options;
+pub use llvm::set_specialization;
+pub use llvm::try_compile_matmul_jit_dynamic;
+pub use llvm::try_compile_matmul_jit_half;
+pub use llvm::try_compile_matmul_jit_with_layout;
+pub use llvm::try_compile_matmul_jit_with_options;
+pub use llvm::try_compile_matmul_jit_with_template;
+pub use llvm::try_ll_gemm_jit;
+pub use llvm::try_ll_matmul_jit_batched;
+pub use llvm::try_ll_matmul_jit_half;
+pub use llvm::try_ll_matmul_jit_int;
+pub use llvm::try_ll_matmul_jit_into;
+pub use llvm::try_ll_matmul_jit_into_with;
+pub use llvm::try_ll_matmul_jit_with_options;
+pub use llvm::try_ll_matmul_jit_with_template;
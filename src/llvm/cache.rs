@@ -0,0 +1,1125 @@
+use std::any::{Any, TypeId, type_name};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::common::Scalar;
+use crate::llvm::disk_cache::DiskCache;
+use crate::llvm::error::MatmulError;
+use crate::llvm::jit::{ElemTypes, JitEntry, KernelAbi, KernelSource, LlMatmulJitSig};
+use crate::llvm::jit::{MatrixLayout, NO_TRANS, ShapeKey, Specialization, TransKey};
+use crate::llvm::jit::{compile_matmul_jit_from_ir, instantiate_kernel, warn_default_template};
+use crate::llvm::options::{CodegenOptions, JitOptions, resolve_options};
+
+pub static JIT_CACHE: OnceLock<JitCache> = OnceLock::new();
+
+/// The process-wide cache the `ll_matmul_jit*` calls compile kernels into.
+pub fn jit_cache() -> &'static JitCache {
+    JIT_CACHE.get_or_init(JitCache::new)
+}
+
+/// A kernel is identified by its shape, the IR it was compiled from,
+/// the symbol we look up, its signature and how it was code-generated.
+/// Two templates (or two entry points) for the same shape never collide.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct JitKey {
+    shape: ShapeKey,
+    // matmul and gemm kernels share the cache, the entry is downcast on the way out
+    signature: TypeId,
+    // square shapes instantiate to the same IR in both layouts
+    layout: MatrixLayout,
+    // templates without {TRANS_A}/{TRANS_B} instantiate to the same IR either way
+    trans: TransKey,
+    ir_hash: u64,
+    function_name: String,
+    codegen: CodegenOptions,
+}
+
+impl JitKey {
+    fn new<F: KernelAbi>(
+        shape: ShapeKey,
+        layout: MatrixLayout,
+        trans: TransKey,
+        ir: &str,
+        function_name: &str,
+        codegen: &CodegenOptions,
+    ) -> Self {
+        let mut hasher = DefaultHasher::new();
+        ir.hash(&mut hasher);
+        Self {
+            shape,
+            signature: TypeId::of::<F>(),
+            layout,
+            trans,
+            ir_hash: hasher.finish(),
+            function_name: function_name.to_string(),
+            codegen: codegen.clone(),
+        }
+    }
+}
+
+/// How many kernels [`JitCache`] keeps, see [`set_jit_cache_capacity`].
+///
+/// Past either limit the least recently used kernels are evicted. Evicting drops the
+/// cache's handle only: a kernel still held through an `Arc<JitEntry>` keeps working
+/// and is freed with the last handle, the next call for its key compiles it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitCacheCapacity {
+    /// Number of kernels, `None` for no limit.
+    pub max_entries: Option<usize>,
+    /// Sum of the kernels' [`JitEntry::code_bytes`], `None` for no limit.
+    pub max_code_bytes: Option<usize>,
+}
+
+impl JitCacheCapacity {
+    /// No limit, every kernel stays for the whole process.
+    pub const UNBOUNDED: Self = Self {
+        max_entries: None,
+        max_code_bytes: None,
+    };
+}
+
+impl Default for JitCacheCapacity {
+    /// 512 kernels, enough for the shapes of a model, bounded for a server seeing arbitrary ones.
+    fn default() -> Self {
+        Self {
+            max_entries: Some(512),
+            max_code_bytes: None,
+        }
+    }
+}
+
+/// Sets the capacity of the process-wide JIT cache, evicting right away what's over it.
+pub fn set_jit_cache_capacity(capacity: JitCacheCapacity) {
+    jit_cache().set_capacity(capacity);
+}
+
+/// What [`JitCache::stats`] reports about one cached kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelStats {
+    /// `(m, n, k)` as the kernel was requested (the dimensions the caller passes, whatever the layout).
+    pub shape: (usize, usize, usize),
+    pub layout: MatrixLayout,
+    /// The kernel's signature type, e.g. `LlMatmulJitSig<f32>`.
+    pub kernel: &'static str,
+    pub function_name: String,
+    /// See [`JitEntry::compile_time`].
+    pub compile_time: Duration,
+    /// See [`JitEntry::ir_bytes`].
+    pub ir_bytes: usize,
+    /// See [`JitEntry::code_bytes`].
+    pub code_bytes: usize,
+    /// Lookups of the kernel through the cache, the one that compiled it included.
+    pub calls: u64,
+    pub last_used: SystemTime,
+}
+
+/// A snapshot of [`JitCache`], plain data for a metrics exporter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitCacheStats {
+    /// Lookups that found their kernel cached, or compiled by a concurrent lookup.
+    pub hits: u64,
+    /// Lookups that compiled their kernel (or failed to).
+    pub misses: u64,
+    /// Kernels dropped to stay within the capacity, [`JitCache::clear`] and [`JitCache::remove`] not counted.
+    pub evictions: u64,
+    pub capacity: JitCacheCapacity,
+    /// Sum of the kernels' `code_bytes`.
+    pub code_bytes: usize,
+    /// Least recently used first.
+    pub kernels: Vec<KernelStats>,
+}
+
+struct CachedKernel {
+    entry: Arc<dyn Any + Send + Sync>,
+    kernel: &'static str,
+    compile_time: Duration,
+    ir_bytes: usize,
+    code_bytes: usize,
+    usage: KernelUsage,
+}
+
+/// Updated on every lookup, under the map's read lock.
+#[derive(Default)]
+struct KernelUsage {
+    calls: AtomicU64,
+    // nanoseconds since `JitCache::epoch`, what eviction orders by
+    last_used: AtomicU64,
+}
+
+impl KernelUsage {
+    fn touch(&self, epoch: Instant) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.last_used
+            .store(epoch.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct CacheMap {
+    kernels: HashMap<JitKey, CachedKernel>,
+    // keys being compiled, not in `kernels` yet
+    in_flight: HashMap<JitKey, Arc<InFlight>>,
+    capacity: JitCacheCapacity,
+    code_bytes: usize,
+    evictions: u64,
+}
+
+impl CacheMap {
+    fn get(&self, key: &JitKey, epoch: Instant) -> Option<Arc<dyn Any + Send + Sync>> {
+        let kernel = self.kernels.get(key)?;
+        kernel.usage.touch(epoch);
+        Some(kernel.entry.clone())
+    }
+
+    /// Inserts a kernel, evicting the least recently used ones past the capacity.
+    fn insert<F: KernelAbi>(&mut self, key: JitKey, entry: Arc<JitEntry<F>>, epoch: Instant) {
+        let usage = KernelUsage::default();
+        usage.touch(epoch);
+        self.code_bytes += entry.code_bytes;
+        let kernel = CachedKernel {
+            kernel: type_name::<F>(),
+            compile_time: entry.compile_time,
+            ir_bytes: entry.ir_bytes,
+            code_bytes: entry.code_bytes,
+            usage,
+            entry,
+        };
+        if let Some(replaced) = self.kernels.insert(key, kernel) {
+            self.code_bytes -= replaced.code_bytes;
+        }
+        self.evict();
+    }
+
+    fn remove_where(&mut self, mut pred: impl FnMut(&JitKey) -> bool) -> usize {
+        let before = self.kernels.len();
+        let mut code_bytes = self.code_bytes;
+        self.kernels.retain(|key, kernel| {
+            let remove = pred(key);
+            if remove {
+                code_bytes -= kernel.code_bytes;
+            }
+            !remove
+        });
+        self.code_bytes = code_bytes;
+        before - self.kernels.len()
+    }
+
+    /// Evicts the least recently used kernels past the capacity, returns how many.
+    fn evict(&mut self) -> usize {
+        let over = |map: &Self| {
+            map.capacity
+                .max_entries
+                .is_some_and(|max| map.kernels.len() > max)
+                || map
+                    .capacity
+                    .max_code_bytes
+                    .is_some_and(|max| map.code_bytes > max)
+        };
+        let mut evicted = 0;
+        while over(self) {
+            // a linear scan, the cache holds hundreds of kernels and compiling one costs far more
+            let Some(lru) = self
+                .kernels
+                .iter()
+                .min_by_key(|(_, kernel)| kernel.usage.last_used.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(kernel) = self.kernels.remove(&lru) {
+                self.code_bytes -= kernel.code_bytes;
+                evicted += 1;
+            }
+        }
+        self.evictions += evicted as u64;
+        evicted
+    }
+}
+
+/// A compile in progress, the lookups of its key wait for it instead of compiling too.
+#[derive(Default)]
+struct InFlight {
+    outcome: Mutex<Outcome>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+enum Outcome {
+    #[default]
+    Compiling,
+    Compiled(Arc<dyn Any + Send + Sync>),
+    // the same IR and codegen would fail the same way, the waiters get the error too
+    Failed(MatmulError),
+    // the compile panicked, there's no error to share
+    Abandoned,
+}
+
+impl InFlight {
+    /// Blocks until the compile ends, with its kernel or its error, `None` if it panicked.
+    fn wait(&self) -> Option<Result<Arc<dyn Any + Send + Sync>, MatmulError>> {
+        let mut outcome = self.outcome.lock().unwrap();
+        loop {
+            match &*outcome {
+                Outcome::Compiling => outcome = self.done.wait(outcome).unwrap(),
+                Outcome::Compiled(e) => return Some(Ok(e.clone())),
+                Outcome::Failed(e) => return Some(Err(e.clone())),
+                Outcome::Abandoned => return None,
+            }
+        }
+    }
+}
+
+/// Ends the compile of `key`: takes it out of the in-flight ones and wakes its waiters
+/// with `outcome`. On drop, so a panicking compile doesn't leave them hanging.
+struct Landing<'a> {
+    map: &'a RwLock<CacheMap>,
+    key: &'a JitKey,
+    outcome: Option<Result<Arc<dyn Any + Send + Sync>, MatmulError>>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        let flight = self.map.write().unwrap().in_flight.remove(self.key);
+        if let Some(flight) = flight {
+            *flight.outcome.lock().unwrap() = match self.outcome.take() {
+                Some(Ok(e)) => Outcome::Compiled(e),
+                Some(Err(e)) => Outcome::Failed(e),
+                None => Outcome::Abandoned,
+            };
+            flight.done.notify_all();
+        }
+    }
+}
+
+pub struct JitCache {
+    // read-locked on a hit, write-locked to insert, remove or register a compile
+    map: RwLock<CacheMap>,
+    epoch: Instant,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // calls per (shape, element type), for `Specialization::AfterCalls`: read-locked to count,
+    // write-locked only to add a shape
+    shape_calls: RwLock<HashMap<(ShapeKey, TypeId), AtomicU32>>,
+    // `None` resolves the process-wide one (`set_jit_cache_dir` / `LL_MATMUL_CACHE_DIR`) per compile
+    disk: Option<DiskCache>,
+}
+
+#[derive(Debug)]
+pub(crate) enum JitError {
+    CompilationFailed(MatmulError),
+}
+
+impl JitCache {
+    fn new() -> Self {
+        Self {
+            map: RwLock::new(CacheMap::default()),
+            epoch: Instant::now(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            shape_calls: RwLock::new(HashMap::new()),
+            disk: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_disk_cache(disk: DiskCache) -> Self {
+        Self {
+            disk: Some(disk),
+            ..Self::new()
+        }
+    }
+
+    #[cfg(test)]
+    fn with_capacity(capacity: JitCacheCapacity) -> Self {
+        let cache = Self::new();
+        cache.set_capacity(capacity);
+        cache
+    }
+
+    /// Number of cached kernels.
+    pub fn len(&self) -> usize {
+        self.map.read().unwrap().kernels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn set_capacity(&self, capacity: JitCacheCapacity) {
+        let mut map = self.map.write().unwrap();
+        map.capacity = capacity;
+        map.evict();
+    }
+
+    /// Counters and per-kernel statistics, as of now.
+    pub fn stats(&self) -> JitCacheStats {
+        let map = self.map.read().unwrap();
+        let (now, since_epoch) = (SystemTime::now(), self.epoch.elapsed());
+        let mut kernels: Vec<_> = map
+            .kernels
+            .iter()
+            .map(|(key, kernel)| {
+                let last_used = kernel.usage.last_used.load(Ordering::Relaxed);
+                let stats = KernelStats {
+                    shape: key.shape,
+                    layout: key.layout,
+                    kernel: kernel.kernel,
+                    function_name: key.function_name.clone(),
+                    compile_time: kernel.compile_time,
+                    ir_bytes: kernel.ir_bytes,
+                    code_bytes: kernel.code_bytes,
+                    calls: kernel.usage.calls.load(Ordering::Relaxed),
+                    last_used: now - since_epoch.saturating_sub(Duration::from_nanos(last_used)),
+                };
+                (last_used, stats)
+            })
+            .collect();
+        kernels.sort_by_key(|(last_used, _)| *last_used);
+        JitCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: map.evictions,
+            capacity: map.capacity,
+            code_bytes: map.code_bytes,
+            kernels: kernels.into_iter().map(|(_, stats)| stats).collect(),
+        }
+    }
+
+    /// Drops every cached kernel, the counters are kept.
+    /// Kernels still held through an `Arc<JitEntry>` are freed with their last handle.
+    pub fn clear(&self) {
+        self.map.write().unwrap().remove_where(|_| true);
+    }
+
+    /// Drops the kernels of `shape` (`(m, n, k)` as passed to the matmul calls), whatever
+    /// their element type, layout, template or options. Returns how many there were.
+    pub fn remove(&self, shape: (usize, usize, usize)) -> usize {
+        self.map
+            .write()
+            .unwrap()
+            .remove_where(|key| key.shape == shape)
+    }
+
+    /// Compiles the kernels [`ll_matmul_jit_with_options`](crate::llvm::ll_matmul_jit_with_options)
+    /// and [`ll_matmul_jit_into`](crate::llvm::ll_matmul_jit_into) use for `shapes`,
+    /// so the first call of each doesn't pay for it. Stops at the first failure.
+    pub fn warm<T: Scalar>(
+        &self,
+        shapes: &[(usize, usize, usize)],
+        options: &JitOptions,
+    ) -> Result<(), MatmulError> {
+        for &shape in shapes {
+            self.get_or_compile::<LlMatmulJitSig<T>>(
+                shape,
+                MatrixLayout::RowMajor,
+                NO_TRANS,
+                ElemTypes::of::<T>(),
+                options,
+            )
+            .map_err(|e| match e {
+                JitError::CompilationFailed(e) => e,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Counts a call of `shape` and tells whether it should run a specialized kernel.
+    pub(crate) fn should_specialize<T: Scalar>(
+        &self,
+        shape: ShapeKey,
+        policy: Specialization,
+    ) -> bool {
+        match policy {
+            Specialization::Always => true,
+            Specialization::Never => false,
+            Specialization::AfterCalls(n) => {
+                let key = (shape, TypeId::of::<T>());
+                let count = |calls: &AtomicU32| {
+                    // past `n` the count isn't written anymore, the threads only read it
+                    if calls.load(Ordering::Relaxed) >= n {
+                        return true;
+                    }
+                    calls
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_add(1))
+                        .map_or(true, |before| before + 1 >= n)
+                };
+                if let Some(calls) = self.shape_calls.read().unwrap().get(&key) {
+                    return count(calls);
+                }
+                count(self.shape_calls.write().unwrap().entry(key).or_default())
+            }
+        }
+    }
+
+    pub(crate) fn get_or_compile<F: KernelAbi>(
+        &self,
+        shape: ShapeKey,
+        layout: MatrixLayout,
+        trans: TransKey,
+        elems: ElemTypes,
+        options: &JitOptions,
+    ) -> Result<Arc<JitEntry<F>>, JitError> {
+        // the options (template, function name, codegen) are resolved on every call,
+        // so switching them (or the env vars) mid-process picks a different kernel
+        let options = resolve_options::<F>(options);
+        let codegen = CodegenOptions::resolve(&options);
+        let source = instantiate_kernel::<F>(&options, shape, layout, trans, elems)
+            .map_err(JitError::CompilationFailed)?;
+        let key = JitKey::new::<F>(
+            shape,
+            layout,
+            trans,
+            &source.ir,
+            &source.function_name,
+            &codegen,
+        );
+
+        // a hit only read-locks the map, the usage counters are atomics
+        if let Some(e) = self.map.read().unwrap().get(&key, self.epoch) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(downcast_entry(e));
+        }
+
+        // concurrent lookups of a key that isn't cached yet wait for the first one's compile,
+        // compiles of different keys run in parallel (the map isn't locked while compiling)
+        let flight = {
+            let mut map = self.map.write().unwrap();
+            // inserted since the read lock was released
+            if let Some(e) = map.get(&key, self.epoch) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(downcast_entry(e));
+            }
+            let flight = map.in_flight.get(&key).cloned();
+            if flight.is_none() {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                map.in_flight
+                    .insert(key.clone(), Arc::new(InFlight::default()));
+            }
+            flight
+        };
+        if let Some(flight) = flight {
+            match flight.wait() {
+                Some(Ok(e)) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    // counts the call, unless the kernel was already evicted
+                    self.map.read().unwrap().get(&key, self.epoch);
+                    return Ok(downcast_entry(e));
+                }
+                // a failed compile is shared, the waiters don't all compile it again
+                Some(Err(e)) => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return Err(JitError::CompilationFailed(e));
+                }
+                None => {}
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            // the compile panicked, compiling again gives this call a kernel or an error of its own
+            let entry = Arc::new(self.compile::<F>(&source, layout, &codegen, &options, shape)?);
+            self.insert(key, entry.clone());
+            return Ok(entry);
+        }
+
+        let mut landing = Landing {
+            map: &self.map,
+            key: &key,
+            outcome: None,
+        };
+        let entry = match self.compile::<F>(&source, layout, &codegen, &options, shape) {
+            Ok(entry) => Arc::new(entry),
+            Err(JitError::CompilationFailed(e)) => {
+                landing.outcome = Some(Err(e.clone()));
+                return Err(JitError::CompilationFailed(e));
+            }
+        };
+        self.insert(key.clone(), entry.clone());
+        landing.outcome = Some(Ok(entry.clone()));
+        Ok(entry)
+    }
+
+    fn insert<F: KernelAbi>(&self, key: JitKey, entry: Arc<JitEntry<F>>) {
+        self.map.write().unwrap().insert(key, entry, self.epoch);
+    }
+
+    fn compile<F: KernelAbi>(
+        &self,
+        source: &KernelSource,
+        layout: MatrixLayout,
+        codegen: &CodegenOptions,
+        options: &JitOptions,
+        shape: ShapeKey,
+    ) -> Result<JitEntry<F>, JitError> {
+        warn_default_template::<F>(options.template.as_ref(), shape);
+
+        // compile in a fresh context, emit the object code, link it in its own JIT,
+        // get the function, wrap in Arc<JitEntry>
+        let env_disk;
+        let disk = match &self.disk {
+            Some(disk) => Some(disk),
+            None => {
+                env_disk = DiskCache::from_env();
+                env_disk.as_ref()
+            }
+        };
+        unsafe {
+            compile_matmul_jit_from_ir(source, layout, codegen, disk)
+                .map_err(JitError::CompilationFailed)
+        }
+    }
+
+    /// [`get_or_compile`](Self::get_or_compile) for operands at the addresses `operands`:
+    /// when they're less aligned than the template header asks, the kernel of `F`'s default
+    /// template if the header allows the fallback, [`MatmulError::MisalignedOperand`] otherwise.
+    pub(crate) fn get_for_operands<F: KernelAbi>(
+        &self,
+        shape: ShapeKey,
+        layout: MatrixLayout,
+        trans: TransKey,
+        elems: ElemTypes,
+        options: &JitOptions,
+        operands: &[usize],
+    ) -> Result<Arc<JitEntry<F>>, MatmulError> {
+        let compile = |options: &JitOptions| {
+            self.get_or_compile::<F>(shape, layout, trans, elems, options)
+                .map_err(|e| match e {
+                    JitError::CompilationFailed(e) => e,
+                })
+        };
+        let entry = compile(options)?;
+        if operands.iter().all(|addr| addr.is_multiple_of(entry.align)) {
+            Ok(entry)
+        } else if entry.fallback {
+            compile(&options.pin_default_template::<F>(shape))
+        } else {
+            Err(MatmulError::MisalignedOperand { align: entry.align })
+        }
+    }
+}
+
+// the key holds the TypeId of F, so a mismatch here is a bug in JitKey
+fn downcast_entry<F: KernelAbi>(entry: Arc<dyn Any + Send + Sync>) -> Arc<JitEntry<F>> {
+    entry
+        .downcast::<JitEntry<F>>()
+        .expect("JIT cache entry doesn't match its key signature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{DEFAULT_FUNCTION_NAME_JIT_CPU, DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU};
+    use crate::common::{DEFAULT_IR_TEMPLATE_JIT_CPU, UNROLLED_IR_TEMPLATE_JIT_CPU};
+    use crate::common::{assert_vec_eq, native_matmul};
+    use crate::llvm::jit::{LlGemmJitSig, Trans};
+    use crate::llvm::options::LOWER_MATRIX_INTRINSICS;
+    use inkwell::OptimizationLevel;
+    use inkwell::targets::{CodeModel, RelocMode};
+    use std::env;
+    use std::fs;
+
+    // the lookup most tests make: a row-major f32 matmul kernel
+    fn get_f32(
+        cache: &JitCache,
+        shape: ShapeKey,
+        options: &JitOptions,
+    ) -> Result<Arc<JitEntry>, JitError> {
+        cache.get_or_compile::<LlMatmulJitSig>(
+            shape,
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            ElemTypes::of::<f32>(),
+            options,
+        )
+    }
+
+    #[test]
+    fn test_jit_caching() {
+        let cache = jit_cache();
+
+        let shape1: ShapeKey = (2, 2, 2);
+        let shape2: ShapeKey = (3, 3, 3);
+
+        let entry1_a =
+            get_f32(cache, shape1, &JitOptions::new()).expect("Failed to compile shape1");
+
+        let entry1_b =
+            get_f32(cache, shape1, &JitOptions::new()).expect("Failed to compile shape1 again");
+        assert!(
+            Arc::ptr_eq(&entry1_a, &entry1_b),
+            "Cache should return the same Arc for the same shape"
+        );
+
+        let entry2 = get_f32(cache, shape2, &JitOptions::new()).expect("Failed to compile shape2");
+        assert!(
+            !Arc::ptr_eq(&entry1_a, &entry2),
+            "Cache should return different Arcs for different shapes"
+        );
+
+        let entry1_c =
+            get_f32(cache, shape1, &JitOptions::new()).expect("Failed to retrieve shape1");
+        assert!(
+            Arc::ptr_eq(&entry1_a, &entry1_c),
+            "Original entry should still be in cache"
+        );
+    }
+
+    #[test]
+    fn test_jit_cache_keyed_by_template() {
+        let cache = JitCache::new();
+        // n = 8 (the kernel's M): the unrolled template requires 32-byte columns
+        let shape: ShapeKey = (3, 8, 3);
+
+        let naive = get_f32(
+            &cache,
+            shape,
+            &JitOptions::new().template(DEFAULT_IR_TEMPLATE_JIT_CPU),
+        )
+        .expect("Failed to compile naive template");
+        let unrolled = get_f32(
+            &cache,
+            shape,
+            &JitOptions::new().template(UNROLLED_IR_TEMPLATE_JIT_CPU),
+        )
+        .expect("Failed to compile unrolled template");
+        assert!(
+            !Arc::ptr_eq(&naive, &unrolled),
+            "Different templates for the same shape must not share a kernel"
+        );
+
+        let naive_again = get_f32(
+            &cache,
+            shape,
+            &JitOptions::new().template(DEFAULT_IR_TEMPLATE_JIT_CPU),
+        )
+        .expect("Failed to retrieve naive template");
+        let unrolled_again = get_f32(
+            &cache,
+            shape,
+            &JitOptions::new().template(UNROLLED_IR_TEMPLATE_JIT_CPU),
+        )
+        .expect("Failed to retrieve unrolled template");
+        assert!(Arc::ptr_eq(&naive, &naive_again));
+        assert!(Arc::ptr_eq(&unrolled, &unrolled_again));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_jit_cache_eviction() {
+        let cache = JitCache::with_capacity(JitCacheCapacity {
+            max_entries: Some(2),
+            max_code_bytes: None,
+        });
+        let get = |shape: ShapeKey| {
+            get_f32(&cache, shape, &JitOptions::new()).expect("Failed to compile")
+        };
+
+        let first = get((2, 2, 2));
+        let second = get((3, 3, 3));
+        // (2, 2, 2) is now the most recently used, (3, 3, 3) goes first
+        assert!(Arc::ptr_eq(&first, &get((2, 2, 2))));
+        let third = get((4, 4, 4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 1);
+        assert!(Arc::ptr_eq(&first, &get((2, 2, 2))));
+        assert!(Arc::ptr_eq(&third, &get((4, 4, 4))));
+
+        // an evicted kernel outlives the cache's handle and is freed with the last one
+        let a: Vec<f32> = (0..9).map(|x| x as f32).collect();
+        let mut result = vec![0.0; 9];
+        unsafe { second.call(a.as_ptr(), a.as_ptr(), result.as_mut_ptr()) };
+        assert_vec_eq(&result, &native_matmul(&a, (3, 3), &a, (3, 3)), 1e-5);
+        let second_weak = Arc::downgrade(&second);
+        drop(second);
+        assert!(second_weak.upgrade().is_none());
+
+        // (2, 2, 2) was the least recently used
+        get((3, 3, 3));
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&third, &get((4, 4, 4))));
+        assert!(!Arc::ptr_eq(&first, &get((2, 2, 2))));
+
+        // a byte budget smaller than one kernel keeps nothing, the kernel still runs
+        cache.set_capacity(JitCacheCapacity {
+            max_entries: None,
+            max_code_bytes: Some(first.code_bytes - 1),
+        });
+        assert_eq!(cache.len(), 0);
+        assert!(first.code_bytes > 0);
+        let again = get((2, 2, 2));
+        assert_eq!(cache.len(), 0);
+        assert!(!Arc::ptr_eq(&first, &again));
+        let a: Vec<f32> = (0..4).map(|x| x as f32).collect();
+        let mut result = vec![0.0; 4];
+        unsafe { again.call(a.as_ptr(), a.as_ptr(), result.as_mut_ptr()) };
+        assert_vec_eq(&result, &native_matmul(&a, (2, 2), &a, (2, 2)), 1e-5);
+    }
+
+    #[test]
+    fn test_jit_cache_stats() {
+        let cache = JitCache::new();
+        cache
+            .warm::<f32>(&[(2, 3, 4), (4, 3, 2)], &JitOptions::new())
+            .expect("Failed to warm the cache");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 2, 0));
+        assert_eq!(stats.capacity, JitCacheCapacity::default());
+        let shapes: Vec<_> = stats.kernels.iter().map(|kernel| kernel.shape).collect();
+        assert_eq!(shapes, [(2, 3, 4), (4, 3, 2)]);
+        for kernel in &stats.kernels {
+            assert_eq!(kernel.layout, MatrixLayout::RowMajor);
+            assert_eq!(kernel.function_name, DEFAULT_FUNCTION_NAME_JIT_CPU);
+            assert!(kernel.kernel.contains("LlMatmulJitSig<f32>"));
+            assert!(kernel.ir_bytes > 0 && kernel.code_bytes > 0);
+            assert_eq!(kernel.calls, 1);
+        }
+        assert_eq!(
+            stats.code_bytes,
+            stats.kernels.iter().map(|kernel| kernel.code_bytes).sum()
+        );
+
+        // the call ll_matmul_jit_into makes for a (2, 3) * (3, 4) hits the warmed kernel
+        let entry = get_f32(&cache, (2, 3, 4), &JitOptions::new())
+            .expect("Failed to get the warmed kernel");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        let last = stats.kernels.last().unwrap();
+        assert_eq!((last.shape, last.calls), ((2, 3, 4), 2));
+        assert!(last.last_used >= stats.kernels[0].last_used);
+        assert_eq!(last.compile_time, entry.compile_time);
+
+        assert_eq!(cache.remove((2, 3, 4)), 1);
+        assert_eq!(cache.remove((2, 3, 4)), 0);
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.code_bytes), (1, 2, 0));
+        assert!(stats.kernels.is_empty());
+    }
+
+    #[test]
+    fn test_jit_cache_compiles_once_per_key() {
+        let cache = JitCache::new();
+        let threads = 8;
+        let shapes: [ShapeKey; 2] = [(5, 6, 7), (7, 6, 5)];
+        let barrier = std::sync::Barrier::new(threads * shapes.len());
+        let entries: Vec<(ShapeKey, Arc<JitEntry>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .flat_map(|_| shapes)
+                .map(|shape| {
+                    let (cache, barrier) = (&cache, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        let entry =
+                            get_f32(cache, shape, &JitOptions::new()).expect("Failed to compile");
+                        (shape, entry)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let stats = cache.stats();
+        assert_eq!(stats.misses, shapes.len() as u64, "one compile per key");
+        assert_eq!(stats.hits, ((threads - 1) * shapes.len()) as u64);
+        assert!(
+            stats
+                .kernels
+                .iter()
+                .all(|kernel| kernel.calls == threads as u64)
+        );
+        for shape in shapes {
+            let mut same_shape = entries.iter().filter(|(s, _)| *s == shape);
+            let (_, first) = same_shape.next().unwrap();
+            assert!(same_shape.all(|(_, entry)| Arc::ptr_eq(first, entry)));
+        }
+    }
+
+    #[test]
+    fn test_in_flight_failure_is_shared() {
+        let map = RwLock::new(CacheMap::default());
+        let key = JitKey::new::<LlMatmulJitSig>(
+            (2, 2, 2),
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            "ir",
+            DEFAULT_FUNCTION_NAME_JIT_CPU,
+            &CodegenOptions {
+                cpu: "x86-64".to_string(),
+                features: String::new(),
+                opt_level: OptimizationLevel::Aggressive,
+                passes: LOWER_MATRIX_INTRINSICS.to_string(),
+                vectorize: true,
+                unroll: true,
+                verify_each: false,
+                reloc_mode: RelocMode::PIC,
+                code_model: CodeModel::JITDefault,
+            },
+        );
+        let flight = Arc::new(InFlight::default());
+        map.write()
+            .unwrap()
+            .in_flight
+            .insert(key.clone(), flight.clone());
+
+        let failed = std::thread::scope(|scope| {
+            let waiters: Vec<_> = (0..4).map(|_| scope.spawn(|| flight.wait())).collect();
+            let mut landing = Landing {
+                map: &map,
+                key: &key,
+                outcome: None,
+            };
+            landing.outcome = Some(Err(MatmulError::EngineCreation("no JIT".to_string())));
+            drop(landing);
+            waiters
+                .into_iter()
+                .map(|waiter| waiter.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // every waiter gets the leader's error, none compiles again
+        assert!(failed.iter().all(|outcome| matches!(
+            outcome,
+            Some(Err(MatmulError::EngineCreation(msg))) if msg == "no JIT"
+        )));
+        assert!(map.read().unwrap().in_flight.is_empty());
+
+        // a panicking compile leaves no error to share, the waiters compile themselves
+        let flight = Arc::new(InFlight::default());
+        map.write()
+            .unwrap()
+            .in_flight
+            .insert(key.clone(), flight.clone());
+        drop(Landing {
+            map: &map,
+            key: &key,
+            outcome: None,
+        });
+        assert!(flight.wait().is_none());
+    }
+
+    #[test]
+    fn test_jit_cache_shared_map() {
+        let cache = JitCache::new();
+        let get =
+            |options: &JitOptions| get_f32(&cache, (2, 2, 2), options).expect("Failed to compile");
+
+        let first = get(&JitOptions::new());
+        // hits from this thread and from another one, through the same map
+        assert!(Arc::ptr_eq(&first, &get(&JitOptions::new())));
+        let other = std::thread::scope(|scope| scope.spawn(|| get(&JitOptions::new())).join());
+        assert!(Arc::ptr_eq(&first, &other.unwrap()));
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses, stats.kernels[0].calls),
+            (2, 1, 3)
+        );
+
+        // other options with the same IR and codegen share the kernel
+        let same_template = JitOptions::new().template(DEFAULT_IR_TEMPLATE_JIT_CPU);
+        assert!(Arc::ptr_eq(&first, &get(&same_template)));
+        assert_eq!(cache.len(), 1);
+
+        // a kernel removed while another thread holds it keeps working there,
+        // and is freed with the last holder
+        let first_weak = Arc::downgrade(&first);
+        let (held, removed) = (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        std::thread::scope(|scope| {
+            let holder = scope.spawn(|| {
+                let kernel = get(&JitOptions::new());
+                held.wait();
+                removed.wait();
+                let (a, b, mut c) = ([1.0f32; 4], [2.0f32; 4], [0.0f32; 4]);
+                unsafe { kernel.call(a.as_ptr(), b.as_ptr(), c.as_mut_ptr()) };
+                c
+            });
+            held.wait();
+            assert_eq!(cache.remove((2, 2, 2)), 1);
+            cache.clear();
+            removed.wait();
+            assert_eq!(holder.join().unwrap(), [4.0f32; 4]);
+        });
+        drop(first);
+        assert!(first_weak.upgrade().is_none());
+        get(&JitOptions::new());
+        assert_eq!(cache.stats().misses, 2);
+    }
+
+    #[test]
+    fn test_jit_cache_keyed_by_layout() {
+        let cache = JitCache::new();
+        // square shape: both layouts instantiate the very same IR
+        let shape: ShapeKey = (2, 2, 2);
+
+        let row_major =
+            get_f32(&cache, shape, &JitOptions::new()).expect("Failed to compile row-major kernel");
+        let col_major = cache
+            .get_or_compile::<LlMatmulJitSig>(
+                shape,
+                MatrixLayout::ColumnMajor,
+                NO_TRANS,
+                ElemTypes::of::<f32>(),
+                &JitOptions::new(),
+            )
+            .expect("Failed to compile column-major kernel");
+        assert!(!Arc::ptr_eq(&row_major, &col_major));
+        assert_eq!(row_major.layout, MatrixLayout::RowMajor);
+        assert_eq!(col_major.layout, MatrixLayout::ColumnMajor);
+    }
+
+    #[test]
+    fn test_jit_cache_keyed_by_signature() {
+        let cache = JitCache::new();
+        let shape: ShapeKey = (2, 2, 2);
+
+        let matmul =
+            get_f32(&cache, shape, &JitOptions::new()).expect("Failed to compile matmul kernel");
+        let gemm = cache
+            .get_or_compile::<LlGemmJitSig>(
+                shape,
+                MatrixLayout::RowMajor,
+                NO_TRANS,
+                ElemTypes::of::<f32>(),
+                &JitOptions::new(),
+            )
+            .expect("Failed to compile gemm kernel");
+        let gemm_again = cache
+            .get_or_compile::<LlGemmJitSig>(
+                shape,
+                MatrixLayout::RowMajor,
+                NO_TRANS,
+                ElemTypes::of::<f32>(),
+                &JitOptions::new(),
+            )
+            .expect("Failed to retrieve gemm kernel");
+        assert!(Arc::ptr_eq(&gemm, &gemm_again));
+        assert_eq!(matmul.layout, gemm.layout);
+        assert_eq!(cache.len(), 2);
+
+        // the gemm template run through the matmul ABI must not hit the gemm entry
+        let result = get_f32(
+            &cache,
+            shape,
+            &JitOptions::new().template(DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU),
+        );
+        assert!(matches!(
+            result,
+            Err(JitError::CompilationFailed(
+                MatmulError::SymbolNotFound { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_jit_cache_keyed_by_trans() {
+        let cache = JitCache::new();
+        let shape: ShapeKey = (2, 3, 4);
+
+        let entries: Vec<_> = [
+            (Trans::No, Trans::No),
+            (Trans::Yes, Trans::No),
+            (Trans::No, Trans::Yes),
+            (Trans::Yes, Trans::Yes),
+        ]
+        .into_iter()
+        .map(|trans| {
+            cache
+                .get_or_compile::<LlGemmJitSig>(
+                    shape,
+                    MatrixLayout::RowMajor,
+                    trans,
+                    ElemTypes::of::<f32>(),
+                    &JitOptions::new(),
+                )
+                .expect("Failed to compile gemm kernel")
+        })
+        .collect();
+        assert_eq!(cache.len(), 4);
+
+        let again = cache
+            .get_or_compile::<LlGemmJitSig>(
+                shape,
+                MatrixLayout::RowMajor,
+                (Trans::Yes, Trans::No),
+                ElemTypes::of::<f32>(),
+                &JitOptions::new(),
+            )
+            .expect("Failed to retrieve gemm kernel");
+        assert!(Arc::ptr_eq(&entries[1], &again));
+    }
+
+    #[test]
+    fn test_should_specialize() {
+        let cache = JitCache::new();
+        let policy = Specialization::AfterCalls(3);
+        let calls: Vec<bool> = (0..4)
+            .map(|_| cache.should_specialize::<f32>((2, 3, 4), policy))
+            .collect();
+        assert_eq!(calls, [false, false, true, true]);
+        // counted per shape and per element type
+        assert!(!cache.should_specialize::<f64>((2, 3, 4), policy));
+        assert!(!cache.should_specialize::<f32>((3, 2, 4), policy));
+
+        assert!(cache.should_specialize::<f32>((5, 5, 5), Specialization::Always));
+        assert!(!cache.should_specialize::<f32>((5, 5, 5), Specialization::Never));
+        assert!(cache.should_specialize::<f32>((6, 6, 6), Specialization::AfterCalls(1)));
+
+        // counted across threads: exactly the first n - 1 calls, whichever thread made them, don't
+        let policy = Specialization::AfterCalls(400);
+        let specialized: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..100)
+                            .filter(|_| cache.should_specialize::<f32>((7, 7, 7), policy))
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(specialized, 800 - 399);
+    }
+
+    #[test]
+    fn test_jit_cache_hits_disk() {
+        let dir = env::temp_dir().join(format!("llmm-jit-disk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let shape: ShapeKey = (3, 5, 4);
+        let a: Vec<f32> = (0..12).map(|v| v as f32).collect();
+        let b: Vec<f32> = (0..20).map(|v| (v % 3) as f32).collect();
+        let run = |cache: &JitCache| {
+            let entry =
+                get_f32(cache, shape, &JitOptions::new()).expect("Failed to compile kernel");
+            let mut result = vec![0.0f32; 15];
+            unsafe { entry.call(a.as_ptr(), b.as_ptr(), result.as_mut_ptr()) };
+            result
+        };
+
+        let first = JitCache::with_disk_cache(DiskCache::new(&dir));
+        let expected = run(&first);
+        let disk = first.disk.as_ref().unwrap();
+        assert_eq!((disk.loads(), disk.stores()), (0, 1));
+
+        // a fresh in-memory cache (as in the next process) links the stored object code
+        let second = JitCache::with_disk_cache(DiskCache::new(&dir));
+        assert_eq!(run(&second), expected);
+        let disk = second.disk.as_ref().unwrap();
+        assert_eq!((disk.loads(), disk.stores()), (1, 0));
+        let sizes = |cache: &JitCache| {
+            let kernel = &cache.stats().kernels[0];
+            (kernel.ir_bytes, kernel.code_bytes)
+        };
+        assert_eq!(sizes(&second), sizes(&first));
+
+        // a corrupted entry is ignored and rewritten
+        for entry in fs::read_dir(&dir).unwrap() {
+            fs::write(entry.unwrap().path(), b"not an entry").unwrap();
+        }
+        let third = JitCache::with_disk_cache(DiskCache::new(&dir));
+        assert_eq!(run(&third), expected);
+        let disk = third.disk.as_ref().unwrap();
+        assert_eq!((disk.loads(), disk.stores()), (0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,48 @@
+use std::any::Any;
+
+use crate::common::Scalar;
+
 // check build.rs for the build process
 #[allow(unused)]
 unsafe extern "C" {
     #[link_name = "ll_matmul_4x4"]
     pub unsafe fn ll_matmul_4x4(a: *const f32, b: *const f32, result: *mut f32);
+    #[link_name = "ll_matmul_4x4_f64"]
+    pub unsafe fn ll_matmul_4x4_f64(a: *const f64, b: *const f64, result: *mut f64);
     #[link_name = "ll_matmul_4x4_unrolled"]
     pub unsafe fn ll_matmul_4x4_unrolled(a: *const f32, b: *const f32, result: *mut f32);
 }
+
+/// A kernel build.rs compiled from the AOT manifest (`aot_kernels.txt`),
+/// instantiated like the JIT's row-major kernels: `func` takes `b` first.
+pub(crate) struct AotKernel<T: 'static> {
+    pub(crate) shape: (usize, usize, usize),
+    // FNV-1a of the template it was instantiated from
+    pub(crate) template_hash: u64,
+    // `align:` of the template header, the dispatch skips it for less aligned operands
+    pub(crate) align: usize,
+    pub(crate) func: unsafe extern "C" fn(*const T, *const T, *mut T),
+}
+
+impl<T> AotKernel<T> {
+    /// `result = a * b`, row major.
+    /// # Safety
+    /// `a`, `b` and `result` must point to buffers of `self.shape`.
+    #[inline(always)]
+    pub(crate) unsafe fn call(&self, a: *const T, b: *const T, result: *mut T) {
+        unsafe { (self.func)(b, a, result) }
+    }
+}
+
+// AOT_KERNELS_F32 / AOT_KERNELS_F64 and the extern declarations they point to
+include!(concat!(env!("OUT_DIR"), "/aot_kernels.rs"));
+
+/// The AOT kernels of element type `T`, empty without a manifest.
+pub(crate) fn aot_kernels<T: Scalar>() -> &'static [AotKernel<T>] {
+    let tables: [&dyn Any; 2] = [&AOT_KERNELS_F32, &AOT_KERNELS_F64];
+    tables
+        .into_iter()
+        .find_map(|table| table.downcast_ref::<&'static [AotKernel<T>]>())
+        .copied()
+        .unwrap_or_default()
+}
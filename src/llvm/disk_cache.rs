@@ -0,0 +1,220 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::common::JIT_CACHE_DIR_ENV;
+use crate::llvm::template::fnv1a;
+
+// bump when the entry layout (or what goes into a key) changes
+const MAGIC: &[u8; 8] = b"LLMMOB01";
+
+/// Compiled kernels kept across processes, one file per kernel.
+///
+/// An entry is the kernel's object code, as the target machine emitted it after
+/// `lower-matrix-intrinsics` and the optimization pipeline. Loading one skips the
+/// lowering and the codegen, the object is only linked.
+///
+/// Layout of an entry: `MAGIC`, key length (u32 LE), key, payload length (u64 LE),
+/// FNV-1a of the payload (u64 LE), payload. The file name is a hash of the key,
+/// the key itself is stored and compared on load, so a hash collision or an entry
+/// written by another LLVM / crate version is a miss, not a wrong kernel.
+#[derive(Debug)]
+pub(crate) struct DiskCache {
+    dir: PathBuf,
+    // entries loaded / written by this instance
+    loads: AtomicUsize,
+    stores: AtomicUsize,
+}
+
+static DISK_CACHE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// names the temporary file of each `store`, threads of a process can store the same key at once
+static STORE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the directory compiled kernels are persisted to, `None` falls back to
+/// `LL_MATMUL_CACHE_DIR` (no disk cache if that's unset too).
+/// The directory is created if it doesn't exist.
+pub fn set_jit_cache_dir(dir: Option<PathBuf>) -> io::Result<()> {
+    if let Some(dir) = &dir {
+        fs::create_dir_all(dir)?;
+    }
+    *DISK_CACHE_DIR.write().unwrap() = dir;
+    Ok(())
+}
+
+impl DiskCache {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            loads: AtomicUsize::new(0),
+            stores: AtomicUsize::new(0),
+        }
+    }
+
+    /// The cache set with [`set_jit_cache_dir`], else the one `LL_MATMUL_CACHE_DIR` points to.
+    /// Resolved on every compile, like the template env vars.
+    pub(crate) fn from_env() -> Option<Self> {
+        if let Some(dir) = DISK_CACHE_DIR.read().unwrap().as_ref() {
+            return Some(Self::new(dir));
+        }
+        env::var_os(JIT_CACHE_DIR_ENV).map(Self::new)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn loads(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn stores(&self) -> usize {
+        self.stores.load(Ordering::Relaxed)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}.llmm", fnv1a(key.as_bytes())))
+    }
+
+    /// The payload stored for `key`, `None` if it's missing, stale or corrupted.
+    pub(crate) fn load(&self, key: &str) -> Option<Vec<u8>> {
+        let bytes = fs::read(self.entry_path(key)).ok()?;
+        let payload = decode_entry(&bytes, key)?;
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        Some(payload.to_vec())
+    }
+
+    /// Writes `payload` for `key`, replacing any previous entry.
+    /// The entry is written next to its final path and renamed,
+    /// so a concurrent reader never sees half of it.
+    pub(crate) fn store(&self, key: &str, payload: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.entry_path(key);
+        let seq = STORE_SEQ.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("tmp{}-{}", std::process::id(), seq));
+        fs::write(&tmp, encode_entry(key, payload))?;
+        fs::rename(&tmp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+        self.stores.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn encode_entry(key: &str, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + key.len() + 16 + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn decode_entry<'a>(bytes: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let rest = bytes.strip_prefix(MAGIC)?;
+    let (key_len, rest) = rest.split_first_chunk::<4>()?;
+    let key_len = u32::from_le_bytes(*key_len) as usize;
+    let (stored_key, rest) = rest.split_at_checked(key_len)?;
+    if stored_key != key.as_bytes() {
+        return None;
+    }
+    let (payload_len, rest) = rest.split_first_chunk::<8>()?;
+    let (checksum, payload) = rest.split_first_chunk::<8>()?;
+    if payload.len() as u64 != u64::from_le_bytes(*payload_len)
+        || fnv1a(payload) != u64::from_le_bytes(*checksum)
+    {
+        return None;
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("llmm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_disk_cache_round_trip() {
+        let dir = temp_dir("round-trip");
+        let cache = DiskCache::new(&dir);
+        assert_eq!(cache.load("key"), None);
+        cache.store("key", b"object").unwrap();
+        assert_eq!(cache.stores(), 1);
+
+        // another instance on the same directory sees the entry
+        let other = DiskCache::new(&dir);
+        assert_eq!(other.load("key").as_deref(), Some(&b"object"[..]));
+        assert_eq!(other.loads(), 1);
+        assert_eq!(other.load("other key"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_rejects_corrupted_entries() {
+        let dir = temp_dir("corrupted");
+        let cache = DiskCache::new(&dir);
+        cache.store("key", b"object").unwrap();
+        let path = cache.entry_path("key");
+        let entry = fs::read(&path).unwrap();
+
+        // flipped payload byte, truncated entry, garbage
+        let mut flipped = entry.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        for bad in [
+            flipped,
+            entry[..entry.len() - 1].to_vec(),
+            b"garbage".to_vec(),
+        ] {
+            fs::write(&path, bad).unwrap();
+            assert_eq!(cache.load("key"), None);
+        }
+        assert_eq!(cache.loads(), 0);
+
+        // rewritten on the next store
+        cache.store("key", b"object").unwrap();
+        assert_eq!(cache.load("key").as_deref(), Some(&b"object"[..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_concurrent_stores() {
+        // threads of one process writing the same key don't share a temporary file
+        let dir = temp_dir("concurrent");
+        let cache = DiskCache::new(&dir);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        cache.store("key", b"object").unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.stores(), 160);
+        assert_eq!(cache.load("key").as_deref(), Some(&b"object"[..]));
+        // only the entry is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_rejects_stale_key() {
+        // same file, key written by another version: a miss
+        let dir = temp_dir("stale");
+        let cache = DiskCache::new(&dir);
+        let path = cache.entry_path("v2|key");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, encode_entry("v1|key", b"object")).unwrap();
+        assert_eq!(cache.load("v2|key"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::llvm::template::TemplateError;
+
+/// Errors returned by the fallible (`try_*`) CPU JIT entry points.
+/// `Clone`, a compile that failed fails the same way for every lookup that waited for it.
+#[derive(Debug, Clone)]
+pub enum MatmulError {
+    /// The operands can't be multiplied: empty, inner dimensions disagree,
+    /// or a slice is shorter than its declared shape.
+    ShapeMismatch {
+        a_shape: (usize, usize),
+        b_shape: (usize, usize),
+        reason: &'static str,
+    },
+    /// The template file pointed to by `LL_MATMUL_TEMPLATE` couldn't be read.
+    TemplateIo {
+        path: String,
+        source: Arc<io::Error>,
+    },
+    /// The template has none of the `{M}`, `{N}`, `{K}` placeholders,
+    /// most likely a hardcoded `.ll` file used as a template.
+    TemplateMissingPlaceholder,
+    /// The template uses some of the `{M}`, `{N}`, `{K}` dimensions but not all,
+    /// directly or through a derived placeholder like `{VEC_A_SIZE}`.
+    TemplateUnusedPlaceholders { names: Vec<&'static str> },
+    /// A `{NAME}` placeholder the kernel isn't instantiated with, most likely a typo.
+    /// `line` is 1-based in the template.
+    TemplateUnknownPlaceholder { name: String, line: usize },
+    /// A placeholder expression like `{K/8}` is malformed, divides by zero,
+    /// overflows or goes below 0.
+    TemplateExpression {
+        expr: String,
+        line: usize,
+        reason: String,
+    },
+    /// A template header line isn't a known `;; key: value`, `line` is 1-based in the template.
+    TemplateHeader { line: usize, reason: String },
+    /// The `abi:` of the template header isn't the signature of the requested kernel.
+    TemplateAbiMismatch {
+        expected: &'static str,
+        declared: String,
+    },
+    /// The shape doesn't meet a `require:` of the template header, which has no `fallback: default`.
+    /// `shape` is the (m, n, k) the template is instantiated with.
+    TemplateRequirement {
+        requirement: String,
+        shape: (usize, usize, usize),
+    },
+    /// An operand is less aligned than the `align:` of the template header,
+    /// which has no `fallback: default`.
+    MisalignedOperand { align: usize },
+    /// A transposed operand was requested, but the template has no
+    /// `{TRANS_A}`/`{TRANS_B}` placeholder to honour it (e.g. the unrolled ones).
+    TransposeUnsupported { placeholder: &'static str },
+    /// A non-`f32` kernel was requested from a template without an `{ELEM_TY}` placeholder.
+    ElemTypeUnsupported { elem: &'static str },
+    /// A half-precision kernel was requested from a template without the
+    /// `{ACC_TY}`/`{OUT_TY}` placeholders, it would accumulate in the storage type.
+    MixedPrecisionUnsupported {
+        elem: &'static str,
+        out: &'static str,
+    },
+    /// Checked integer arithmetic was requested from a template without a `{CHECKED}` placeholder.
+    OverflowCheckUnsupported,
+    /// A checked integer kernel overflowed its accumulator.
+    IntegerOverflow,
+    /// LLVM rejected the instantiated IR.
+    /// `line`/`column` point into the instantiated IR, not the template.
+    IrParse {
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// The LLVM verifier rejected the module, `stage` is `instantiated`
+    /// (the template is broken) or `lowered`/`optimized` (a pass broke it).
+    IrVerify {
+        stage: &'static str,
+        message: String,
+    },
+    /// Target lookup or the `lower-matrix-intrinsics` pipeline failed.
+    PassPipeline(String),
+    /// The ORC JIT couldn't be created, or couldn't link the kernel's object code.
+    EngineCreation(String),
+    /// The target machine couldn't emit the assembly of an inspected kernel,
+    /// or the object code of a compiled one.
+    AsmEmission(String),
+    /// The entry point isn't defined by the compiled module.
+    SymbolNotFound { name: String, reason: String },
+    /// The entry point doesn't have the signature of the requested kernel,
+    /// both spelled as LLVM prints function types (`void (ptr, ptr, ptr)`).
+    SignatureMismatch {
+        name: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for MatmulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatmulError::ShapeMismatch {
+                a_shape,
+                b_shape,
+                reason,
+            } => write!(f, "{} (a: {:?}, b: {:?})", reason, a_shape, b_shape),
+            MatmulError::TemplateIo { path, source } => {
+                write!(f, "Failed to read template from {}: {}", path, source)
+            }
+            MatmulError::TemplateMissingPlaceholder => write!(
+                f,
+                "Template must contain placeholders like {{M}}, {{N}}, {{K}} for matrix dimensions. \
+                 If using a hardcoded IR file (like matmul_4x4.ll), do not use it as a template for different sizes."
+            ),
+            MatmulError::TemplateUnusedPlaceholders { names } => write!(
+                f,
+                "Template never uses the {} placeholder(s), the kernel would ignore that dimension",
+                names
+                    .iter()
+                    .map(|name| format!("{{{}}}", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            MatmulError::TemplateUnknownPlaceholder { name, line } => write!(
+                f,
+                "Unknown template placeholder {{{}}} at line {}",
+                name, line
+            ),
+            MatmulError::TemplateExpression { expr, line, reason } => write!(
+                f,
+                "Bad template placeholder {{{}}} at line {}: {}",
+                expr, line, reason
+            ),
+            MatmulError::TemplateHeader { line, reason } => {
+                write!(f, "Bad template header at line {}: {}", line, reason)
+            }
+            MatmulError::TemplateAbiMismatch { expected, declared } => write!(
+                f,
+                "Template declares the ABI {}, the kernel needs {}",
+                declared, expected
+            ),
+            MatmulError::TemplateRequirement { requirement, shape } => write!(
+                f,
+                "Template requires {}, not met by (M, N, K) = {:?}",
+                requirement, shape
+            ),
+            MatmulError::MisalignedOperand { align } => {
+                write!(f, "Template requires operands aligned to {} bytes", align)
+            }
+            MatmulError::TransposeUnsupported { placeholder } => write!(
+                f,
+                "Template has no {} placeholder, it can't be used with a transposed operand",
+                placeholder
+            ),
+            MatmulError::ElemTypeUnsupported { elem } => write!(
+                f,
+                "Template has no {{ELEM_TY}} placeholder, it can't be instantiated for {}",
+                elem
+            ),
+            MatmulError::MixedPrecisionUnsupported { elem, out } => write!(
+                f,
+                "Template has no {{ACC_TY}}/{{OUT_TY}} placeholders, it can't load {} and store {}",
+                elem, out
+            ),
+            MatmulError::OverflowCheckUnsupported => write!(
+                f,
+                "Template has no {{CHECKED}} placeholder, it can't be used with checked arithmetic"
+            ),
+            MatmulError::IntegerOverflow => write!(f, "Integer overflow in the matmul accumulator"),
+            MatmulError::IrParse {
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(
+                f,
+                "Failed to parse LLVM IR at {}:{}: {}",
+                line, column, message
+            ),
+            MatmulError::IrParse { message, .. } => {
+                write!(f, "Failed to parse LLVM IR: {}", message)
+            }
+            MatmulError::IrVerify { stage, message } => {
+                write!(f, "Invalid {} LLVM IR: {}", stage, message)
+            }
+            MatmulError::PassPipeline(msg) => write!(f, "Failed to lower LLVM IR: {}", msg),
+            MatmulError::EngineCreation(msg) => {
+                write!(f, "Failed to create JIT or link the kernel: {}", msg)
+            }
+            MatmulError::AsmEmission(msg) => write!(f, "Failed to emit machine code: {}", msg),
+            MatmulError::SymbolNotFound { name, reason } => {
+                write!(f, "Failed to find JIT function {} : {}", name, reason)
+            }
+            MatmulError::SignatureMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "JIT function {} has the type {}, the kernel needs {}",
+                name, found, expected
+            ),
+        }
+    }
+}
+
+impl From<TemplateError> for MatmulError {
+    fn from(err: TemplateError) -> Self {
+        match err {
+            TemplateError::UnknownPlaceholder { name, line } => {
+                MatmulError::TemplateUnknownPlaceholder { name, line }
+            }
+            TemplateError::BadExpression { expr, line, reason } => {
+                MatmulError::TemplateExpression { expr, line, reason }
+            }
+            TemplateError::BadHeader { line, reason } => {
+                MatmulError::TemplateHeader { line, reason }
+            }
+        }
+    }
+}
+
+impl std::error::Error for MatmulError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MatmulError::TemplateIo { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// Extracts `line:column` from an LLVM parser diagnostic,
+/// which looks like `matmul_ir:4:20: error: expected type`.
+pub(crate) fn parse_ir_diagnostic(message: &str) -> (Option<usize>, Option<usize>) {
+    let first_line = message.lines().next().unwrap_or_default();
+    let mut parts = first_line.split(':');
+    // skip the buffer name, then look for the first two numeric fields
+    parts.next();
+    let line = parts.next().and_then(|p| p.trim().parse().ok());
+    let column = line.and(parts.next().and_then(|p| p.trim().parse().ok()));
+    (line, column)
+}
@@ -1,158 +1,1564 @@
 use core::panic;
-use std::collections::{HashMap, hash_map::Entry};
 use std::env;
-use std::ffi::CStr;
-use std::fs;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::ffi::{CStr, CString};
+use std::hash::Hash;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::common::{DEFAULT_FUNCTION_NAME_JIT_CPU, TEMPLATE_JIT_CPU_ENV};
-use crate::common::{DEFAULT_IR_TEMPLATE_JIT_CPU, TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{DEFAULT_FUNCTION_NAME_BATCHED_JIT_CPU, DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU};
+use crate::common::{DEFAULT_FUNCTION_NAME_DYN_JIT_CPU, DEFAULT_IR_TEMPLATE_DYN_JIT_CPU};
+use crate::common::{DEFAULT_FUNCTION_NAME_GEMM_JIT_CPU, DEFAULT_FUNCTION_NAME_JIT_CPU};
+use crate::common::{DEFAULT_FUNCTION_NAME_HALF_JIT_CPU, DEFAULT_IR_TEMPLATE_HALF_JIT_CPU};
+use crate::common::{DEFAULT_FUNCTION_NAME_INT_JIT_CPU, DEFAULT_IR_TEMPLATE_INT_JIT_CPU};
+use crate::common::{DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU, DEFAULT_IR_TEMPLATE_JIT_CPU};
+use crate::common::{HalfFormat, HalfOutput, IntAccumulator, IntScalar, Overflow, Scalar};
+use crate::common::{NAIVE_TEMPLATE_MAX_DIM, TILED_IR_TEMPLATE_JIT_CPU};
+use crate::common::{TEMPLATE_BATCHED_JIT_CPU_ENV, TEMPLATE_BATCHED_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{TEMPLATE_DYN_JIT_CPU_ENV, TEMPLATE_DYN_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{TEMPLATE_GEMM_JIT_CPU_ENV, TEMPLATE_GEMM_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{TEMPLATE_HALF_JIT_CPU_ENV, TEMPLATE_HALF_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{TEMPLATE_INT_JIT_CPU_ENV, TEMPLATE_INT_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::common::{TEMPLATE_JIT_CPU_ENV, TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME};
+use crate::llvm::compiled::{AotKernel, aot_kernels};
+use crate::llvm::disk_cache::DiskCache;
+use crate::llvm::error::{MatmulError, parse_ir_diagnostic};
+use crate::llvm::options::resolve_template;
+use crate::llvm::options::{CodegenOptions, LOWER_MATRIX_INTRINSICS, resolve_options};
+use crate::llvm::orc::LinkedObject;
+use crate::llvm::template::{Bindings, IrType, MATMUL_ABI, fnv1a, instantiate, parse_header};
 
-use inkwell::OptimizationLevel;
 use inkwell::context::Context;
-use inkwell::execution_engine::JitFunction;
+use inkwell::execution_engine::UnsafeFunctionPointer;
 use inkwell::llvm_sys;
 use inkwell::llvm_sys::core::LLVMDisposeMessage;
 use inkwell::llvm_sys::error::LLVMGetErrorMessage;
 use inkwell::memory_buffer::MemoryBuffer;
 use inkwell::module::Module;
 use inkwell::passes::PassBuilderOptions;
-use inkwell::targets::{CodeModel, RelocMode, Target, TargetMachine};
+use inkwell::support::get_llvm_version;
+use inkwell::targets::{FileType, Target, TargetMachine};
 
-type LlMatmulJitSig = unsafe extern "C" fn(*const f32, *const f32, *mut f32);
-type ShapeKey = (usize, usize, usize);
+// the cache and the options have modules of their own, their public paths stay here too
+pub use crate::llvm::cache::JIT_CACHE;
+pub use crate::llvm::cache::JitCache;
+pub use crate::llvm::cache::JitCacheCapacity;
+pub use crate::llvm::cache::JitCacheStats;
+pub use crate::llvm::cache::KernelStats;
+pub use crate::llvm::cache::jit_cache;
+pub use crate::llvm::cache::set_jit_cache_capacity;
+pub use crate::llvm::options::JitOptions;
+pub use crate::llvm::options::TemplateSource;
+pub use crate::llvm::options::set_jit_options;
 
-pub static JIT_CACHE: OnceLock<JitCache> = OnceLock::new();
+/// `result = a * b`
+pub type LlMatmulJitSig<T = f32> = unsafe extern "C" fn(*const T, *const T, *mut T);
+/// `result[i] = a[i] * b[i]` for `i < batch`, item `i` of `a` starts at `a + i * a_batch_stride`
+/// (resp. `b`, `result`), the strides are in elements: `(a, b, result, batch, a_batch_stride, b_batch_stride, c_batch_stride)`.
+pub type LlBatchedMatmulJitSig<T = f32> =
+    unsafe extern "C" fn(*const T, *const T, *mut T, u64, u64, u64, u64);
+/// `result(m×n) = a(m×k) * b(k×n)` for any shape, column major with leading dimensions
+/// (column strides, in elements): `(a, b, result, m, n, k, lda, ldb, ldc)`.
+pub type LlDynMatmulJitSig<T = f32> =
+    unsafe extern "C" fn(*const T, *const T, *mut T, u64, u64, u64, u64, u64, u64);
+/// `c = alpha * a * b + beta * c`, `c` is read (unless `beta == 0`) and written in place.
+pub type LlGemmJitSig<T = f32> = unsafe extern "C" fn(T, *const T, *const T, T, *mut T);
+/// `result = a * b` on [`HalfFormat`] bit patterns, accumulated in `f32`.
+/// `result` is `f32` or, for `O = u16`, truncated back to the input format.
+pub type LlHalfMatmulJitSig<O = f32> = unsafe extern "C" fn(*const u16, *const u16, *mut O);
+/// `result = a * b` on integers, summed in `I::Acc`.
+/// Returns non-zero if a checked kernel overflowed, wrapping kernels always return 0.
+pub type LlIntMatmulJitSig<I = i8> =
+    unsafe extern "C" fn(*const I, *const I, *mut <I as IntScalar>::Acc) -> i32;
+pub(crate) type ShapeKey = (usize, usize, usize);
+pub(crate) type TransKey = (Trans, Trans);
 
-#[allow(dead_code)]
-pub struct JitEntry {
-    // ExecutionEngine and JitFunction hold references to LLVM objects
-    // that must not be dropped. We use Box::leak to convert to 'static references.
-    pub func: JitFunction<'static, LlMatmulJitSig>,
+pub(crate) const NO_TRANS: TransKey = (Trans::No, Trans::No);
+// the shape a shape-generic kernel is cached and instantiated under,
+// real shapes are never empty (see `check_dims`)
+const DYNAMIC_SHAPE: ShapeKey = (0, 0, 0);
+
+/// Ties a kernel signature to the template and entry point it's compiled from.
+pub(crate) trait KernelAbi: UnsafeFunctionPointer + Copy + Send + Sync + 'static {
+    const DEFAULT_TEMPLATE: &'static str;
+    /// Replaces `DEFAULT_TEMPLATE` past `NAIVE_TEMPLATE_MAX_DIM`.
+    const TILED_TEMPLATE: Option<&'static str> = None;
+    const TEMPLATE_ENV: &'static str;
+    const DEFAULT_FUNCTION_NAME: &'static str;
+    const FUNCTION_NAME_ENV: &'static str;
+    /// Signature of the entry point, as the `abi:` of a template header spells it.
+    const ABI: &'static str;
+    /// Whether the template and the function name of [`set_jit_options`] are for it.
+    const PROCESS_TEMPLATE: bool = false;
+}
+
+impl<T: Scalar> KernelAbi for LlMatmulJitSig<T> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_JIT_CPU;
+    const TILED_TEMPLATE: Option<&'static str> = Some(TILED_IR_TEMPLATE_JIT_CPU);
+    const TEMPLATE_ENV: &'static str = TEMPLATE_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = MATMUL_ABI;
+    const PROCESS_TEMPLATE: bool = true;
+}
+
+impl<T: Scalar> KernelAbi for LlGemmJitSig<T> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU;
+    const TEMPLATE_ENV: &'static str = TEMPLATE_GEMM_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_GEMM_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_GEMM_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = "(elem, ptr, ptr, elem, ptr) -> void";
+}
+
+impl<T: Scalar> KernelAbi for LlBatchedMatmulJitSig<T> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU;
+    const TEMPLATE_ENV: &'static str = TEMPLATE_BATCHED_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_BATCHED_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_BATCHED_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = "(ptr, ptr, ptr, i64, i64, i64, i64) -> void";
 }
 
-// Context and ExecutionEngine are not modified after creation.
-// every JitEntry has its own Context and ExecutionEngine, so there is no risk of data race.
-unsafe impl Send for JitEntry {}
-unsafe impl Sync for JitEntry {}
+impl<T: Scalar> KernelAbi for LlDynMatmulJitSig<T> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_DYN_JIT_CPU;
+    const TEMPLATE_ENV: &'static str = TEMPLATE_DYN_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_DYN_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_DYN_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = "(ptr, ptr, ptr, i64, i64, i64, i64, i64, i64) -> void";
+}
+
+impl<O: HalfOutput> KernelAbi for LlHalfMatmulJitSig<O> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_HALF_JIT_CPU;
+    const TEMPLATE_ENV: &'static str = TEMPLATE_HALF_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_HALF_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_HALF_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = MATMUL_ABI;
+}
+
+impl<I: IntScalar> KernelAbi for LlIntMatmulJitSig<I> {
+    const DEFAULT_TEMPLATE: &'static str = DEFAULT_IR_TEMPLATE_INT_JIT_CPU;
+    const TEMPLATE_ENV: &'static str = TEMPLATE_INT_JIT_CPU_ENV;
+    const DEFAULT_FUNCTION_NAME: &'static str = DEFAULT_FUNCTION_NAME_INT_JIT_CPU;
+    const FUNCTION_NAME_ENV: &'static str = TEMPLATE_INT_JIT_CPU_ENV_FUNCTION_NAME;
+    const ABI: &'static str = "(ptr, ptr, ptr) -> i32";
+}
+
+impl IrType {
+    fn of<T: Scalar>() -> Self {
+        Self {
+            ty: T::IR_TYPE,
+            suffix: T::IR_SUFFIX,
+            bytes: T::BYTES,
+        }
+    }
+
+    fn half(format: HalfFormat) -> Self {
+        Self {
+            ty: format.ir_type(),
+            suffix: format.ir_suffix(),
+            bytes: 2,
+        }
+    }
+
+    fn int<I: IntScalar>() -> Self {
+        Self {
+            ty: I::IR_TYPE,
+            suffix: I::IR_SUFFIX,
+            bytes: I::BYTES,
+        }
+    }
 
-pub struct JitCache {
-    map: Mutex<HashMap<ShapeKey, Arc<JitEntry>>>,
+    fn int_acc<A: IntAccumulator>() -> Self {
+        Self {
+            ty: A::IR_TYPE,
+            suffix: A::IR_SUFFIX,
+            bytes: A::BYTES,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum JitError {
-    CompilationFailed(String),
+/// Element types a template is instantiated with:
+/// `{ELEM_*}` for `a` and `b`, `{ACC_*}` for the sums, `{OUT_*}` for the result.
+/// They are all the same type except for the half-precision and the narrow integer kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ElemTypes {
+    elem: IrType,
+    acc: IrType,
+    out: IrType,
+    /// `{ELEM_EXT}`, widens `elem` to `acc`
+    ext: &'static str,
+    /// `{CHECKED}`, only the integer templates honour it
+    overflow: Overflow,
 }
 
-impl JitCache {
-    fn new() -> Self {
+impl ElemTypes {
+    pub(crate) fn of<T: Scalar>() -> Self {
+        let ty = IrType::of::<T>();
+        Self {
+            elem: ty,
+            acc: ty,
+            out: ty,
+            ext: "bitcast",
+            overflow: Overflow::Wrapping,
+        }
+    }
+
+    fn half<O: HalfOutput>(format: HalfFormat) -> Self {
+        let storage = IrType::half(format);
+        let acc = IrType::of::<f32>();
         Self {
-            map: Mutex::new(HashMap::new()),
+            elem: storage,
+            acc,
+            out: if O::WIDENED { acc } else { storage },
+            ext: "fpext",
+            overflow: Overflow::Wrapping,
+        }
+    }
+
+    fn int<I: IntScalar>(overflow: Overflow) -> Self {
+        let acc = IrType::int_acc::<I::Acc>();
+        Self {
+            elem: IrType::int::<I>(),
+            acc,
+            out: acc,
+            ext: I::IR_EXT,
+            overflow,
+        }
+    }
+
+    /// Cast from the accumulator to the result type, substituted for `{OUT_CAST}`.
+    fn out_cast(&self) -> &'static str {
+        if self.out == self.acc {
+            // a no-op, so templates don't need a separate path when nothing is narrowed
+            "bitcast"
+        } else {
+            "fptrunc"
+        }
+    }
+}
+
+/// Memory order of `a`, `b` and `result` as seen by the caller of a kernel.
+///
+/// Templates are written against `llvm.matrix.column.major.load/store`.
+/// A row-major kernel is the same template instantiated for
+/// C^T(n×m) = B^T(n×k) * A^T(k×m), since a row-major buffer read as
+/// column-major is the transposed matrix (same trick as `ll_matmul_4x4`).
+/// No data is moved, only the dimensions and the operand order are swapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MatrixLayout {
+    #[default]
+    ColumnMajor,
+    RowMajor,
+}
+
+impl MatrixLayout {
+    /// The (m, n, k) the column-major template has to be instantiated with.
+    fn kernel_shape(self, (m, n, k): ShapeKey) -> ShapeKey {
+        match self {
+            MatrixLayout::ColumnMajor => (m, n, k),
+            MatrixLayout::RowMajor => (n, m, k),
+        }
+    }
+
+    /// The (trans_a, trans_b) of the column-major kernel,
+    /// a row-major kernel gets B as its first operand.
+    fn kernel_trans(self, (trans_a, trans_b): TransKey) -> TransKey {
+        match self {
+            MatrixLayout::ColumnMajor => (trans_a, trans_b),
+            MatrixLayout::RowMajor => (trans_b, trans_a),
+        }
+    }
+}
+
+/// When [`ll_matmul_jit_into`] compiles a kernel for the exact shape it's called with.
+///
+/// A specialized kernel has `m`, `n`, `k` baked in and is the fastest,
+/// but each new shape costs a full parse, lowering and engine creation.
+/// Until then the call runs the shape-generic kernel ([`LlDynMatmulJitSig`]),
+/// compiled once per element type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Specialization {
+    /// Compile every shape on its first call.
+    #[default]
+    Always,
+    /// Compile a shape on its `n`-th call, the earlier ones run the shape-generic kernel.
+    AfterCalls(u32),
+    /// Always run the shape-generic kernel, e.g. for unbounded sequence lengths.
+    Never,
+}
+
+impl Specialization {
+    // packed in one atomic, read on every call: the variant in the high bits, `n` in the low ones
+    fn to_bits(self) -> u64 {
+        match self {
+            Specialization::Always => 0,
+            Specialization::AfterCalls(n) => 1 << 32 | n as u64,
+            Specialization::Never => 2 << 32,
+        }
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        match bits >> 32 {
+            0 => Specialization::Always,
+            1 => Specialization::AfterCalls(bits as u32),
+            _ => Specialization::Never,
+        }
+    }
+}
+
+static SPECIALIZATION: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the [`Specialization`] used by [`ll_matmul_jit_into`] and [`ll_matmul_jit_with_template`].
+pub fn set_specialization(policy: Specialization) {
+    SPECIALIZATION.store(policy.to_bits(), Ordering::Relaxed);
+}
+
+fn specialization() -> Specialization {
+    Specialization::from_bits(SPECIALIZATION.load(Ordering::Relaxed))
+}
+
+/// Where the items of a batch start, as the distance in elements between consecutive items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BatchLayout {
+    /// Items are packed back to back: `m*k`, `k*n` and `m*n` elements apart.
+    #[default]
+    Contiguous,
+    /// Items are `a`, `b` and `out` elements apart.
+    /// A stride of 0 reuses the same `a` (or `b`) for every item.
+    Strided { a: usize, b: usize, out: usize },
+}
+
+impl BatchLayout {
+    /// (a, b, out) strides for items of shape (m, n, k).
+    fn strides(self, (m, n, k): ShapeKey) -> (usize, usize, usize) {
+        match self {
+            BatchLayout::Contiguous => (m * k, k * n, m * n),
+            BatchLayout::Strided { a, b, out } => (a, b, out),
+        }
+    }
+}
+
+/// Whether an operand is used as stored (`op(X) = X`) or transposed (`op(X) = X^T`).
+///
+/// The transpose happens inside the kernel (`llvm.matrix.transpose` on the
+/// loaded operand), the caller's buffer is never copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Trans {
+    #[default]
+    No,
+    Yes,
+}
+
+impl Trans {
+    /// Shape of `op(X)` for an `X` stored as `(rows, cols)`.
+    fn apply(self, (rows, cols): (usize, usize)) -> (usize, usize) {
+        match self {
+            Trans::No => (rows, cols),
+            Trans::Yes => (cols, rows),
+        }
+    }
+
+    fn as_ir(self) -> &'static str {
+        match self {
+            Trans::No => "false",
+            Trans::Yes => "true",
+        }
+    }
+}
+
+/// A compiled kernel, owning the JIT its object code is linked into;
+/// the code is freed when the entry is dropped (for cached kernels: evicted from the
+/// cache and no `Arc` handle left).
+#[allow(dead_code)]
+pub struct JitEntry<F = LlMatmulJitSig> {
+    // points into `object`'s code, private: a copy of it could outlive the code
+    func: F,
+    pub layout: MatrixLayout,
+    /// Alignment in bytes the operands need, the `align:` of the template header (1 without).
+    pub align: usize,
+    /// Size in bytes of the lowered module, as bitcode.
+    pub ir_bytes: usize,
+    /// Size in bytes of the kernel's object code, as the target machine emits it.
+    pub code_bytes: usize,
+    /// Time spent lowering (or loading from the disk cache), code-generating and linking the kernel.
+    pub compile_time: Duration,
+    // the header allows falling back to the default template
+    pub(crate) fallback: bool,
+    object: LinkedObject,
+}
+
+impl<T: Scalar> JitEntry<LlMatmulJitSig<T>> {
+    /// Runs the kernel on operands stored in `self.layout` order.
+    /// # Safety
+    /// `a`, `b` and `result` must point to buffers of the shape the kernel was compiled for.
+    #[inline(always)]
+    pub unsafe fn call(&self, a: *const T, b: *const T, result: *mut T) {
+        unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => (self.func)(a, b, result),
+                MatrixLayout::RowMajor => (self.func)(b, a, result),
+            }
         }
     }
+}
 
-    fn get_or_compile(
+impl<T: Scalar> JitEntry<LlBatchedMatmulJitSig<T>> {
+    /// Runs the kernel on `batch` items stored in `self.layout` order,
+    /// `(a_stride, b_stride, result_stride)` are in elements.
+    /// # Safety
+    /// `a`, `b` and `result` must hold `batch` items of the shape the kernel was compiled for
+    /// at the given strides.
+    #[inline(always)]
+    pub unsafe fn call(
         &self,
-        shape: ShapeKey,
-        ir_template: Option<&str>,
-    ) -> Result<Arc<JitEntry>, JitError> {
-        // First check with read lock (if we had RwLock, but Mutex is fine for now)
-        // Optimization: check if exists before compiling
-        {
-            let map = self.map.lock().unwrap();
-            if let Some(e) = map.get(&shape).cloned() {
-                return Ok(e);
+        a: *const T,
+        b: *const T,
+        result: *mut T,
+        batch: usize,
+        (a_stride, b_stride, result_stride): (usize, usize, usize),
+    ) {
+        let (batch, a_stride, b_stride, result_stride) = (
+            batch as u64,
+            a_stride as u64,
+            b_stride as u64,
+            result_stride as u64,
+        );
+        unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => {
+                    (self.func)(a, b, result, batch, a_stride, b_stride, result_stride)
+                }
+                MatrixLayout::RowMajor => {
+                    (self.func)(b, a, result, batch, b_stride, a_stride, result_stride)
+                }
             }
         }
+    }
+}
 
-        // compile, create a Box<Context>, create module with that context,
-        // create execution_engine, get function, wrap in Arc<JitEntry>
-        let entry = unsafe {
-            compile_matmul_jit_with_template(shape.0, shape.1, shape.2, ir_template)
-                .map_err(JitError::CompilationFailed)?
+impl<T: Scalar> JitEntry<LlDynMatmulJitSig<T>> {
+    /// Runs the kernel on packed `a(m×k)`, `b(k×n)` and `result(m×n)` stored in `self.layout` order.
+    /// # Safety
+    /// `a`, `b` and `result` must hold `m*k`, `k*n` and `m*n` elements.
+    #[inline(always)]
+    pub unsafe fn call(
+        &self,
+        a: *const T,
+        b: *const T,
+        result: *mut T,
+        (m, n, k): (usize, usize, usize),
+    ) {
+        let (lda, ldb, ldc) = match self.layout {
+            MatrixLayout::ColumnMajor => (m, k, m),
+            MatrixLayout::RowMajor => (k, n, n),
         };
+        unsafe { self.call_strided(a, lda, b, ldb, result, ldc, (m, n, k)) }
+    }
 
-        let entry = Arc::new(entry);
+    /// Same as [`JitEntry::call`] on sub-matrices: `lda`, `ldb` and `ldc` are the distance
+    /// in elements between consecutive columns (rows for [`MatrixLayout::RowMajor`]).
+    /// # Safety
+    /// Every element of the three `(m, n, k)` operands must be in bounds at the given strides.
+    #[allow(clippy::too_many_arguments)]
+    #[inline(always)]
+    pub unsafe fn call_strided(
+        &self,
+        a: *const T,
+        lda: usize,
+        b: *const T,
+        ldb: usize,
+        result: *mut T,
+        ldc: usize,
+        (m, n, k): (usize, usize, usize),
+    ) {
+        let (m, n, k) = (m as u64, n as u64, k as u64);
+        let (lda, ldb, ldc) = (lda as u64, ldb as u64, ldc as u64);
+        unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => (self.func)(a, b, result, m, n, k, lda, ldb, ldc),
+                MatrixLayout::RowMajor => (self.func)(b, a, result, n, m, k, ldb, lda, ldc),
+            }
+        }
+    }
+}
+
+impl<O: HalfOutput> JitEntry<LlHalfMatmulJitSig<O>> {
+    /// Runs the kernel on half-precision operands stored in `self.layout` order.
+    /// Not named `call`, so [`JitEntry::call`] still infers `T` from its arguments.
+    /// # Safety
+    /// `a`, `b` and `result` must point to buffers of the shape the kernel was compiled for.
+    #[inline(always)]
+    pub unsafe fn call_half(&self, a: *const u16, b: *const u16, result: *mut O) {
+        unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => (self.func)(a, b, result),
+                MatrixLayout::RowMajor => (self.func)(b, a, result),
+            }
+        }
+    }
+}
 
-        let mut map = self.map.lock().unwrap();
+impl<I: IntScalar> JitEntry<LlIntMatmulJitSig<I>> {
+    /// Runs the kernel on operands stored in `self.layout` order,
+    /// returns `true` if a checked kernel overflowed.
+    /// # Safety
+    /// `a`, `b` and `result` must point to buffers of the shape the kernel was compiled for.
+    #[inline(always)]
+    pub unsafe fn call(&self, a: *const I, b: *const I, result: *mut I::Acc) -> bool {
+        let status = unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => (self.func)(a, b, result),
+                MatrixLayout::RowMajor => (self.func)(b, a, result),
+            }
+        };
+        status != 0
+    }
+}
 
-        // in case another thread compiled it already while we were jit-compiling
-        match map.entry(shape) {
-            Entry::Occupied(e) => Ok(e.get().clone()),
-            Entry::Vacant(e) => {
-                e.insert(entry.clone());
-                Ok(entry)
+impl<T: Scalar> JitEntry<LlGemmJitSig<T>> {
+    /// Runs `c = alpha * a * b + beta * c` on operands stored in `self.layout` order.
+    /// # Safety
+    /// `a`, `b` and `c` must point to buffers of the shape the kernel was compiled for.
+    #[inline(always)]
+    pub unsafe fn call(&self, alpha: T, a: *const T, b: *const T, beta: T, c: *mut T) {
+        unsafe {
+            match self.layout {
+                MatrixLayout::ColumnMajor => (self.func)(alpha, a, b, beta, c),
+                // C^T = alpha * B^T * A^T + beta * C^T
+                MatrixLayout::RowMajor => (self.func)(alpha, b, a, beta, c),
             }
         }
     }
 }
 
 // template to be udated at runtime
-// Matrix multiplication (row major in rust, column major in llvm):
-// I guess this needs a FIXME, but for now it doesn't worth the hassel
+// Matrix multiplication, row major in and out:
+// the kernel is compiled as a row-major one (see `MatrixLayout`),
+// so the caller's slices are handed to llvm as-is.
 //  C(m×n) = A(m×k) * B(k×n)
-pub unsafe fn ll_matmul_jit_with_template(
-    a: &[f32],
+pub unsafe fn ll_matmul_jit_with_template<T: Scalar>(
+    a: &[T],
     a_shape: (usize, usize),
-    b: &[f32],
+    b: &[T],
     b_shape: (usize, usize),
     ir_template: Option<&str>,
-) -> Vec<f32> {
-    assert!(
-        a_shape.0 > 0 && a_shape.1 > 0 && b_shape.0 > 0 && b_shape.1 > 0,
-        "empty arrays are not supported"
-    );
-    assert!(a_shape.1 == b_shape.0, "shapes dosn't match");
+) -> Vec<T> {
+    match unsafe { try_ll_matmul_jit_with_template(a, a_shape, b, b_shape, ir_template) } {
+        Ok(result) => result,
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_with_template`], but every failure (bad shapes,
+/// unreadable or broken template, lowering, engine, missing symbol)
+/// is returned as a [`MatmulError`] instead of panicking.
+pub unsafe fn try_ll_matmul_jit_with_template<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    ir_template: Option<&str>,
+) -> Result<Vec<T>, MatmulError> {
+    unsafe {
+        try_ll_matmul_jit_with_options(
+            a,
+            a_shape,
+            b,
+            b_shape,
+            &JitOptions::with_template(ir_template),
+        )
+    }
+}
 
+/// Same as [`ll_matmul_jit_with_template`] with the [`JitOptions`] of this call,
+/// what they leave unset comes from [`set_jit_options`], then the env vars.
+/// # Safety
+/// The kernel runs whatever the resolved template compiles to,
+/// it must implement [`LlMatmulJitSig`] for the given shapes.
+pub unsafe fn ll_matmul_jit_with_options<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    options: &JitOptions,
+) -> Vec<T> {
+    match unsafe { try_ll_matmul_jit_with_options(a, a_shape, b, b_shape, options) } {
+        Ok(result) => result,
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_with_options`] with a typed error.
+/// # Safety
+/// See [`ll_matmul_jit_with_options`].
+pub unsafe fn try_ll_matmul_jit_with_options<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    options: &JitOptions,
+) -> Result<Vec<T>, MatmulError> {
+    check_shapes(a, a_shape, b, b_shape)?;
+    let mut result = vec![T::ZERO; a_shape.0 * b_shape.1];
+    unsafe {
+        matmul_into(
+            a,
+            a_shape,
+            b,
+            b_shape,
+            &mut result,
+            options,
+            specialization(),
+        )?
+    };
+    Ok(result)
+}
+
+/// Writes `A * B` (row major) into `out` instead of returning a new `Vec`.
+/// The row-major kernel reads `a`, `b` and writes `out` in place,
+/// so no matrix storage is allocated or copied per call.
+/// Uses the template from `LL_MATMUL_TEMPLATE` or the naive default,
+/// shapes are specialized according to [`set_specialization`].
+/// A large product is split into row blocks run on scoped threads, see [`JitOptions::threads`].
+pub unsafe fn ll_matmul_jit_into<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    out: &mut [T],
+) {
+    match unsafe { try_ll_matmul_jit_into(a, a_shape, b, b_shape, out) } {
+        Ok(()) => {}
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_into`] with a typed error.
+pub unsafe fn try_ll_matmul_jit_into<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    out: &mut [T],
+) -> Result<(), MatmulError> {
+    unsafe { try_ll_matmul_jit_into_with(a, a_shape, b, b_shape, out, specialization()) }
+}
+
+/// Same as [`ll_matmul_jit_into`] with an explicit [`Specialization`]
+/// instead of the process-wide one.
+/// # Safety
+/// The specialized kernel runs whatever `LL_MATMUL_TEMPLATE` compiles to,
+/// the shape-generic one whatever `LL_DYN_MATMUL_TEMPLATE` compiles to.
+pub unsafe fn ll_matmul_jit_into_with<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    out: &mut [T],
+    policy: Specialization,
+) {
+    match unsafe { try_ll_matmul_jit_into_with(a, a_shape, b, b_shape, out, policy) } {
+        Ok(()) => {}
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_into_with`] with a typed error.
+/// # Safety
+/// See [`ll_matmul_jit_into_with`].
+pub unsafe fn try_ll_matmul_jit_into_with<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    out: &mut [T],
+    policy: Specialization,
+) -> Result<(), MatmulError> {
+    check_shapes(a, a_shape, b, b_shape)?;
+    unsafe { matmul_into(a, a_shape, b, b_shape, out, &JitOptions::new(), policy) }
+}
+
+// shapes of a and b must already be checked,
+// the template and function name of `options` only apply once the shape is specialized
+unsafe fn matmul_into<T: Scalar>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    out: &mut [T],
+    options: &JitOptions,
+    policy: Specialization,
+) -> Result<(), MatmulError> {
     let m = a_shape.0;
     let n = b_shape.1;
     let k = a_shape.1; // or b_shape.0
 
+    if out.len() != m * n {
+        return Err(MatmulError::ShapeMismatch {
+            a_shape,
+            b_shape,
+            reason: "`out` length doesn't match the result shape",
+        });
+    }
+
     let shape_key: ShapeKey = (m, n, k);
 
-    let cache = JIT_CACHE.get_or_init(JitCache::new);
-    let entry = cache
-        .get_or_compile(shape_key, ir_template)
-        .unwrap_or_else(|e| match e {
-            JitError::CompilationFailed(msg) => panic!("JIT Compilation failed: {}", msg),
-        });
+    let operands = [a.as_ptr().addr(), b.as_ptr().addr(), out.as_ptr().addr()];
+    if let Some(kernel) = aot_kernel::<T>(shape_key, options)?.filter(|kernel| {
+        operands
+            .iter()
+            .all(|addr| addr.is_multiple_of(kernel.align))
+    }) {
+        unsafe { kernel.call(a.as_ptr(), b.as_ptr(), out.as_mut_ptr()) };
+        return Ok(());
+    }
+
+    let cache = jit_cache();
+    let specialize = cache.should_specialize::<T>(shape_key, policy);
+    if let Some(block_rows) = row_block(shape_key) {
+        return unsafe { matmul_row_blocks(a, b, out, shape_key, block_rows, options, specialize) };
+    }
 
-    let a_col_major = row_major_to_col_major(a, a_shape.0, a_shape.1);
-    let b_col_major = row_major_to_col_major(b, b_shape.0, b_shape.1);
-    let mut result = vec![0.0; m * n];
+    if !specialize {
+        let entry = cache.get_for_operands::<LlDynMatmulJitSig<T>>(
+            DYNAMIC_SHAPE,
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            ElemTypes::of::<T>(),
+            &options.codegen_only(),
+            &operands,
+        )?;
+        unsafe { entry.call(a.as_ptr(), b.as_ptr(), out.as_mut_ptr(), shape_key) };
+        return Ok(());
+    }
+
+    let entry = cache.get_for_operands::<LlMatmulJitSig<T>>(
+        shape_key,
+        MatrixLayout::RowMajor,
+        NO_TRANS,
+        ElemTypes::of::<T>(),
+        options,
+        &operands,
+    )?;
 
     unsafe {
-        //println!("calling ll_matmul_jit");
-        entry.func.call(
-            a_col_major.as_ptr(),
-            b_col_major.as_ptr(),
-            result.as_mut_ptr(),
+        entry.call(a.as_ptr(), b.as_ptr(), out.as_mut_ptr());
+    }
+    Ok(())
+}
+
+// below this many multiply-adds a product runs as one kernel on the calling thread
+const ROW_BLOCK_MIN_WORK: usize = 1 << 21;
+const ROW_BLOCK_MIN_ROWS: usize = 32;
+const ROW_BLOCKS: usize = 32;
+
+/// Rows of the blocks a large product is split into, `None` for one kernel over the whole.
+/// About `ROW_BLOCKS` blocks, a multiple of 8 rows so they keep the widest tiles.
+/// It depends on the shape alone: the blocks, and so the bits of the result,
+/// are the same whatever the thread count.
+fn row_block((m, n, k): ShapeKey) -> Option<usize> {
+    if m.saturating_mul(n).saturating_mul(k) < ROW_BLOCK_MIN_WORK || m < 2 * ROW_BLOCK_MIN_ROWS {
+        return None;
+    }
+    Some(
+        m.div_ceil(ROW_BLOCKS)
+            .next_multiple_of(8)
+            .max(ROW_BLOCK_MIN_ROWS),
+    )
+}
+
+/// [`matmul_into`] over row blocks of `a` and `out` (`out[r0..r1] = a[r0..r1] * b`),
+/// each one a kernel call, spread over the threads of the options.
+/// The kernels are looked up on the calling thread, the blocks of a thread are contiguous.
+unsafe fn matmul_row_blocks<T: Scalar>(
+    a: &[T],
+    b: &[T],
+    out: &mut [T],
+    (m, n, k): ShapeKey,
+    block_rows: usize,
+    options: &JitOptions,
+    specialize: bool,
+) -> Result<(), MatmulError> {
+    let cache = jit_cache();
+    let elem = size_of::<T>();
+    let mut operands = vec![b.as_ptr().addr()];
+    for r0 in (0..m).step_by(block_rows) {
+        operands.push(a.as_ptr().addr() + r0 * k * elem);
+        operands.push(out.as_ptr().addr() + r0 * n * elem);
+    }
+
+    enum Kernels<T: Scalar> {
+        // for a full block and the last one, if it's shorter
+        Specialized(
+            Arc<JitEntry<LlMatmulJitSig<T>>>,
+            Option<Arc<JitEntry<LlMatmulJitSig<T>>>>,
+        ),
+        Dynamic(Arc<JitEntry<LlDynMatmulJitSig<T>>>),
+    }
+    let specialized = |rows: usize| {
+        cache.get_for_operands::<LlMatmulJitSig<T>>(
+            (rows, n, k),
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            ElemTypes::of::<T>(),
+            options,
+            &operands,
+        )
+    };
+    let tail_rows = m % block_rows;
+    let kernels = if specialize {
+        let tail = (tail_rows > 0)
+            .then(|| specialized(tail_rows))
+            .transpose()?;
+        Kernels::Specialized(specialized(block_rows)?, tail)
+    } else {
+        Kernels::Dynamic(cache.get_for_operands::<LlDynMatmulJitSig<T>>(
+            DYNAMIC_SHAPE,
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            ElemTypes::of::<T>(),
+            &options.codegen_only(),
+            &operands,
+        )?)
+    };
+
+    let run_block = |a: &[T], out: &mut [T]| {
+        let rows = out.len() / n;
+        let (a, b, out) = (a.as_ptr(), b.as_ptr(), out.as_mut_ptr());
+        unsafe {
+            match &kernels {
+                Kernels::Specialized(_, Some(tail)) if rows == tail_rows => tail.call(a, b, out),
+                Kernels::Specialized(full, _) => full.call(a, b, out),
+                Kernels::Dynamic(entry) => entry.call(a, b, out, (rows, n, k)),
+            }
+        }
+    };
+    let run_blocks = |a: &[T], out: &mut [T]| {
+        for (a, out) in a.chunks(block_rows * k).zip(out.chunks_mut(block_rows * n)) {
+            run_block(a, out);
+        }
+    };
+
+    let blocks = m.div_ceil(block_rows);
+    let thread_rows = blocks.div_ceil(options.resolve_threads()) * block_rows;
+    thread::scope(|scope| {
+        let run_blocks = &run_blocks;
+        let mut parts = a
+            .chunks(thread_rows * k)
+            .zip(out.chunks_mut(thread_rows * n));
+        let first = parts.next();
+        for (a, out) in parts {
+            scope.spawn(move || run_blocks(a, out));
+        }
+        // the calling thread takes its share instead of waiting
+        if let Some((a, out)) = first {
+            run_blocks(a, out);
+        }
+    });
+    Ok(())
+}
+
+/// The kernel build.rs compiled for `shape` from the template the JIT would use, if any.
+fn aot_kernel<T: Scalar>(
+    shape: ShapeKey,
+    options: &JitOptions,
+) -> Result<Option<&'static AotKernel<T>>, MatmulError> {
+    let mut candidates = aot_kernels::<T>()
+        .iter()
+        .filter(|kernel| kernel.shape == shape)
+        .peekable();
+    if candidates.peek().is_none() {
+        return Ok(None);
+    }
+    // AOT kernels are built with the entry point of the template and build.rs' codegen
+    let options = resolve_options::<LlMatmulJitSig<T>>(options);
+    if options.has_codegen() {
+        return Ok(None);
+    }
+    let template = resolve_template::<LlMatmulJitSig<T>>(options.template.as_ref(), shape)?;
+    let entry = parse_header(&template)?.entry;
+    let entry = entry.as_deref().unwrap_or(DEFAULT_FUNCTION_NAME_JIT_CPU);
+    if options.function_name.is_some_and(|name| name != entry) {
+        return Ok(None);
+    }
+    let template_hash = fnv1a(template.as_bytes());
+    Ok(candidates.find(|kernel| kernel.template_hash == template_hash))
+}
+
+/// Multiplies `batch` same-shaped pairs, row major in and out:
+///  out[i](m×n) = a[i](m×k) * b[i](k×n)
+/// The loop over the batch is in the kernel, so the cache is looked up once
+/// and nothing is allocated or copied per item.
+/// `batch_layout` tells where the items start in `a_batch`, `b_batch` and `out`.
+/// Uses the template from `LL_BATCHED_MATMUL_TEMPLATE` or the naive batched default.
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_BATCHED_MATMUL_TEMPLATE`) compiles to,
+/// it must implement [`LlBatchedMatmulJitSig`] for the given shapes.
+pub unsafe fn ll_matmul_jit_batched<T: Scalar>(
+    a_batch: &[T],
+    b_batch: &[T],
+    batch: usize,
+    shapes: ((usize, usize), (usize, usize)),
+    out: &mut [T],
+    batch_layout: BatchLayout,
+    ir_template: Option<&str>,
+) {
+    match unsafe {
+        try_ll_matmul_jit_batched(
+            a_batch,
+            b_batch,
+            batch,
+            shapes,
+            out,
+            batch_layout,
+            ir_template,
+        )
+    } {
+        Ok(()) => {}
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_batched`] with a typed error.
+/// # Safety
+/// See [`ll_matmul_jit_batched`].
+pub unsafe fn try_ll_matmul_jit_batched<T: Scalar>(
+    a_batch: &[T],
+    b_batch: &[T],
+    batch: usize,
+    (a_shape, b_shape): ((usize, usize), (usize, usize)),
+    out: &mut [T],
+    batch_layout: BatchLayout,
+    ir_template: Option<&str>,
+) -> Result<(), MatmulError> {
+    check_dims(a_shape, b_shape)?;
+    let m = a_shape.0;
+    let n = b_shape.1;
+    let k = a_shape.1;
+    let strides = batch_layout.strides((m, n, k));
+    let (a_stride, b_stride, out_stride) = strides;
+
+    let mismatch = |reason| MatmulError::ShapeMismatch {
+        a_shape,
+        b_shape,
+        reason,
+    };
+    if batch > 1 && out_stride < m * n {
+        return Err(mismatch("`out` items overlap, the out stride is below m*n"));
+    }
+    // the last item has to fit, whatever the stride
+    let fits =
+        |len: usize, stride: usize, item: usize| batch == 0 || len >= (batch - 1) * stride + item;
+    if !fits(a_batch.len(), a_stride, m * k) {
+        return Err(mismatch("`a_batch` is too short for the batch"));
+    }
+    if !fits(b_batch.len(), b_stride, k * n) {
+        return Err(mismatch("`b_batch` is too short for the batch"));
+    }
+    if !fits(out.len(), out_stride, m * n) {
+        return Err(mismatch("`out` is too short for the batch"));
+    }
+    if batch == 0 {
+        return Ok(());
+    }
+
+    let cache = jit_cache();
+    let entry = cache.get_for_operands::<LlBatchedMatmulJitSig<T>>(
+        (m, n, k),
+        MatrixLayout::RowMajor,
+        NO_TRANS,
+        ElemTypes::of::<T>(),
+        &JitOptions::with_template(ir_template),
+        &[
+            a_batch.as_ptr().addr(),
+            b_batch.as_ptr().addr(),
+            out.as_ptr().addr(),
+        ],
+    )?;
+
+    unsafe {
+        entry.call(
+            a_batch.as_ptr(),
+            b_batch.as_ptr(),
+            out.as_mut_ptr(),
+            batch,
+            strides,
         );
     }
+    Ok(())
+}
+
+/// Matrix multiplication on half-precision storage, row major in and out.
+/// `a` and `b` are `format` bit patterns (e.g. `half::f16::to_bits`),
+/// they are widened to `f32` in the kernel and the products are summed in `f32`.
+/// With `O = f32` the sums are returned as is, with `O = u16`
+/// they're rounded back to `format`.
+/// Uses the template from `LL_HALF_MATMUL_TEMPLATE` or the naive half default.
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_HALF_MATMUL_TEMPLATE`) compiles to,
+/// it must implement [`LlHalfMatmulJitSig`] for the given shapes.
+pub unsafe fn ll_matmul_jit_half<O: HalfOutput>(
+    format: HalfFormat,
+    a: &[u16],
+    a_shape: (usize, usize),
+    b: &[u16],
+    b_shape: (usize, usize),
+    ir_template: Option<&str>,
+) -> Vec<O> {
+    match unsafe { try_ll_matmul_jit_half(format, a, a_shape, b, b_shape, ir_template) } {
+        Ok(result) => result,
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_half`] with a typed error.
+/// # Safety
+/// See [`ll_matmul_jit_half`].
+pub unsafe fn try_ll_matmul_jit_half<O: HalfOutput>(
+    format: HalfFormat,
+    a: &[u16],
+    a_shape: (usize, usize),
+    b: &[u16],
+    b_shape: (usize, usize),
+    ir_template: Option<&str>,
+) -> Result<Vec<O>, MatmulError> {
+    check_shapes(a, a_shape, b, b_shape)?;
+    let m = a_shape.0;
+    let n = b_shape.1;
+    let k = a_shape.1;
+
+    let mut result = vec![O::ZERO; m * n];
+    let cache = jit_cache();
+    let entry = cache.get_for_operands::<LlHalfMatmulJitSig<O>>(
+        (m, n, k),
+        MatrixLayout::RowMajor,
+        NO_TRANS,
+        ElemTypes::half::<O>(format),
+        &JitOptions::with_template(ir_template),
+        &[a.as_ptr().addr(), b.as_ptr().addr(), result.as_ptr().addr()],
+    )?;
+
+    unsafe {
+        entry.call_half(a.as_ptr(), b.as_ptr(), result.as_mut_ptr());
+    }
+    Ok(result)
+}
+
+/// Integer matrix multiplication, row major in and out:
+/// `a` and `b` are widened to `I::Acc` (`i32` for `i8`/`u8`) and summed in it.
+/// With [`Overflow::Wrapping`] the sums wrap around, with [`Overflow::Checked`]
+/// an overflowing product or partial sum panics.
+/// Uses the template from `LL_INT_MATMUL_TEMPLATE` or the naive integer default.
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_INT_MATMUL_TEMPLATE`) compiles to,
+/// it must implement [`LlIntMatmulJitSig`] for the given shapes.
+pub unsafe fn ll_matmul_jit_int<I: IntScalar>(
+    overflow: Overflow,
+    a: &[I],
+    a_shape: (usize, usize),
+    b: &[I],
+    b_shape: (usize, usize),
+    ir_template: Option<&str>,
+) -> Vec<I::Acc> {
+    match unsafe { try_ll_matmul_jit_int(overflow, a, a_shape, b, b_shape, ir_template) } {
+        Ok(result) => result,
+        Err(e @ (MatmulError::ShapeMismatch { .. } | MatmulError::IntegerOverflow)) => {
+            panic!("{}", e)
+        }
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_matmul_jit_int`], an overflow is returned as
+/// [`MatmulError::IntegerOverflow`] with the other failures.
+/// # Safety
+/// See [`ll_matmul_jit_int`].
+pub unsafe fn try_ll_matmul_jit_int<I: IntScalar>(
+    overflow: Overflow,
+    a: &[I],
+    a_shape: (usize, usize),
+    b: &[I],
+    b_shape: (usize, usize),
+    ir_template: Option<&str>,
+) -> Result<Vec<I::Acc>, MatmulError> {
+    check_shapes(a, a_shape, b, b_shape)?;
+    let m = a_shape.0;
+    let n = b_shape.1;
+    let k = a_shape.1;
+
+    let mut result = vec![I::Acc::ZERO; m * n];
+    let cache = jit_cache();
+    let entry = cache.get_for_operands::<LlIntMatmulJitSig<I>>(
+        (m, n, k),
+        MatrixLayout::RowMajor,
+        NO_TRANS,
+        ElemTypes::int::<I>(overflow),
+        &JitOptions::with_template(ir_template),
+        &[a.as_ptr().addr(), b.as_ptr().addr(), result.as_ptr().addr()],
+    )?;
+
+    let overflowed = unsafe { entry.call(a.as_ptr(), b.as_ptr(), result.as_mut_ptr()) };
+    if overflowed {
+        return Err(MatmulError::IntegerOverflow);
+    }
+    Ok(result)
+}
+
+/// GEMM, row major in and out:
+///  C(m×n) = alpha * op(A)(m×k) * op(B)(k×n) + beta * C(m×n)
+/// `a_shape`/`b_shape` are the shapes of `a` and `b` as stored,
+/// with [`Trans::Yes`] `a` is stored k×m (resp. `b` n×k).
+/// `c` is updated in place. As in BLAS, `beta == 0` doesn't read `c`,
+/// so it may hold garbage (even NaN) on entry.
+/// Uses the template from `LL_GEMM_TEMPLATE` or the naive gemm default.
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_GEMM_TEMPLATE`) compiles to,
+/// it must implement [`LlGemmJitSig`] for the given shapes.
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn ll_gemm_jit<T: Scalar>(
+    trans_a: Trans,
+    trans_b: Trans,
+    alpha: T,
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    beta: T,
+    c: &mut [T],
+    ir_template: Option<&str>,
+) {
+    match unsafe {
+        try_ll_gemm_jit(
+            trans_a,
+            trans_b,
+            alpha,
+            a,
+            a_shape,
+            b,
+            b_shape,
+            beta,
+            c,
+            ir_template,
+        )
+    } {
+        Ok(()) => {}
+        Err(e @ MatmulError::ShapeMismatch { .. }) => panic!("{}", e),
+        Err(e) => panic!("JIT Compilation failed: {}", e),
+    }
+}
+
+/// Same as [`ll_gemm_jit`] with a typed error.
+/// # Safety
+/// See [`ll_gemm_jit`].
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn try_ll_gemm_jit<T: Scalar>(
+    trans_a: Trans,
+    trans_b: Trans,
+    alpha: T,
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+    beta: T,
+    c: &mut [T],
+    ir_template: Option<&str>,
+) -> Result<(), MatmulError> {
+    // shapes are checked (and reported) as op(A), op(B),
+    // the stored sizes are the same either way
+    let a_shape = trans_a.apply(a_shape);
+    let b_shape = trans_b.apply(b_shape);
+    check_shapes(a, a_shape, b, b_shape)?;
+    let m = a_shape.0;
+    let n = b_shape.1;
+    let k = a_shape.1;
+
+    if c.len() != m * n {
+        return Err(MatmulError::ShapeMismatch {
+            a_shape,
+            b_shape,
+            reason: "`c` length doesn't match the result shape",
+        });
+    }
+
+    let cache = jit_cache();
+    let entry = cache.get_for_operands::<LlGemmJitSig<T>>(
+        (m, n, k),
+        MatrixLayout::RowMajor,
+        (trans_a, trans_b),
+        ElemTypes::of::<T>(),
+        &JitOptions::with_template(ir_template),
+        &[a.as_ptr().addr(), b.as_ptr().addr(), c.as_ptr().addr()],
+    )?;
+
+    unsafe {
+        entry.call(alpha, a.as_ptr(), b.as_ptr(), beta, c.as_mut_ptr());
+    }
+    Ok(())
+}
+
+fn check_shapes<T>(
+    a: &[T],
+    a_shape: (usize, usize),
+    b: &[T],
+    b_shape: (usize, usize),
+) -> Result<(), MatmulError> {
+    check_dims(a_shape, b_shape)?;
+    let mismatch = |reason| MatmulError::ShapeMismatch {
+        a_shape,
+        b_shape,
+        reason,
+    };
+    if a.len() != a_shape.0 * a_shape.1 {
+        return Err(mismatch("`a` length doesn't match a_shape"));
+    }
+    if b.len() != b_shape.0 * b_shape.1 {
+        return Err(mismatch("`b` length doesn't match b_shape"));
+    }
+    Ok(())
+}
+
+fn check_dims(a_shape: (usize, usize), b_shape: (usize, usize)) -> Result<(), MatmulError> {
+    let mismatch = |reason| MatmulError::ShapeMismatch {
+        a_shape,
+        b_shape,
+        reason,
+    };
+    if a_shape.0 == 0 || a_shape.1 == 0 || b_shape.0 == 0 || b_shape.1 == 0 {
+        return Err(mismatch("empty arrays are not supported"));
+    }
+    if a_shape.1 != b_shape.0 {
+        return Err(mismatch("shapes doesn't match"));
+    }
+    Ok(())
+}
 
-    col_major_to_row_major(&result, m, n)
+/// Compiles a column-major kernel, `func` expects column-major `a`, `b` and `result`.
+pub unsafe fn compile_matmul_jit_with_template<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    ir_template: Option<&str>,
+) -> Result<JitEntry<LlMatmulJitSig<T>>, String> {
+    unsafe { try_compile_matmul_jit_with_template(m, n, k, ir_template) }.map_err(|e| e.to_string())
 }
 
-pub unsafe fn compile_matmul_jit_with_template(
+/// Same as [`compile_matmul_jit_with_template`] with a typed error.
+pub unsafe fn try_compile_matmul_jit_with_template<T: Scalar>(
     m: usize,
     n: usize,
     k: usize,
     ir_template: Option<&str>,
-) -> Result<JitEntry, String> {
-    //println!("compiling matmul_jit for m={}, n={}, k={}", m, n, k);
-
-    let template_content = if let Some(t) = ir_template {
-        t.to_string()
-    } else if let Ok(path) = env::var(TEMPLATE_JIT_CPU_ENV) {
-        fs::read_to_string(&path).unwrap_or_else(|e| {
-            panic!("Failed to read template from {}: {}", path, e);
-        })
-    } else {
-        eprintln!(
-            r#" 
+) -> Result<JitEntry<LlMatmulJitSig<T>>, MatmulError> {
+    unsafe { try_compile_matmul_jit_with_layout(m, n, k, ir_template, MatrixLayout::ColumnMajor) }
+}
+
+/// Compiles a kernel for `layout`; use [`JitEntry::call`] so the operands
+/// are passed in the order that layout needs.
+pub unsafe fn compile_matmul_jit_with_layout<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlMatmulJitSig<T>>, String> {
+    unsafe { try_compile_matmul_jit_with_layout(m, n, k, ir_template, layout) }
+        .map_err(|e| e.to_string())
+}
+
+/// Same as [`compile_matmul_jit_with_layout`] with a typed error.
+pub unsafe fn try_compile_matmul_jit_with_layout<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlMatmulJitSig<T>>, MatmulError> {
+    unsafe {
+        compile_uncached(
+            (m, n, k),
+            ElemTypes::of::<T>(),
+            &JitOptions::with_template(ir_template),
+            layout,
+        )
+    }
+}
+
+/// Same as [`compile_matmul_jit_with_layout`] with the [`JitOptions`] of this call,
+/// what they leave unset comes from [`set_jit_options`], then the env vars.
+/// # Safety
+/// The kernel runs whatever the resolved template compiles to,
+/// it must implement [`LlMatmulJitSig`] for the given shapes.
+pub unsafe fn compile_matmul_jit_with_options<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    layout: MatrixLayout,
+    options: &JitOptions,
+) -> Result<JitEntry<LlMatmulJitSig<T>>, String> {
+    unsafe { try_compile_matmul_jit_with_options(m, n, k, layout, options) }
+        .map_err(|e| e.to_string())
+}
+
+/// Same as [`compile_matmul_jit_with_options`] with a typed error.
+/// # Safety
+/// See [`compile_matmul_jit_with_options`].
+pub unsafe fn try_compile_matmul_jit_with_options<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    layout: MatrixLayout,
+    options: &JitOptions,
+) -> Result<JitEntry<LlMatmulJitSig<T>>, MatmulError> {
+    unsafe { compile_uncached((m, n, k), ElemTypes::of::<T>(), options, layout) }
+}
+
+/// Compiles a half-precision kernel for `layout`, `a` and `b` are `format` bit patterns.
+/// `O = f32` keeps the `f32` accumulator, `O = u16` truncates the result to `format`.
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_HALF_MATMUL_TEMPLATE`) compiles to,
+/// it must implement [`LlHalfMatmulJitSig`] for the given shapes.
+pub unsafe fn compile_matmul_jit_half<O: HalfOutput>(
+    m: usize,
+    n: usize,
+    k: usize,
+    format: HalfFormat,
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlHalfMatmulJitSig<O>>, String> {
+    unsafe { try_compile_matmul_jit_half(m, n, k, format, ir_template, layout) }
+        .map_err(|e| e.to_string())
+}
+
+/// Same as [`compile_matmul_jit_half`] with a typed error.
+/// # Safety
+/// See [`compile_matmul_jit_half`].
+pub unsafe fn try_compile_matmul_jit_half<O: HalfOutput>(
+    m: usize,
+    n: usize,
+    k: usize,
+    format: HalfFormat,
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlHalfMatmulJitSig<O>>, MatmulError> {
+    unsafe {
+        compile_uncached(
+            (m, n, k),
+            ElemTypes::half::<O>(format),
+            &JitOptions::with_template(ir_template),
+            layout,
+        )
+    }
+}
+
+/// Compiles the shape-generic kernel for `layout`, one kernel serves every shape
+/// (see [`JitEntry::call`] and [`JitEntry::call_strided`]).
+/// # Safety
+/// The kernel runs whatever `ir_template` (or `LL_DYN_MATMUL_TEMPLATE`) compiles to,
+/// it must implement [`LlDynMatmulJitSig`].
+pub unsafe fn compile_matmul_jit_dynamic<T: Scalar>(
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlDynMatmulJitSig<T>>, String> {
+    unsafe { try_compile_matmul_jit_dynamic(ir_template, layout) }.map_err(|e| e.to_string())
+}
+
+/// Same as [`compile_matmul_jit_dynamic`] with a typed error.
+/// # Safety
+/// See [`compile_matmul_jit_dynamic`].
+pub unsafe fn try_compile_matmul_jit_dynamic<T: Scalar>(
+    ir_template: Option<&str>,
+    layout: MatrixLayout,
+) -> Result<JitEntry<LlDynMatmulJitSig<T>>, MatmulError> {
+    unsafe {
+        compile_uncached(
+            DYNAMIC_SHAPE,
+            ElemTypes::of::<T>(),
+            &JitOptions::with_template(ir_template),
+            layout,
+        )
+    }
+}
+
+// the compile_* entry points bypass JIT_CACHE, the caller owns the kernel
+unsafe fn compile_uncached<F: KernelAbi>(
+    shape: ShapeKey,
+    elems: ElemTypes,
+    options: &JitOptions,
+    layout: MatrixLayout,
+) -> Result<JitEntry<F>, MatmulError> {
+    let options = resolve_options::<F>(options);
+    let source = instantiate_kernel::<F>(&options, shape, layout, NO_TRANS, elems)?;
+    warn_default_template::<F>(options.template.as_ref(), shape);
+
+    let disk = DiskCache::from_env();
+    unsafe {
+        compile_matmul_jit_from_ir(
+            &source,
+            layout,
+            &CodegenOptions::resolve(&options),
+            disk.as_ref(),
+        )
+    }
+}
+
+/// A kernel at each step of its compilation, see [`inspect_matmul_jit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelInspection {
+    /// The template with its placeholders substituted.
+    pub instantiated_ir: String,
+    /// After `lower-matrix-intrinsics` alone.
+    pub lowered_ir: String,
+    /// After the pass pipeline of the options, the module the JIT code-generates
+    /// (the same as `lowered_ir` with the default pipeline).
+    pub optimized_ir: String,
+    /// `optimized_ir` as the target machine of the options emits it.
+    pub assembly: String,
+}
+
+/// The IR and assembly of the kernel [`compile_matmul_jit_with_options`] compiles
+/// for the same arguments, to diff templates or options or to attach to a bug report.
+/// Nothing is JIT-compiled, run or cached.
+pub fn inspect_matmul_jit<T: Scalar>(
+    m: usize,
+    n: usize,
+    k: usize,
+    layout: MatrixLayout,
+    options: &JitOptions,
+) -> Result<KernelInspection, MatmulError> {
+    inspect_kernel::<LlMatmulJitSig<T>>((m, n, k), ElemTypes::of::<T>(), options, layout)
+}
+
+fn inspect_kernel<F: KernelAbi>(
+    shape: ShapeKey,
+    elems: ElemTypes,
+    options: &JitOptions,
+    layout: MatrixLayout,
+) -> Result<KernelInspection, MatmulError> {
+    let options = resolve_options::<F>(options);
+    let source = instantiate_kernel::<F>(&options, shape, layout, NO_TRANS, elems)?;
+    let codegen = CodegenOptions::resolve(&options);
+    let machine = target_machine(&codegen)?;
+
+    let context = Context::create();
+    let lowered = parse_ir(&context, &source.ir)?;
+    verify_module(&lowered, "instantiated")?;
+    check_entry(&lowered, &source)?;
+    run_passes(&lowered, LOWER_MATRIX_INTRINSICS, &machine, &codegen)?;
+    verify_module(&lowered, "lowered")?;
+    let optimized = parse_ir(&context, &source.ir)?;
+    run_passes(&optimized, &codegen.passes, &machine, &codegen)?;
+    verify_module(&optimized, "optimized")?;
+    let assembly = machine
+        .write_to_memory_buffer(&optimized, FileType::Assembly)
+        .map_err(|e| MatmulError::AsmEmission(e.to_string()))?;
+
+    Ok(KernelInspection {
+        instantiated_ir: source.ir,
+        lowered_ir: lowered.print_to_string().to_string(),
+        optimized_ir: optimized.print_to_string().to_string(),
+        assembly: String::from_utf8_lossy(assembly.as_slice()).into_owned(),
+    })
+}
+
+/// The naive template of `F`, or its tiled one when a dimension is past
+/// `NAIVE_TEMPLATE_MAX_DIM` (the naive lowering would OOM).
+pub(crate) fn default_template<F: KernelAbi>((m, n, k): ShapeKey) -> &'static str {
+    match F::TILED_TEMPLATE {
+        Some(tiled) if m.max(n).max(k) > NAIVE_TEMPLATE_MAX_DIM => tiled,
+        _ => F::DEFAULT_TEMPLATE,
+    }
+}
+
+/// A template instantiated for one kernel, with what its header says about calling it.
+pub(crate) struct KernelSource {
+    pub(crate) ir: String,
+    pub(crate) function_name: String,
+    // `F::ABI` as LLVM prints the function type, `void (ptr, ptr, ptr)`
+    signature: String,
+    align: usize,
+    fallback: bool,
+}
+
+/// Instantiates the template of the (resolved) `options` for `shape`.
+/// The header is checked against the kernel: a template declaring another ABI
+/// or element types is refused, and one whose `require:` the shape doesn't meet
+/// is replaced by `F`'s default template with `fallback: default`, refused otherwise.
+/// The entry point is the function name of the options, else the header's `entry:`.
+pub(crate) fn instantiate_kernel<F: KernelAbi>(
+    options: &JitOptions,
+    shape: ShapeKey,
+    layout: MatrixLayout,
+    trans: TransKey,
+    elems: ElemTypes,
+) -> Result<KernelSource, MatmulError> {
+    let template = resolve_template::<F>(options.template.as_ref(), shape)?;
+    let header = parse_header(&template)?;
+    if !header.supports_abi(F::ABI) {
+        return Err(MatmulError::TemplateAbiMismatch {
+            expected: F::ABI,
+            declared: header.abi.unwrap_or_default(),
+        });
+    }
+    let (kernel_shape, kernel_trans) = (layout.kernel_shape(shape), layout.kernel_trans(trans));
+    let ir = instantiate_template(&template, kernel_shape, kernel_trans, elems)?;
+    if !header.supports_elem(elems.elem.ty) {
+        return Err(MatmulError::ElemTypeUnsupported {
+            elem: elems.elem.ty,
+        });
+    }
+    if let Some(requirement) = header.unmet(&bindings(kernel_shape, kernel_trans, elems))? {
+        let default = default_template::<F>(shape);
+        if header.fallback && template != default {
+            let options = options.pin_default_template::<F>(shape);
+            return instantiate_kernel::<F>(&options, shape, layout, trans, elems);
+        }
+        return Err(MatmulError::TemplateRequirement {
+            requirement: requirement.to_string(),
+            shape: kernel_shape,
+        });
+    }
+    Ok(KernelSource {
+        ir,
+        function_name: options
+            .function_name
+            .clone()
+            .or(header.entry)
+            .unwrap_or_else(|| F::DEFAULT_FUNCTION_NAME.to_string()),
+        signature: llvm_signature(F::ABI, elems.elem.ty),
+        align: header.align.unwrap_or(1),
+        fallback: header.fallback,
+    })
+}
+
+/// `abi` (`(elem, ptr) -> void`) as LLVM prints a function type (`void (float, ptr)`).
+fn llvm_signature(abi: &str, elem: &str) -> String {
+    let (params, ret) = abi
+        .split_once("->")
+        .expect("ABIs are spelled `(params) -> ret`");
+    format!("{} {}", ret.trim(), params.trim()).replace("elem", elem)
+}
+
+pub(crate) fn warn_default_template<F: KernelAbi>(
+    template: Option<&TemplateSource>,
+    (m, n, k): ShapeKey,
+) {
+    if template.is_some()
+        || (m, n, k) == DYNAMIC_SHAPE
+        || default_template::<F>((m, n, k)) != F::DEFAULT_TEMPLATE
+    {
+        return;
+    }
+    eprintln!(
+        r#" 
 // You are using `DEFAULT_IR_TEMPLATE` (naive)
-// with size of ({m}x{n} * {k}x{n})
+// with size of ({m}x{k} * {k}x{n})
 // when targeting non specilized hardware (CPU),
 // opt will go crazy and try to lower intrinsics to the lowest level possible
 // and this will make the code explode in size
@@ -160,47 +1566,169 @@ pub unsafe fn compile_matmul_jit_with_template(
 // so we need to have a threshold (32 in my case)
 // up to you to check which value is best for you
 // you can play with examples/debug_large_matmul.rs to check which value is best for you
+// (matmul kernels switch to the tiled template past NAIVE_TEMPLATE_MAX_DIM)
 "#
-        );
-        DEFAULT_IR_TEMPLATE_JIT_CPU.to_string()
-    };
+    );
+}
 
-    // Check if the template contains placeholders (it should for JIT instantiation)
-    if !template_content.contains("{M}")
-        && !template_content.contains("{N}")
-        && !template_content.contains("{K}")
+fn bindings(shape: ShapeKey, (trans_a, trans_b): TransKey, elems: ElemTypes) -> Bindings {
+    Bindings {
+        shape,
+        trans: (trans_a.as_ir(), trans_b.as_ir()),
+        elem: elems.elem,
+        acc: elems.acc,
+        out: elems.out,
+        out_cast: elems.out_cast(),
+        ext: elems.ext,
+        checked: elems.overflow.as_ir(),
+    }
+}
+
+fn instantiate_template(
+    template_content: &str,
+    (m, n, k): ShapeKey,
+    (trans_a, trans_b): TransKey,
+    elems: ElemTypes,
+) -> Result<String, MatmulError> {
+    let instantiated = instantiate(
+        template_content,
+        &bindings((m, n, k), (trans_a, trans_b), elems),
+    )?;
+    // Check if the template uses the dimensions (it should for JIT instantiation),
+    // shape-generic templates take m, n, k as arguments instead
+    if (m, n, k) != DYNAMIC_SHAPE {
+        let unused = instantiated.unused(&["M", "N", "K"]);
+        if unused.len() == 3 {
+            return Err(MatmulError::TemplateMissingPlaceholder);
+        }
+        if !unused.is_empty() {
+            return Err(MatmulError::TemplateUnusedPlaceholders { names: unused });
+        }
+    }
+    // a template that ignores the flag would silently compute op(X) = X
+    for (trans, name, placeholder) in [
+        (trans_a, "TRANS_A", "{TRANS_A}"),
+        (trans_b, "TRANS_B", "{TRANS_B}"),
+    ] {
+        if trans == Trans::Yes && !instantiated.uses(name) {
+            return Err(MatmulError::TransposeUnsupported { placeholder });
+        }
+    }
+    // same for a template hardcoding `float`
+    if elems.elem != IrType::of::<f32>() && !instantiated.uses("ELEM_TY") {
+        return Err(MatmulError::ElemTypeUnsupported {
+            elem: elems.elem.ty,
+        });
+    }
+    // a template without the flag would silently wrap
+    if elems.overflow == Overflow::Checked && !instantiated.uses("CHECKED") {
+        return Err(MatmulError::OverflowCheckUnsupported);
+    }
+    // or one that would accumulate in (and store) the narrow storage type,
+    // {OUT_TY} is only needed when the result isn't stored as the accumulator (e.g. `u8` -> `i32`)
+    if (elems.acc != elems.elem && !instantiated.uses("ACC_TY"))
+        || (elems.out != elems.acc && !instantiated.uses("OUT_TY"))
     {
-        return Err(
-            "Template must contain placeholders like {M}, {N}, {K} for matrix dimensions. \
-             If using a hardcoded IR file (like matmul_4x4.ll), do not use it as a template for different sizes.".to_string()
-        );
+        return Err(MatmulError::MixedPrecisionUnsupported {
+            elem: elems.elem.ty,
+            out: elems.out.ty,
+        });
     }
+    let ir_runtime = instantiated.ir;
+
+    Ok(ir_runtime)
+}
 
-    let ir_runtime = template_content
-        .replace("{M}", &m.to_string())
-        .replace("{N}", &n.to_string())
-        .replace("{K}", &k.to_string())
-        .replace("{VEC_A_SIZE}", &((m * k).to_string()))
-        .replace("{VEC_B_SIZE}", &((k * n).to_string()))
-        .replace("{VEC_C_SIZE}", &((m * n).to_string()))
-        .replace("{A_STRIDE}", &m.to_string())
-        .replace("{B_STRIDE}", &k.to_string())
-        .replace("{C_STRIDE}", &m.to_string());
+/// Everything the lowered module depends on, [`DiskCache`] compares it on load.
+/// The IR hash covers the template, the shape, the element types and the transposes.
+fn disk_cache_key(ir: &str, function_name: &str, codegen: &CodegenOptions) -> String {
+    let (major, minor, patch) = get_llvm_version();
+    format!(
+        "llvm {}.{}.{}|{} {}|{}|{}|{}|O{}|{:?} {:?}|{}|vec {} unroll {} verify {}|{}|ir {:016x}",
+        major,
+        minor,
+        patch,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        TargetMachine::get_default_triple(),
+        codegen.cpu,
+        codegen.features,
+        codegen.opt_level as u32,
+        codegen.reloc_mode,
+        codegen.code_model,
+        codegen.passes,
+        codegen.vectorize,
+        codegen.unroll,
+        codegen.verify_each,
+        function_name,
+        fnv1a(ir.as_bytes())
+    )
+}
+
+// parses the instantiated IR and runs the pass pipeline (lower-matrix-intrinsics by default) on it
+fn lower_ir<'ctx>(
+    context: &'ctx Context,
+    ir_runtime: &str,
+    codegen: &CodegenOptions,
+) -> Result<Module<'ctx>, MatmulError> {
+    let module = parse_ir(context, ir_runtime)?;
+    verify_module(&module, "instantiated")?;
+    let machine = target_machine(codegen)?;
+    run_passes(&module, &codegen.passes, &machine, codegen)?;
+    verify_module(&module, "lowered")?;
+    Ok(module)
+}
 
-    //println!("IR instantiated:\n{}", ir_runtime);
+fn verify_module(module: &Module, stage: &'static str) -> Result<(), MatmulError> {
+    module.verify().map_err(|e| MatmulError::IrVerify {
+        stage,
+        message: e.to_string(),
+    })
+}
 
-    // each JIT compilation gets its own context leaked to 'static
-    // this is okay(?) because llvm-ontext needs to live for the entire program
-    let context = Box::leak(Box::new(Context::create()));
+/// Checks that the module defines the entry point with the kernel's signature,
+/// `get_function` would hand out any function of that name as `F`.
+fn check_entry(module: &Module, source: &KernelSource) -> Result<(), MatmulError> {
+    let name = &source.function_name;
+    let not_found = |reason: &str| MatmulError::SymbolNotFound {
+        name: name.clone(),
+        reason: reason.to_string(),
+    };
+    let function = module
+        .get_function(name)
+        .ok_or_else(|| not_found("the module has no function of this name"))?;
+    if function.count_basic_blocks() == 0 {
+        return Err(not_found("the module only declares it"));
+    }
+    let found = function.get_type().print_to_string().to_string();
+    let normalized = |signature: &str| signature.split_whitespace().collect::<String>();
+    if normalized(&found) != normalized(&source.signature) {
+        return Err(MatmulError::SignatureMismatch {
+            name: name.clone(),
+            expected: source.signature.clone(),
+            found,
+        });
+    }
+    Ok(())
+}
 
+fn parse_ir<'ctx>(context: &'ctx Context, ir_runtime: &str) -> Result<Module<'ctx>, MatmulError> {
     let buffer = MemoryBuffer::create_from_memory_range_copy(ir_runtime.as_bytes(), "matmul_ir");
-    let module: Module<'static> = match context.create_module_from_ir(buffer) {
-        Ok(module) => module,
+    match context.create_module_from_ir(buffer) {
+        Ok(module) => Ok(module),
         Err(e) => {
-            return Err(format!("Failed to parse LLVM IR: {}", e));
+            let message = e.to_string();
+            let (line, column) = parse_ir_diagnostic(&message);
+            Err(MatmulError::IrParse {
+                line,
+                column,
+                message,
+            })
         }
-    };
+    }
+}
 
+fn target_machine(codegen: &CodegenOptions) -> Result<TargetMachine, MatmulError> {
     // lowering so we can jit,
     // cause we use too high level matrix intrinsics
     // we need to reproduce the build.rs logic
@@ -213,41 +1741,42 @@ pub unsafe fn compile_matmul_jit_with_template(
     let target = match Target::from_triple(&triple) {
         Ok(target) => target,
         Err(e) => {
-            return Err(format!(
-                "{} {}",
-                format!("target from triplet failed : {}", triple),
-                e
-            ));
+            return Err(MatmulError::PassPipeline(format!(
+                "target from triplet failed : {} {}",
+                triple, e
+            )));
         }
     };
-    let machine = match target.create_target_machine(
-        &triple,
-        TargetMachine::get_host_cpu_name()
-            .to_str()
-            .expect("host cpu name"), // FIXME: is this same as native ?
-        "+avx2,+fma",
-        OptimizationLevel::Aggressive,
-        RelocMode::PIC,
-        CodeModel::JITDefault,
-    ) {
-        Some(tm) => tm,
-        None => return Err("couldn't create target machine".to_string()),
-    };
+    target
+        .create_target_machine(
+            &triple,
+            &codegen.cpu,
+            &codegen.features,
+            codegen.opt_level,
+            codegen.reloc_mode,
+            codegen.code_model,
+        )
+        .ok_or_else(|| MatmulError::PassPipeline("couldn't create target machine".to_string()))
+}
 
+fn run_passes(
+    module: &Module,
+    pipeline: &str,
+    machine: &TargetMachine,
+    codegen: &CodegenOptions,
+) -> Result<(), MatmulError> {
     let pass_options = PassBuilderOptions::create();
-    // TODO : this pass fails set to true
-    // needs FIXME ?
-    pass_options.set_verify_each(false);
+    pass_options.set_verify_each(codegen.verify_each);
     //FIXME: should be true in debug mode, but not in test or bench mode
     pass_options.set_debug_logging(false);
 
     // https://llvm.org/docs/NewPassManager.html#invoking-opt
     // opt --help
     // the 4 next passes are most have for matrix multiplication
-    pass_options.set_loop_interleaving(true);
-    pass_options.set_loop_vectorization(true);
-    pass_options.set_loop_slp_vectorization(true);
-    pass_options.set_loop_unrolling(true);
+    pass_options.set_loop_interleaving(codegen.vectorize);
+    pass_options.set_loop_vectorization(codegen.vectorize);
+    pass_options.set_loop_slp_vectorization(codegen.vectorize);
+    pass_options.set_loop_unrolling(codegen.unroll);
 
     // don't know about these
     // we need to align with build.rs
@@ -257,134 +1786,610 @@ pub unsafe fn compile_matmul_jit_with_template(
     pass_options.set_call_graph_profile(true);
     pass_options.set_merge_functions(true);
 
+    let passes = CString::new(pipeline).map_err(|_| {
+        MatmulError::PassPipeline(format!("pass pipeline {:?} contains a NUL", pipeline))
+    })?;
     unsafe {
-        //println!("running passes");
         let error = llvm_sys::transforms::pass_builder::LLVMRunPasses(
             module.as_mut_ptr(),
-            c"lower-matrix-intrinsics".as_ptr(),
+            passes.as_ptr(),
             machine.as_mut_ptr(),
             pass_options.as_mut_ptr(),
         );
-        //println!("passes run done");
         if !error.is_null() {
             let message = LLVMGetErrorMessage(error);
             if !message.is_null() {
                 let message_str = CStr::from_ptr(message).to_string_lossy().into_owned();
                 LLVMDisposeMessage(message);
-                return Err(format!(
-                    "shit happend can't recover any diag! good luck: {}",
-                    message_str
-                ));
+                return Err(MatmulError::PassPipeline(format!(
+                    "{} failed: {}",
+                    pipeline, message_str
+                )));
             } else {
-                return Err("shit happend can't recover any diag! good luck".to_string());
+                return Err(MatmulError::PassPipeline(format!(
+                    "{} failed without a diagnostic",
+                    pipeline
+                )));
             }
         }
     };
 
-    //println!("IR lowered:\n{}", module.print_to_string());
+    Ok(())
+}
 
-    let execution_engine = Box::leak(Box::new(
-        match module.create_jit_execution_engine(OptimizationLevel::Aggressive) {
-            Ok(execution_engine) => execution_engine,
-            Err(e) => {
-                return Err(format!("Failed to create JIT execution engine: {}", e));
-            }
-        },
-    ));
-    //println!("execution_engine created");
-
-    let ll_matmul_jit: JitFunction<LlMatmulJitSig> = unsafe {
-        let function_name = env::var(TEMPLATE_JIT_CPU_ENV_FUNCTION_NAME)
-            .unwrap_or(DEFAULT_FUNCTION_NAME_JIT_CPU.to_string());
-        match execution_engine.get_function(function_name.as_str()) {
-            Ok(ll_matmul_jit) => ll_matmul_jit,
-            Err(e) => {
-                return Err(format!(
-                    "Failed to find JIT function {} : {}",
-                    function_name, e
-                ));
-            }
-        }
+pub(crate) unsafe fn compile_matmul_jit_from_ir<F: KernelAbi>(
+    source: &KernelSource,
+    layout: MatrixLayout,
+    codegen: &CodegenOptions,
+    disk: Option<&DiskCache>,
+) -> Result<JitEntry<F>, MatmulError> {
+    let start = Instant::now();
+    let (ir_runtime, function_name) = (source.ir.as_str(), source.function_name.as_str());
+    let link = |object: &[u8], ir_bytes: usize| -> Result<JitEntry<F>, MatmulError> {
+        let (object_jit, address) = LinkedObject::link(object, function_name)?;
+        Ok(JitEntry {
+            func: unsafe { function_at(address) },
+            layout,
+            align: source.align,
+            ir_bytes,
+            code_bytes: object.len(),
+            compile_time: start.elapsed(),
+            fallback: source.fallback,
+            object: object_jit,
+        })
     };
-    //println!("ll_matmul_jit found");
-    Ok(JitEntry {
-        func: ll_matmul_jit,
-    })
+
+    let disk_key = disk.map(|_| disk_cache_key(ir_runtime, function_name, codegen));
+    // an entry is only written once the kernel passed `check_entry`, loading it
+    // skips the parse, the lowering and the codegen, the object is linked as is.
+    // A stale or corrupted entry (or an object LLVM refuses to link) is a miss:
+    // the kernel is compiled again and the entry rewritten
+    let cached = disk.zip(disk_key.as_deref()).and_then(|(disk, key)| {
+        let payload = disk.load(key)?;
+        let (ir_bytes, object) = decode_object(&payload)?;
+        link(object, ir_bytes).ok()
+    });
+    if let Some(entry) = cached {
+        return Ok(entry);
+    }
+
+    // the context and the module only live until the object code is emitted,
+    // the entry owns the JIT the object is linked into
+    let context = Context::create();
+    let module = lower_ir(&context, ir_runtime, codegen)?;
+    let ir_bytes = module.write_bitcode_to_memory().get_size();
+    check_entry(&module, source)?;
+    // the one codegen of the kernel, linked as is
+    let object = target_machine(codegen)?
+        .write_to_memory_buffer(&module, FileType::Object)
+        .map_err(|e| MatmulError::AsmEmission(e.to_string()))?;
+    if let (Some(disk), Some(key)) = (disk, &disk_key) {
+        // best effort, a read-only or full disk only costs the next run a compile
+        let _ = disk.store(key, &encode_object(ir_bytes, object.as_slice()));
+    }
+    link(object.as_slice(), ir_bytes)
+}
+
+// a disk cache payload: the size of the lowered bitcode (u64 LE), for `JitEntry::ir_bytes`,
+// then the object code
+fn encode_object(ir_bytes: usize, object: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8 + object.len());
+    payload.extend_from_slice(&(ir_bytes as u64).to_le_bytes());
+    payload.extend_from_slice(object);
+    payload
+}
+
+fn decode_object(payload: &[u8]) -> Option<(usize, &[u8])> {
+    let (ir_bytes, object) = payload.split_first_chunk::<8>()?;
+    Some((u64::from_le_bytes(*ir_bytes) as usize, object))
+}
+
+/// The kernel at `address` as an `F`.
+/// # Safety
+/// `address` must be the entry point of a function of signature `F`,
+/// callable as long as the returned pointer is.
+unsafe fn function_at<F: KernelAbi>(address: usize) -> F {
+    // `F` is a plain `unsafe extern "C" fn` (`UnsafeFunctionPointer` is only implemented for those)
+    assert_eq!(size_of::<F>(), size_of::<usize>());
+    unsafe { mem::transmute_copy(&address) }
 }
 
 /// Converts a matrix from row-major to column-major order.
 /// Input: src - flat row-major matrix (m x n)
 /// Output: flat column-major matrix (m x n)
 #[inline(always)]
-pub fn row_major_to_col_major(src: &[f32], m: usize, n: usize) -> Vec<f32> {
+pub fn row_major_to_col_major<T: Scalar>(src: &[T], m: usize, n: usize) -> Vec<T> {
     assert!(
         !src.is_empty(),
         "row_major_to_col_major :: `src` can't be empty"
     );
-    let mut dst = vec![0.0; m * n];
+    let mut dst = vec![T::ZERO; m * n];
+    row_major_to_col_major_into(src, m, n, &mut dst);
+    dst
+}
+
+/// Same as [`row_major_to_col_major`], writing into `dst` (at least m x n).
+#[inline(always)]
+pub fn row_major_to_col_major_into<T: Scalar>(src: &[T], m: usize, n: usize, dst: &mut [T]) {
     for row in 0..m {
         for col in 0..n {
             dst[col * m + row] = src[row * n + col];
         }
     }
-    dst
 }
 
 /// Converts a matrix from column-major to row-major order.
 /// Input: src - flat column-major matrix (m x n)
 /// Output: flat row-major matrix (m x n)
 #[inline(always)]
-pub fn col_major_to_row_major(src: &[f32], m: usize, n: usize) -> Vec<f32> {
+pub fn col_major_to_row_major<T: Scalar>(src: &[T], m: usize, n: usize) -> Vec<T> {
     assert!(
         !src.is_empty(),
         "col_major_to_row_major :: `src` can't be empty"
     );
-    let mut dst = vec![0.0; m * n];
+    let mut dst = vec![T::ZERO; m * n];
+    col_major_to_row_major_into(src, m, n, &mut dst);
+    dst
+}
+
+/// Same as [`col_major_to_row_major`], writing into `dst` (at least m x n).
+#[inline(always)]
+pub fn col_major_to_row_major_into<T: Scalar>(src: &[T], m: usize, n: usize, dst: &mut [T]) {
     for row in 0..m {
         for col in 0..n {
             dst[row * n + col] = src[col * m + row];
         }
     }
-    dst
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::{UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU, UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU};
+    use crate::common::{UNROLLED_IR_TEMPLATE_HALF_JIT_CPU, UNROLLED_IR_TEMPLATE_JIT_CPU};
+    use crate::common::{assert_vec_eq, generate_random_matrix, native_matmul};
+    use crate::llvm::template::tile_size;
+    use std::collections::HashSet;
 
     #[test]
-    fn test_jit_caching() {
-        let cache = JIT_CACHE.get_or_init(JitCache::new);
+    fn test_parse_ir_diagnostic() {
+        assert_eq!(
+            parse_ir_diagnostic("matmul_ir:4:20: error: expected type\n  %a = ...\n   ^"),
+            (Some(4), Some(20))
+        );
+        assert_eq!(parse_ir_diagnostic("something went wrong"), (None, None));
+    }
 
-        let shape1: ShapeKey = (2, 2, 2);
-        let shape2: ShapeKey = (3, 3, 3);
+    #[test]
+    fn test_specialization_bits() {
+        for policy in [
+            Specialization::Always,
+            Specialization::AfterCalls(0),
+            Specialization::AfterCalls(u32::MAX),
+            Specialization::Never,
+        ] {
+            assert_eq!(Specialization::from_bits(policy.to_bits()), policy);
+        }
+    }
 
-        let entry1_a = cache
-            .get_or_compile(shape1, None)
-            .expect("Failed to compile shape1");
+    #[test]
+    fn test_instantiate_template_trans_unsupported() {
+        let template = "{M} {K} {N} {TRANS_A}";
+        let ir = instantiate_template(
+            template,
+            (2, 3, 4),
+            (Trans::Yes, Trans::No),
+            ElemTypes::of::<f32>(),
+        )
+        .expect("TRANS_A is in the template");
+        assert_eq!(ir, "2 4 3 true");
 
-        let entry1_b = cache
-            .get_or_compile(shape1, None)
-            .expect("Failed to compile shape1 again");
-        assert!(
-            Arc::ptr_eq(&entry1_a, &entry1_b),
-            "Cache should return the same Arc for the same shape"
+        let result = instantiate_template(
+            template,
+            (2, 3, 4),
+            (Trans::No, Trans::Yes),
+            ElemTypes::of::<f32>(),
         );
+        assert!(matches!(
+            result,
+            Err(MatmulError::TransposeUnsupported {
+                placeholder: "{TRANS_B}"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_instantiate_template_elem_type() {
+        let template = "{M}x{N}x{K} {ELEM_TY} {ELEM_SUFFIX} {ELEM_BYTES}";
+        let ir = instantiate_template(template, (2, 3, 4), NO_TRANS, ElemTypes::of::<f64>())
+            .expect("ELEM_TY is in the template");
+        assert_eq!(ir, "2x3x4 double f64 8");
 
-        let entry2 = cache
-            .get_or_compile(shape2, None)
-            .expect("Failed to compile shape2");
+        // a float-only template still works for f32, and is refused for f64
+        let template = "{M}x{N}x{K} float";
         assert!(
-            !Arc::ptr_eq(&entry1_a, &entry2),
-            "Cache should return different Arcs for different shapes"
+            instantiate_template(template, (2, 3, 4), NO_TRANS, ElemTypes::of::<f32>()).is_ok()
         );
+        let result = instantiate_template(template, (2, 3, 4), NO_TRANS, ElemTypes::of::<f64>());
+        assert!(matches!(
+            result,
+            Err(MatmulError::ElemTypeUnsupported { elem: "double" })
+        ));
+    }
 
-        let entry1_c = cache
-            .get_or_compile(shape1, None)
-            .expect("Failed to retrieve shape1");
-        assert!(
-            Arc::ptr_eq(&entry1_a, &entry1_c),
-            "Original entry should still be in cache"
+    #[test]
+    fn test_instantiate_template_half() {
+        let template = "{M}x{N}x{K} {ELEM_TY} {ACC_TY} {OUT_CAST} {OUT_TY} {OUT_BYTES}";
+        let ir = instantiate_template(
+            template,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::half::<f32>(HalfFormat::Bf16),
+        )
+        .expect("all placeholders are in the template");
+        assert_eq!(ir, "2x3x4 bfloat float bitcast float 4");
+        let ir = instantiate_template(
+            template,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::half::<u16>(HalfFormat::F16),
+        )
+        .expect("all placeholders are in the template");
+        assert_eq!(ir, "2x3x4 half float fptrunc half 2");
+
+        // a plain template would accumulate in `half`, and store it where the caller expects `f32`
+        let result = instantiate_template(
+            DEFAULT_IR_TEMPLATE_JIT_CPU,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::half::<f32>(HalfFormat::F16),
         );
+        assert!(matches!(
+            result,
+            Err(MatmulError::MixedPrecisionUnsupported {
+                elem: "half",
+                out: "float"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_instantiate_template_int() {
+        let template = "{M}x{N}x{K} {ELEM_TY} {ELEM_EXT} {ACC_TY} {CHECKED}";
+        let ir = instantiate_template(
+            template,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::int::<u8>(Overflow::Checked),
+        )
+        .expect("all placeholders are in the template");
+        assert_eq!(ir, "2x3x4 i8 zext i32 true");
+
+        // checked arithmetic needs the flag, wrapping works with any integer template
+        let template = "{M}x{N}x{K} {ELEM_TY} {ELEM_EXT} {ACC_TY}";
+        let ir = instantiate_template(
+            template,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::int::<i64>(Overflow::Wrapping),
+        )
+        .expect("wrapping needs no {CHECKED}");
+        assert_eq!(ir, "2x3x4 i64 bitcast i64");
+        let result = instantiate_template(
+            template,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::int::<i64>(Overflow::Checked),
+        );
+        assert!(matches!(result, Err(MatmulError::OverflowCheckUnsupported)));
+    }
+
+    #[test]
+    fn test_instantiate_template_errors() {
+        let result = instantiate_template("{M} {K}", (2, 3, 4), NO_TRANS, ElemTypes::of::<f32>());
+        assert!(matches!(
+            result,
+            Err(MatmulError::TemplateUnusedPlaceholders { names }) if names == ["N"]
+        ));
+        let result = instantiate_template(
+            "{M} {N} {K}\n{ELEM_TYP}",
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::of::<f32>(),
+        );
+        assert!(matches!(
+            result,
+            Err(MatmulError::TemplateUnknownPlaceholder { name, line: 2 }) if name == "ELEM_TYP"
+        ));
+        let result =
+            instantiate_template("{M} {N} {K/0}", (2, 3, 4), NO_TRANS, ElemTypes::of::<f32>());
+        assert!(matches!(
+            result,
+            Err(MatmulError::TemplateExpression { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_shipped_templates_instantiate() {
+        let f32s = ElemTypes::of::<f32>();
+        for (template, elems) in [
+            (DEFAULT_IR_TEMPLATE_JIT_CPU, f32s),
+            (UNROLLED_IR_TEMPLATE_JIT_CPU, f32s),
+            (TILED_IR_TEMPLATE_JIT_CPU, f32s),
+            (DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU, f32s),
+            (UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU, f32s),
+            (DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU, f32s),
+            (UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU, f32s),
+            (
+                DEFAULT_IR_TEMPLATE_HALF_JIT_CPU,
+                ElemTypes::half::<f32>(HalfFormat::Bf16),
+            ),
+            (
+                UNROLLED_IR_TEMPLATE_HALF_JIT_CPU,
+                ElemTypes::half::<f32>(HalfFormat::Bf16),
+            ),
+            (
+                DEFAULT_IR_TEMPLATE_INT_JIT_CPU,
+                ElemTypes::int::<i32>(Overflow::Checked),
+            ),
+        ] {
+            let ir = instantiate_template(template, (8, 16, 24), NO_TRANS, elems)
+                .expect("shipped templates instantiate");
+            assert!(!ir.contains("{M") && !ir.contains("{ELEM"));
+        }
+    }
+
+    #[test]
+    fn test_shipped_template_headers() {
+        fn entry<F: KernelAbi>(template: &str, elems: ElemTypes) -> String {
+            let options = JitOptions::new().template(template);
+            let source = instantiate_kernel::<F>(
+                &options,
+                (4, 8, 4),
+                MatrixLayout::RowMajor,
+                NO_TRANS,
+                elems,
+            )
+            .expect("shipped templates match their kernel");
+            assert_eq!(source.function_name, F::DEFAULT_FUNCTION_NAME);
+            source.ir
+        }
+        let f32s = ElemTypes::of::<f32>();
+        let bf16 = ElemTypes::half::<f32>(HalfFormat::Bf16);
+        entry::<LlMatmulJitSig>(DEFAULT_IR_TEMPLATE_JIT_CPU, f32s);
+        entry::<LlMatmulJitSig>(TILED_IR_TEMPLATE_JIT_CPU, f32s);
+        let unrolled = entry::<LlMatmulJitSig>(UNROLLED_IR_TEMPLATE_JIT_CPU, f32s);
+        assert!(unrolled.contains("align 32"));
+        entry::<LlGemmJitSig>(DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU, f32s);
+        entry::<LlGemmJitSig>(UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU, f32s);
+        entry::<LlBatchedMatmulJitSig>(DEFAULT_IR_TEMPLATE_BATCHED_JIT_CPU, f32s);
+        entry::<LlBatchedMatmulJitSig>(UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU, f32s);
+        entry::<LlHalfMatmulJitSig>(DEFAULT_IR_TEMPLATE_HALF_JIT_CPU, bf16);
+        entry::<LlHalfMatmulJitSig>(UNROLLED_IR_TEMPLATE_HALF_JIT_CPU, bf16);
+        entry::<LlIntMatmulJitSig<i8>>(
+            DEFAULT_IR_TEMPLATE_INT_JIT_CPU,
+            ElemTypes::int::<i8>(Overflow::Wrapping),
+        );
+        let options = JitOptions::new().template(DEFAULT_IR_TEMPLATE_DYN_JIT_CPU);
+        let source = instantiate_kernel::<LlDynMatmulJitSig>(
+            &options,
+            DYNAMIC_SHAPE,
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            f32s,
+        )
+        .unwrap();
+        assert_eq!(source.function_name, DEFAULT_FUNCTION_NAME_DYN_JIT_CPU);
+    }
+
+    #[test]
+    fn test_template_header_checks() {
+        let kernel = |template: &str, shape: ShapeKey| {
+            instantiate_kernel::<LlMatmulJitSig>(
+                &JitOptions::new().template(template),
+                shape,
+                MatrixLayout::RowMajor,
+                NO_TRANS,
+                ElemTypes::of::<f32>(),
+            )
+        };
+        // the entry point comes from the header, an explicit function name still wins
+        let template = ";; entry: my_matmul\n;; align: 16\n; {M} {N} {K}\n";
+        let source = kernel(template, (2, 3, 4)).unwrap();
+        assert_eq!(
+            (source.function_name.as_str(), source.align, source.fallback),
+            ("my_matmul", 16, false)
+        );
+        let options = JitOptions::new().template(template).function_name("other");
+        let source = instantiate_kernel::<LlMatmulJitSig>(
+            &options,
+            (2, 3, 4),
+            MatrixLayout::RowMajor,
+            NO_TRANS,
+            ElemTypes::of::<f32>(),
+        )
+        .unwrap();
+        assert_eq!(source.function_name, "other");
+
+        // row major: the template's M is n
+        let template = ";; require: M % 8 == 0\n; {M} {N} {K}\n";
+        assert_eq!(
+            kernel(template, (3, 8, 5)).unwrap().ir,
+            ";; require: M % 8 == 0\n; 8 3 5\n"
+        );
+        assert!(matches!(
+            kernel(template, (8, 3, 5)),
+            Err(MatmulError::TemplateRequirement { requirement, shape: (3, 8, 5) })
+                if requirement == "M % 8 == 0"
+        ));
+        let fallback = ";; require: M % 8 == 0\n;; fallback: default\n; {M} {N} {K}\n";
+        let source = kernel(fallback, (8, 3, 5)).unwrap();
+        assert_eq!(
+            source.ir,
+            kernel(DEFAULT_IR_TEMPLATE_JIT_CPU, (8, 3, 5)).unwrap().ir
+        );
+        assert_eq!(source.function_name, DEFAULT_FUNCTION_NAME_JIT_CPU);
+
+        assert!(matches!(
+            kernel(";; abi: (ptr, ptr, ptr) -> i32\n; {M} {N} {K}\n", (2, 3, 4)),
+            Err(MatmulError::TemplateAbiMismatch {
+                expected: MATMUL_ABI,
+                ..
+            })
+        ));
+        assert!(matches!(
+            kernel(";; elem: double\n; {M} {N} {K}\n", (2, 3, 4)),
+            Err(MatmulError::ElemTypeUnsupported { elem: "float" })
+        ));
+        assert!(matches!(
+            kernel(";; entry: a\n;; entyr: b\n; {M} {N} {K}\n", (2, 3, 4)),
+            Err(MatmulError::TemplateHeader { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_llvm_signature() {
+        assert_eq!(
+            llvm_signature(LlMatmulJitSig::<f32>::ABI, "float"),
+            "void (ptr, ptr, ptr)"
+        );
+        assert_eq!(
+            llvm_signature(LlGemmJitSig::<f64>::ABI, "double"),
+            "void (double, ptr, ptr, double, ptr)"
+        );
+        assert_eq!(
+            llvm_signature(LlIntMatmulJitSig::<i8>::ABI, "i8"),
+            "i32 (ptr, ptr, ptr)"
+        );
+    }
+
+    #[test]
+    fn test_tile_size() {
+        assert_eq!(tile_size(1024), 8);
+        assert_eq!(tile_size(36), 4);
+        assert_eq!(tile_size(9), 1);
+        let ir = instantiate_template(
+            "{M} {TILE_M} {TILE_N} {TILE_K} {VEC_TA_SIZE} {VEC_TB_SIZE} {VEC_TC_SIZE}",
+            (512, 6, 20),
+            NO_TRANS,
+            ElemTypes::of::<f32>(),
+        )
+        .expect("all placeholders are in the template");
+        assert_eq!(ir, "512 8 2 4 32 8 16");
+    }
+
+    #[test]
+    fn test_default_template_tiled_past_threshold() {
+        let small = (NAIVE_TEMPLATE_MAX_DIM, 4, 4);
+        let large = (4, 4, NAIVE_TEMPLATE_MAX_DIM + 1);
+        assert_eq!(
+            default_template::<LlMatmulJitSig>(small),
+            DEFAULT_IR_TEMPLATE_JIT_CPU
+        );
+        assert_eq!(
+            default_template::<LlMatmulJitSig>(large),
+            TILED_IR_TEMPLATE_JIT_CPU
+        );
+        // no tiled gemm template, the naive one stays the default
+        assert_eq!(
+            default_template::<LlGemmJitSig>(large),
+            DEFAULT_IR_TEMPLATE_GEMM_JIT_CPU
+        );
+    }
+
+    #[test]
+    fn test_instantiate_template_dynamic() {
+        // no {M}, {N}, {K} in a shape-generic template, that's fine for DYNAMIC_SHAPE only
+        let ir = instantiate_template(
+            DEFAULT_IR_TEMPLATE_DYN_JIT_CPU,
+            DYNAMIC_SHAPE,
+            NO_TRANS,
+            ElemTypes::of::<f64>(),
+        )
+        .expect("the dynamic template needs no shape");
+        assert!(ir.contains("double* %a"));
+        assert!(!ir.contains("{ELEM"));
+        let result = instantiate_template(
+            DEFAULT_IR_TEMPLATE_DYN_JIT_CPU,
+            (2, 3, 4),
+            NO_TRANS,
+            ElemTypes::of::<f32>(),
+        );
+        assert!(matches!(
+            result,
+            Err(MatmulError::TemplateMissingPlaceholder)
+        ));
+    }
+
+    #[test]
+    fn test_row_block() {
+        assert_eq!(row_block((512, 512, 512)), Some(32));
+        assert_eq!(row_block((1024, 1024, 1024)), Some(32));
+        assert_eq!(row_block((4096, 64, 64)), Some(128));
+        // 100 rows: 3 blocks of 32 and one of 4
+        assert_eq!(row_block((100, 100, 300)), Some(32));
+        // too little work, or too few rows to split
+        assert_eq!(row_block((128, 128, 127)), None);
+        assert_eq!(row_block((63, 4096, 4096)), None);
+        assert_eq!(row_block((0, 0, 0)), None);
+    }
+
+    #[test]
+    fn test_disk_cache_key_has_features() {
+        let host = CodegenOptions::host();
+        let generic = host.with_overrides(&JitOptions::new().target_cpu("x86-64"));
+        let no_fma = host.with_overrides(&JitOptions::new().target_features("-fma"));
+        let keys: HashSet<String> = [host, &generic, &no_fma]
+            .into_iter()
+            .map(|codegen| disk_cache_key("ir", DEFAULT_FUNCTION_NAME_JIT_CPU, codegen))
+            .collect();
+        assert_eq!(keys.len(), 3);
+    }
+
+    fn test_aot_kernels_vs_native<T: Scalar>() {
+        for kernel in aot_kernels::<T>() {
+            let (m, n, k) = kernel.shape;
+            let a: Vec<T> = generate_random_matrix(m, k, 1);
+            let b: Vec<T> = generate_random_matrix(k, n, 2);
+            let expected = native_matmul(&a, (m, k), &b, (k, n));
+            let max = expected
+                .iter()
+                .fold(0f64, |acc, v| acc.max(v.to_f64().abs()));
+            let mut result = vec![T::ZERO; m * n];
+            unsafe { kernel.call(a.as_ptr(), b.as_ptr(), result.as_mut_ptr()) };
+            assert_vec_eq(&result, &expected, T::from_f64(max * 1e-5));
+        }
+    }
+
+    #[test]
+    fn test_aot_kernels() {
+        // whatever the manifest (aot_kernels.txt) lists, no JIT involved
+        test_aot_kernels_vs_native::<f32>();
+        test_aot_kernels_vs_native::<f64>();
+    }
+
+    #[test]
+    fn test_aot_kernel_dispatch() {
+        for kernel in aot_kernels::<f32>() {
+            let default = default_template::<LlMatmulJitSig>(kernel.shape);
+            let dispatched = aot_kernel::<f32>(kernel.shape, &JitOptions::new()).unwrap();
+            if kernel.template_hash == fnv1a(default.as_bytes()) {
+                assert!(dispatched.is_some_and(|d| std::ptr::eq(d, kernel)));
+            }
+            // another template means another kernel
+            let other = aot_kernel::<f32>(kernel.shape, &JitOptions::new().template("{M} {N} {K}"))
+                .unwrap();
+            assert!(other.is_none());
+        }
+        // f64 kernels aren't picked for f32 (and the other way around)
+        for kernel in aot_kernels::<f64>() {
+            if aot_kernels::<f32>().iter().all(|k| k.shape != kernel.shape) {
+                assert!(
+                    aot_kernel::<f32>(kernel.shape, &JitOptions::new())
+                        .unwrap()
+                        .is_none()
+                );
+            }
+        }
     }
 }
@@ -13,6 +13,21 @@ entry:
   ret void
 }
 
+; same as ll_matmul_4x4, double precision
+define void @ll_matmul_4x4_f64(double*  %a, double*  %b, double*  %result) {
+entry:
+  ; load matrix
+  %a_col = call <16 x double> @llvm.matrix.column.major.load.v16f64.p0f64(double* %a, i64 4, i1 false, i32 4, i32 4)
+  %b_col = call <16 x double> @llvm.matrix.column.major.load.v16f64.p0f64(double* %b, i64 4, i1 false, i32 4, i32 4)
+
+  ; row major in and out: B^T * A^T = (A * B)^T
+  %res_transposed = call <16 x double> @llvm.matrix.multiply.v16f64.v16f64.v16f64(<16 x double> %b_col, <16 x double> %a_col, i32 4, i32 4, i32 4)
+
+  call void @llvm.matrix.column.major.store.v16f64.p0f64(<16 x double> %res_transposed, double* %result, i64 4, i1 false, i32 4, i32 4)
+
+  ret void
+}
+
 
 ; https://llvm.org/doxygen/classllvm_1_1ShuffleVectorInst.html
 ; https://www.llvm.org/docs/LangRef.html#shufflevector-instruction
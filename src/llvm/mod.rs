@@ -1,8 +1,75 @@
+pub(crate) mod cache;
+pub use cache::JitCache;
+pub use cache::JitCacheCapacity;
+pub use cache::JitCacheStats;
+pub use cache::KernelStats;
+pub use cache::jit_cache;
+pub use cache::set_jit_cache_capacity;
+
+pub(crate) mod disk_cache;
+pub use disk_cache::set_jit_cache_dir;
+
+pub(crate) mod options;
+pub use options::JitOptions;
+pub use options::TemplateSource;
+pub use options::set_jit_options;
+
+pub(crate) mod orc;
+
+pub(crate) mod template;
+
+pub mod error;
+pub use error::MatmulError;
+
+// the codegen settings of `JitOptions`
+pub use inkwell::OptimizationLevel;
+pub use inkwell::targets::{CodeModel, RelocMode};
+
 pub mod jit;
+pub use jit::BatchLayout;
+pub use jit::JitEntry;
+pub use jit::KernelInspection;
+pub use jit::LlBatchedMatmulJitSig;
+pub use jit::LlDynMatmulJitSig;
+pub use jit::LlGemmJitSig;
+pub use jit::LlHalfMatmulJitSig;
+pub use jit::LlIntMatmulJitSig;
+pub use jit::LlMatmulJitSig;
+pub use jit::MatrixLayout;
+pub use jit::Specialization;
+pub use jit::Trans;
 pub use jit::col_major_to_row_major;
+pub use jit::col_major_to_row_major_into;
+pub use jit::compile_matmul_jit_dynamic;
+pub use jit::compile_matmul_jit_half;
+pub use jit::compile_matmul_jit_with_layout;
+pub use jit::compile_matmul_jit_with_options;
 pub use jit::compile_matmul_jit_with_template;
+pub use jit::inspect_matmul_jit;
+pub use jit::ll_gemm_jit;
+pub use jit::ll_matmul_jit_batched;
+pub use jit::ll_matmul_jit_half;
+pub use jit::ll_matmul_jit_int;
+pub use jit::ll_matmul_jit_into;
+pub use jit::ll_matmul_jit_into_with;
+pub use jit::ll_matmul_jit_with_options;
 pub use jit::ll_matmul_jit_with_template;
 pub use jit::row_major_to_col_major;
+pub use jit::row_major_to_col_major_into;
+pub use jit::set_specialization;
+pub use jit::try_compile_matmul_jit_dynamic;
+pub use jit::try_compile_matmul_jit_half;
+pub use jit::try_compile_matmul_jit_with_layout;
+pub use jit::try_compile_matmul_jit_with_options;
+pub use jit::try_compile_matmul_jit_with_template;
+pub use jit::try_ll_gemm_jit;
+pub use jit::try_ll_matmul_jit_batched;
+pub use jit::try_ll_matmul_jit_half;
+pub use jit::try_ll_matmul_jit_int;
+pub use jit::try_ll_matmul_jit_into;
+pub use jit::try_ll_matmul_jit_into_with;
+pub use jit::try_ll_matmul_jit_with_options;
+pub use jit::try_ll_matmul_jit_with_template;
 
 #[cfg(feature = "gpu")]
 pub mod gpu;
@@ -15,4 +82,6 @@ mod compiled;
 #[allow(unused)]
 pub use compiled::ll_matmul_4x4;
 #[allow(unused)]
+pub use compiled::ll_matmul_4x4_f64;
+#[allow(unused)]
 pub use compiled::ll_matmul_4x4_unrolled;
@@ -0,0 +1,502 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::common::{TARGET_CPU_ENV, TARGET_FEATURES_ENV};
+use crate::llvm::error::MatmulError;
+use crate::llvm::jit::{KernelAbi, ShapeKey, default_template};
+
+use inkwell::OptimizationLevel;
+use inkwell::targets::{CodeModel, RelocMode, TargetMachine};
+
+/// Where a kernel's template comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TemplateSource {
+    /// The template text itself.
+    Inline(String),
+    /// A file, read again when it changes.
+    File(PathBuf),
+}
+
+/// How kernels are compiled, every setting is optional.
+///
+/// A setting left unset in the options passed to a call comes from the process
+/// default ([`set_jit_options`]), then from the env vars (`LL_MATMUL_TEMPLATE`,
+/// `LL_MATMUL_TEMPLATE_FUNCTION_NAME` and the like, `LL_MATMUL_TARGET_CPU`,
+/// `LL_MATMUL_TARGET_FEATURES`), then from the built-in default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JitOptions {
+    pub(crate) template: Option<TemplateSource>,
+    pub(crate) function_name: Option<String>,
+    opt_level: Option<OptimizationLevel>,
+    passes: Option<String>,
+    vectorize: Option<bool>,
+    unroll: Option<bool>,
+    verify_each: Option<bool>,
+    reloc_mode: Option<RelocMode>,
+    code_model: Option<CodeModel>,
+    target_cpu: Option<String>,
+    target_features: Option<String>,
+    threads: Option<usize>,
+}
+
+impl JitOptions {
+    pub const fn new() -> Self {
+        Self {
+            template: None,
+            function_name: None,
+            opt_level: None,
+            passes: None,
+            vectorize: None,
+            unroll: None,
+            verify_each: None,
+            reloc_mode: None,
+            code_model: None,
+            target_cpu: None,
+            target_features: None,
+            threads: None,
+        }
+    }
+
+    /// Template text, instead of the kernel's default.
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.template = Some(TemplateSource::Inline(template.into()));
+        self
+    }
+
+    /// Template file, read on every compile.
+    pub fn template_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.template = Some(TemplateSource::File(path.into()));
+        self
+    }
+
+    /// Entry point looked up in the compiled module, instead of the kernel's default.
+    pub fn function_name(mut self, name: impl Into<String>) -> Self {
+        self.function_name = Some(name.into());
+        self
+    }
+
+    /// Code generation level of the target machine and the engine, default `Aggressive`.
+    pub fn opt_level(mut self, level: OptimizationLevel) -> Self {
+        self.opt_level = Some(level);
+        self
+    }
+
+    /// New pass manager pipeline run on the instantiated module
+    /// (`opt -passes=` syntax), default `lower-matrix-intrinsics`.
+    /// It has to lower the matrix intrinsics, e.g. `lower-matrix-intrinsics,default<O3>`.
+    pub fn passes(mut self, pipeline: impl Into<String>) -> Self {
+        self.passes = Some(pipeline.into());
+        self
+    }
+
+    /// Loop, SLP vectorization and interleaving in the pipeline, default on.
+    pub fn vectorize(mut self, enabled: bool) -> Self {
+        self.vectorize = Some(enabled);
+        self
+    }
+
+    /// Loop unrolling in the pipeline, default on.
+    pub fn unroll(mut self, enabled: bool) -> Self {
+        self.unroll = Some(enabled);
+        self
+    }
+
+    /// Verifies the module after each pass, default off.
+    pub fn verify_each(mut self, enabled: bool) -> Self {
+        self.verify_each = Some(enabled);
+        self
+    }
+
+    /// Relocation model of the target machine, default `PIC`.
+    pub fn reloc_mode(mut self, mode: RelocMode) -> Self {
+        self.reloc_mode = Some(mode);
+        self
+    }
+
+    /// Code model of the target machine, default `JITDefault`.
+    pub fn code_model(mut self, model: CodeModel) -> Self {
+        self.code_model = Some(model);
+        self
+    }
+
+    /// LLVM CPU name (`x86-64`, `skylake`, ...), instead of the host's.
+    /// Unless [`JitOptions::target_features`] is set too, the kernels
+    /// get that CPU's features and nothing the host has on top.
+    pub fn target_cpu(mut self, cpu: impl Into<String>) -> Self {
+        self.target_cpu = Some(cpu.into());
+        self
+    }
+
+    /// LLVM feature string (`+avx2,+fma`, `-avx512f`, ...) applied on top of the CPU's,
+    /// instead of the host's.
+    pub fn target_features(mut self, features: impl Into<String>) -> Self {
+        self.target_features = Some(features.into());
+        self
+    }
+
+    /// Threads a large matmul is split over, in row blocks, default: the available parallelism.
+    /// The blocks depend on the shape alone, the result is the same, bit for bit, whatever the count.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    // the `ir_template` argument of the older entry points
+    pub(crate) fn with_template(ir_template: Option<&str>) -> Self {
+        match ir_template {
+            Some(template) => Self::new().template(template),
+            None => Self::new(),
+        }
+    }
+
+    /// `self`, with what it leaves unset taken from `fallback`.
+    pub(crate) fn or(self, fallback: &Self) -> Self {
+        Self {
+            template: self.template.or_else(|| fallback.template.clone()),
+            function_name: self
+                .function_name
+                .or_else(|| fallback.function_name.clone()),
+            opt_level: self.opt_level.or(fallback.opt_level),
+            passes: self.passes.or_else(|| fallback.passes.clone()),
+            vectorize: self.vectorize.or(fallback.vectorize),
+            unroll: self.unroll.or(fallback.unroll),
+            verify_each: self.verify_each.or(fallback.verify_each),
+            reloc_mode: self.reloc_mode.or(fallback.reloc_mode),
+            code_model: self.code_model.or(fallback.code_model),
+            target_cpu: self.target_cpu.or_else(|| fallback.target_cpu.clone()),
+            target_features: self
+                .target_features
+                .or_else(|| fallback.target_features.clone()),
+            threads: self.threads.or(fallback.threads),
+        }
+    }
+
+    /// Without the template and the function name, which only fit one kind of kernel.
+    pub(crate) fn codegen_only(&self) -> Self {
+        Self {
+            template: None,
+            function_name: None,
+            ..self.clone()
+        }
+    }
+
+    /// `F`'s default template for `shape` pinned over the process default and the env vars.
+    pub(crate) fn pin_default_template<F: KernelAbi>(&self, shape: ShapeKey) -> Self {
+        Self {
+            template: Some(TemplateSource::Inline(
+                default_template::<F>(shape).to_string(),
+            )),
+            function_name: Some(F::DEFAULT_FUNCTION_NAME.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Whether anything but the template, the function name and the threads is set.
+    pub(crate) fn has_codegen(&self) -> bool {
+        Self {
+            threads: None,
+            ..self.codegen_only()
+        } != Self::new()
+    }
+
+    /// The threads of the options, else of the process default, else the available parallelism.
+    pub(crate) fn resolve_threads(&self) -> usize {
+        self.threads
+            .or_else(|| JIT_OPTIONS.read().unwrap().threads)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+            .max(1)
+    }
+
+    /// The env var layer for kernels of `F`.
+    fn from_env<F: KernelAbi>() -> Self {
+        Self {
+            template: env::var_os(F::TEMPLATE_ENV).map(|path| TemplateSource::File(path.into())),
+            function_name: env::var(F::FUNCTION_NAME_ENV).ok(),
+            target_cpu: env::var(TARGET_CPU_ENV).ok(),
+            target_features: env::var(TARGET_FEATURES_ENV).ok(),
+            ..Self::new()
+        }
+    }
+}
+
+static JIT_OPTIONS: RwLock<JitOptions> = RwLock::new(JitOptions::new());
+
+/// Sets the process default [`JitOptions`] of every kernel compiled from now on,
+/// the env vars only fill what they leave unset.
+/// Their template and function name only apply to the matmul kernels
+/// (the ones of `LL_MATMUL_TEMPLATE`), the other kernels keep theirs.
+/// Kernels already compiled for other options stay cached under them.
+pub fn set_jit_options(options: JitOptions) {
+    *JIT_OPTIONS.write().unwrap() = options;
+}
+
+/// The options a kernel of `F` is compiled with: the call's,
+/// then the process default, then the env vars.
+/// Resolved on every call, so changing any layer mid-process picks a different kernel.
+pub(crate) fn resolve_options<F: KernelAbi>(options: &JitOptions) -> JitOptions {
+    let process = JIT_OPTIONS.read().unwrap();
+    let process = if F::PROCESS_TEMPLATE {
+        process.clone()
+    } else {
+        process.codegen_only()
+    };
+    options
+        .clone()
+        .or(&process)
+        .or(&JitOptions::from_env::<F>())
+}
+
+pub(crate) const LOWER_MATRIX_INTRINSICS: &str = "lower-matrix-intrinsics";
+
+/// Everything that changes the machine code of a kernel besides its IR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CodegenOptions {
+    pub(crate) cpu: String,
+    pub(crate) features: String,
+    pub(crate) opt_level: OptimizationLevel,
+    pub(crate) passes: String,
+    pub(crate) vectorize: bool,
+    pub(crate) unroll: bool,
+    pub(crate) verify_each: bool,
+    pub(crate) reloc_mode: RelocMode,
+    pub(crate) code_model: CodeModel,
+}
+
+impl CodegenOptions {
+    /// The built-in defaults, for what the host CPU supports
+    /// (as `-mcpu=native` would see it).
+    pub(crate) fn host() -> &'static Self {
+        static HOST: OnceLock<CodegenOptions> = OnceLock::new();
+        HOST.get_or_init(|| Self {
+            cpu: TargetMachine::get_host_cpu_name().to_string(),
+            features: TargetMachine::get_host_cpu_features().to_string(),
+            opt_level: OptimizationLevel::Aggressive,
+            passes: LOWER_MATRIX_INTRINSICS.to_string(),
+            vectorize: true,
+            unroll: true,
+            // TODO : this pass fails set to true
+            verify_each: false,
+            reloc_mode: RelocMode::PIC,
+            code_model: CodeModel::JITDefault,
+        })
+    }
+
+    /// The built-in defaults overridden by `options` (already resolved).
+    pub(crate) fn resolve(options: &JitOptions) -> Self {
+        Self::host().with_overrides(options)
+    }
+
+    pub(crate) fn with_overrides(&self, options: &JitOptions) -> Self {
+        let (cpu, features) = match (&options.target_cpu, &options.target_features) {
+            (None, None) => (self.cpu.clone(), self.features.clone()),
+            (None, Some(features)) => (self.cpu.clone(), features.clone()),
+            // the host's features would bring back what the CPU override is meant to drop
+            (Some(cpu), features) => (cpu.clone(), features.clone().unwrap_or_default()),
+        };
+        Self {
+            cpu,
+            features,
+            opt_level: options.opt_level.unwrap_or(self.opt_level),
+            passes: options
+                .passes
+                .clone()
+                .unwrap_or_else(|| self.passes.clone()),
+            vectorize: options.vectorize.unwrap_or(self.vectorize),
+            unroll: options.unroll.unwrap_or(self.unroll),
+            verify_each: options.verify_each.unwrap_or(self.verify_each),
+            reloc_mode: options.reloc_mode.unwrap_or(self.reloc_mode),
+            code_model: options.code_model.unwrap_or(self.code_model),
+        }
+    }
+}
+
+impl Hash for CodegenOptions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.cpu.hash(state);
+        self.features.hash(state);
+        (self.opt_level as u32).hash(state);
+        self.passes.hash(state);
+        (self.vectorize, self.unroll, self.verify_each).hash(state);
+        (self.reloc_mode as u32).hash(state);
+        (self.code_model as u32).hash(state);
+    }
+}
+
+/// The template of the resolved options (explicit, process default or the env var
+/// of `F`, e.g. `LL_MATMUL_TEMPLATE` / `LL_GEMM_TEMPLATE`), else the default for `shape`.
+pub(crate) fn resolve_template<F: KernelAbi>(
+    template: Option<&TemplateSource>,
+    shape: ShapeKey,
+) -> Result<Cow<'_, str>, MatmulError> {
+    match template {
+        Some(TemplateSource::Inline(t)) => Ok(Cow::Borrowed(t)),
+        Some(TemplateSource::File(path)) => read_template_file(path)
+            .map(|text| Cow::Owned(text.to_string()))
+            .map_err(|source| MatmulError::TemplateIo {
+                path: path.display().to_string(),
+                source: Arc::new(source),
+            }),
+        None => Ok(Cow::Borrowed(default_template::<F>(shape))),
+    }
+}
+
+// template files by path, with the mtime and length they were read at
+type TemplateFiles = HashMap<PathBuf, (SystemTime, u64, Arc<str>)>;
+
+static TEMPLATE_FILES: Mutex<Option<TemplateFiles>> = Mutex::new(None);
+
+/// The text of the template file at `path`, read again only when its mtime or length
+/// changed since the last lookup, so an edited file is picked up mid-process.
+fn read_template_file(path: &Path) -> io::Result<Arc<str>> {
+    let meta = fs::metadata(path)?;
+    let (modified, len) = (meta.modified()?, meta.len());
+    let mut files = TEMPLATE_FILES.lock().unwrap();
+    let files = files.get_or_insert_with(HashMap::new);
+    let cached = files
+        .get(path)
+        .filter(|(m, l, _)| (*m, *l) == (modified, len));
+    if let Some((_, _, text)) = cached {
+        return Ok(text.clone());
+    }
+    let text: Arc<str> = fs::read_to_string(path)?.into();
+    files.insert(path.to_path_buf(), (modified, len, text.clone()));
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_file_reread_on_change() {
+        let path = env::temp_dir().join(format!("llmm-template-{}.ll", std::process::id()));
+        fs::write(&path, "; first").unwrap();
+        let first = read_template_file(&path).unwrap();
+        assert_eq!(&*first, "; first");
+        // unchanged, the memoized text
+        assert!(Arc::ptr_eq(&first, &read_template_file(&path).unwrap()));
+        // edited mid-process (the length changes even if the mtime doesn't tick)
+        fs::write(&path, "; second one").unwrap();
+        assert_eq!(&*read_template_file(&path).unwrap(), "; second one");
+        fs::remove_file(&path).unwrap();
+        assert!(read_template_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_codegen_options_overrides() {
+        let host = CodegenOptions {
+            cpu: "skylake-avx512".to_string(),
+            features: "+avx2,+fma,+avx512f".to_string(),
+            opt_level: OptimizationLevel::Aggressive,
+            passes: "lower-matrix-intrinsics".to_string(),
+            vectorize: true,
+            unroll: true,
+            verify_each: false,
+            reloc_mode: RelocMode::PIC,
+            code_model: CodeModel::JITDefault,
+        };
+        let resolve = |options: JitOptions| {
+            let codegen = host.with_overrides(&options);
+            assert_eq!(codegen.opt_level, host.opt_level);
+            (codegen.cpu, codegen.features)
+        };
+        assert_eq!(
+            resolve(JitOptions::new()),
+            (host.cpu.clone(), host.features.clone())
+        );
+        // the CPU's own features, not the host's on top
+        assert_eq!(
+            resolve(JitOptions::new().target_cpu("x86-64")),
+            ("x86-64".to_string(), String::new())
+        );
+        assert_eq!(
+            resolve(JitOptions::new().target_features("-avx512f")),
+            (host.cpu.clone(), "-avx512f".to_string())
+        );
+        assert_eq!(
+            resolve(
+                JitOptions::new()
+                    .target_cpu("haswell")
+                    .target_features("-fma")
+            ),
+            ("haswell".to_string(), "-fma".to_string())
+        );
+
+        let tuned = host.with_overrides(
+            &JitOptions::new()
+                .opt_level(OptimizationLevel::Less)
+                .passes("lower-matrix-intrinsics,instcombine")
+                .vectorize(false)
+                .verify_each(true)
+                .code_model(CodeModel::Small),
+        );
+        assert_eq!(
+            tuned,
+            CodegenOptions {
+                opt_level: OptimizationLevel::Less,
+                passes: "lower-matrix-intrinsics,instcombine".to_string(),
+                vectorize: false,
+                verify_each: true,
+                code_model: CodeModel::Small,
+                ..host.clone()
+            }
+        );
+    }
+
+    #[test]
+    fn test_jit_options_layers() {
+        let call = JitOptions::new()
+            .template("call")
+            .opt_level(OptimizationLevel::None);
+        let process = JitOptions::new()
+            .template("process")
+            .function_name("process_fn")
+            .unroll(false);
+        let env = JitOptions::new()
+            .template_file("env.tmpl")
+            .function_name("env_fn")
+            .target_cpu("x86-64");
+        assert_eq!(
+            call.clone().or(&process).or(&env),
+            JitOptions::new()
+                .template("call")
+                .function_name("process_fn")
+                .opt_level(OptimizationLevel::None)
+                .unroll(false)
+                .target_cpu("x86-64")
+        );
+        // the process template is only for the matmul kernel
+        assert_eq!(
+            call.clone().or(&process.codegen_only()).or(&env),
+            call.clone()
+                .function_name("env_fn")
+                .unroll(false)
+                .target_cpu("x86-64")
+        );
+        assert!(
+            !JitOptions::new()
+                .template("t")
+                .function_name("f")
+                .has_codegen()
+        );
+        assert!(JitOptions::new().unroll(true).has_codegen());
+        assert!(!JitOptions::new().threads(4).has_codegen());
+        assert_eq!(JitOptions::new().threads(0).resolve_threads(), 1);
+        assert_eq!(
+            JitOptions::new()
+                .threads(3)
+                .or(&JitOptions::new().threads(8))
+                .resolve_threads(),
+            3
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::sync::OnceLock;
+
+use inkwell::llvm_sys::core::LLVMCreateMemoryBufferWithMemoryRangeCopy;
+use inkwell::llvm_sys::error::{LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage};
+use inkwell::llvm_sys::orc2::lljit::{
+    LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcDisposeLLJIT, LLVMOrcLLJITAddObjectFile,
+    LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITGetMainJITDylib, LLVMOrcLLJITLookup, LLVMOrcLLJITRef,
+};
+use inkwell::llvm_sys::orc2::{
+    LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess, LLVMOrcJITDylibAddGenerator,
+};
+use inkwell::targets::{InitializationConfig, Target};
+
+use crate::llvm::error::MatmulError;
+
+/// Object code linked into its own ORC `LLJIT`, the memory of the code is freed on drop.
+///
+/// MCJIT only runs modules, it code-generates them itself; an `LLJIT` links the
+/// object the target machine already emitted (or the disk cache kept), so a
+/// kernel is code-generated once, and not at all when it's loaded from disk.
+/// Undefined symbols (libm, compiler-rt helpers) resolve against the process.
+pub(crate) struct LinkedObject {
+    jit: LLVMOrcLLJITRef,
+}
+
+impl LinkedObject {
+    /// Links `object` and looks `function_name` up in it, returns the object and the address.
+    pub(crate) fn link(object: &[u8], function_name: &str) -> Result<(Self, usize), MatmulError> {
+        // the LLJIT detects the host's target machine, which needs the native target
+        // registered; a disk cache hit links before anything else initialized it
+        static NATIVE: OnceLock<Result<(), String>> = OnceLock::new();
+        NATIVE
+            .get_or_init(|| Target::initialize_native(&InitializationConfig::default()))
+            .clone()
+            .map_err(MatmulError::EngineCreation)?;
+
+        let mut jit = ptr::null_mut();
+        // the builder is consumed, even on failure
+        check(unsafe { LLVMOrcCreateLLJIT(&mut jit, LLVMOrcCreateLLJITBuilder()) })
+            .map_err(MatmulError::EngineCreation)?;
+        let linked = Self { jit };
+
+        unsafe {
+            let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+            let mut process = ptr::null_mut();
+            check(LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+                &mut process,
+                LLVMOrcLLJITGetGlobalPrefix(jit),
+                None,
+                ptr::null_mut(),
+            ))
+            .map_err(MatmulError::EngineCreation)?;
+            LLVMOrcJITDylibAddGenerator(dylib, process);
+
+            // the buffer is owned by the JIT from here on
+            let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+                object.as_ptr().cast(),
+                object.len(),
+                c"matmul_obj".as_ptr(),
+            );
+            check(LLVMOrcLLJITAddObjectFile(jit, dylib, buffer))
+                .map_err(MatmulError::EngineCreation)?;
+        }
+
+        let not_found = |reason: String| MatmulError::SymbolNotFound {
+            name: function_name.to_string(),
+            reason,
+        };
+        let name = CString::new(function_name)
+            .map_err(|_| not_found("the name contains a NUL".to_string()))?;
+        let mut address = 0;
+        // the lookup is what materializes (links) the object
+        check(unsafe { LLVMOrcLLJITLookup(jit, &mut address, name.as_ptr()) })
+            .map_err(not_found)?;
+        if address == 0 {
+            return Err(not_found(
+                "the symbol resolved to a null address".to_string(),
+            ));
+        }
+        Ok((linked, address as usize))
+    }
+}
+
+impl Drop for LinkedObject {
+    fn drop(&mut self) {
+        // nothing to report it to, and the code is unusable either way
+        let _ = check(unsafe { LLVMOrcDisposeLLJIT(self.jit) });
+    }
+}
+
+// the JIT is only used by the thread linking the object, afterwards it just owns
+// the code's memory until it's dropped
+unsafe impl Send for LinkedObject {}
+unsafe impl Sync for LinkedObject {}
+
+fn check(error: LLVMErrorRef) -> Result<(), String> {
+    if error.is_null() {
+        return Ok(());
+    }
+    unsafe {
+        // consumes the error
+        let message = LLVMGetErrorMessage(error);
+        let message_str = CStr::from_ptr(message).to_string_lossy().into_owned();
+        LLVMDisposeErrorMessage(message);
+        Err(message_str)
+    }
+}
@@ -0,0 +1,649 @@
+// Template instantiation shared by the JIT (jit.rs) and the AOT kernels
+// build.rs compiles from the manifest: build.rs includes this file with #[path],
+// so it must not depend on anything else in the crate.
+//
+// A placeholder is `{` directly followed by an uppercase letter, a digit, `_` or `(`,
+// up to the next `}` on the same line: `{M}`, `{ELEM_TY}`, `{M*K}`, `{(K+7)/8}`.
+// Anything else is LLVM syntax and left alone: `define void @f() {`, `{ {ACC_TY}, i1 }`.
+//
+// A template can start with a header of `;; key: value` comment lines (see `TemplateHeader`),
+// they stay in the IR as comments.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Past this dimension `lower-matrix-intrinsics` blows up on the naive template,
+/// the default matmul template is then the tiled one.
+pub const NAIVE_TEMPLATE_MAX_DIM: usize = 32;
+
+/// `abi:` of the matmul kernels, `a`, `b`, `result`.
+pub(crate) const MATMUL_ABI: &str = "(ptr, ptr, ptr) -> void";
+
+/// How an element type is spelled in the IR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct IrType {
+    pub(crate) ty: &'static str,
+    pub(crate) suffix: &'static str,
+    pub(crate) bytes: usize,
+}
+
+/// What a template is instantiated with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bindings {
+    /// (m, n, k) of the column-major kernel
+    pub(crate) shape: (usize, usize, usize),
+    /// `{TRANS_A}`, `{TRANS_B}`
+    pub(crate) trans: (&'static str, &'static str),
+    pub(crate) elem: IrType,
+    pub(crate) acc: IrType,
+    pub(crate) out: IrType,
+    pub(crate) out_cast: &'static str,
+    pub(crate) ext: &'static str,
+    pub(crate) checked: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Int(usize),
+    Str(&'static str),
+}
+
+impl Bindings {
+    /// `a`, `b` and the result all of type `ty`, nothing transposed.
+    #[allow(dead_code)]
+    pub(crate) fn plain(shape: (usize, usize, usize), ty: IrType) -> Self {
+        Self {
+            shape,
+            trans: ("false", "false"),
+            elem: ty,
+            acc: ty,
+            out: ty,
+            out_cast: "bitcast",
+            ext: "bitcast",
+            checked: "false",
+        }
+    }
+
+    // the placeholder's name, its value and the dimensions it's derived from
+    fn lookup(&self, name: &str) -> Option<(&'static str, Value, &'static [&'static str])> {
+        use Value::{Int, Str};
+        let (m, n, k) = self.shape;
+        let (tile_m, tile_n, tile_k) = (tile_size(m), tile_size(n), tile_size(k));
+        Some(match name {
+            "M" => ("M", Int(m), &[]),
+            "N" => ("N", Int(n), &[]),
+            "K" => ("K", Int(k), &[]),
+            "VEC_A_SIZE" => ("VEC_A_SIZE", Int(m * k), &["M", "K"]),
+            "VEC_B_SIZE" => ("VEC_B_SIZE", Int(k * n), &["K", "N"]),
+            "VEC_C_SIZE" => ("VEC_C_SIZE", Int(m * n), &["M", "N"]),
+            "A_STRIDE" => ("A_STRIDE", Int(m), &["M"]),
+            "B_STRIDE" => ("B_STRIDE", Int(k), &["K"]),
+            "C_STRIDE" => ("C_STRIDE", Int(m), &["M"]),
+            "TILE_M" => ("TILE_M", Int(tile_m), &["M"]),
+            "TILE_N" => ("TILE_N", Int(tile_n), &["N"]),
+            "TILE_K" => ("TILE_K", Int(tile_k), &["K"]),
+            "VEC_TA_SIZE" => ("VEC_TA_SIZE", Int(tile_m * tile_k), &["M", "K"]),
+            "VEC_TB_SIZE" => ("VEC_TB_SIZE", Int(tile_k * tile_n), &["K", "N"]),
+            "VEC_TC_SIZE" => ("VEC_TC_SIZE", Int(tile_m * tile_n), &["M", "N"]),
+            "TRANS_A" => ("TRANS_A", Str(self.trans.0), &[]),
+            "TRANS_B" => ("TRANS_B", Str(self.trans.1), &[]),
+            "ELEM_TY" => ("ELEM_TY", Str(self.elem.ty), &[]),
+            "ELEM_SUFFIX" => ("ELEM_SUFFIX", Str(self.elem.suffix), &[]),
+            "ELEM_BYTES" => ("ELEM_BYTES", Int(self.elem.bytes), &[]),
+            "ACC_TY" => ("ACC_TY", Str(self.acc.ty), &[]),
+            "ACC_SUFFIX" => ("ACC_SUFFIX", Str(self.acc.suffix), &[]),
+            "ACC_BYTES" => ("ACC_BYTES", Int(self.acc.bytes), &[]),
+            "OUT_TY" => ("OUT_TY", Str(self.out.ty), &[]),
+            "OUT_SUFFIX" => ("OUT_SUFFIX", Str(self.out.suffix), &[]),
+            "OUT_BYTES" => ("OUT_BYTES", Int(self.out.bytes), &[]),
+            "OUT_CAST" => ("OUT_CAST", Str(self.out_cast), &[]),
+            "ELEM_EXT" => ("ELEM_EXT", Str(self.ext), &[]),
+            "CHECKED" => ("CHECKED", Str(self.checked), &[]),
+            _ => return None,
+        })
+    }
+}
+
+/// Why a template couldn't be instantiated, `line` is 1-based in the template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TemplateError {
+    /// `{VEC_A_SZE}`: not a placeholder the kernel is instantiated with.
+    UnknownPlaceholder { name: String, line: usize },
+    /// `{M*}`, `{K/0}`, `{ELEM_TY*2}`, `{M` ...
+    BadExpression {
+        expr: String,
+        line: usize,
+        reason: String,
+    },
+    /// A header line that isn't a known `;; key: value`.
+    BadHeader { line: usize, reason: String },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder { name, line } => {
+                write!(
+                    f,
+                    "unknown placeholder {{{}}} at template line {}",
+                    name, line
+                )
+            }
+            TemplateError::BadExpression { expr, line, reason } => write!(
+                f,
+                "bad placeholder {{{}}} at template line {}: {}",
+                expr, line, reason
+            ),
+            TemplateError::BadHeader { line, reason } => {
+                write!(f, "bad template header at line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+/// The instantiated IR and the placeholders the template used.
+#[derive(Debug)]
+pub(crate) struct Instantiated {
+    pub(crate) ir: String,
+    // a derived placeholder (`{VEC_A_SIZE}`) also uses the dimensions it's computed from
+    used: BTreeSet<&'static str>,
+}
+
+impl Instantiated {
+    pub(crate) fn uses(&self, placeholder: &str) -> bool {
+        self.used.contains(placeholder)
+    }
+
+    /// The placeholders of `required` the template never uses.
+    pub(crate) fn unused(&self, required: &[&'static str]) -> Vec<&'static str> {
+        required
+            .iter()
+            .copied()
+            .filter(|name| !self.uses(name))
+            .collect()
+    }
+}
+
+/// Replaces every placeholder of `template`, an unknown one or a broken
+/// expression is an error instead of being left in the IR.
+pub(crate) fn instantiate(
+    template: &str,
+    bindings: &Bindings,
+) -> Result<Instantiated, TemplateError> {
+    let mut ir = String::with_capacity(template.len());
+    let mut used = BTreeSet::new();
+    for (i, line) in template.split_inclusive('\n').enumerate() {
+        let mut rest = line;
+        while let Some(start) = find_placeholder(rest) {
+            ir.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let Some(end) = after.find('}') else {
+                return Err(TemplateError::BadExpression {
+                    expr: after.trim_end().to_string(),
+                    line: i + 1,
+                    reason: "no closing `}` on the line".to_string(),
+                });
+            };
+            let expr = &after[..end];
+            let value = eval(expr, bindings, &mut used).map_err(|e| e.at(expr, i + 1))?;
+            match value {
+                Value::Int(v) => ir.push_str(&v.to_string()),
+                Value::Str(s) => ir.push_str(s),
+            }
+            rest = &after[end + 1..];
+        }
+        ir.push_str(rest);
+    }
+    Ok(Instantiated { ir, used })
+}
+
+/// The `;; key: value` lines a template starts with, every key is optional:
+///
+///  ;; entry: ll_matmul_cpu_jit            function the JIT looks up
+///  ;; abi: (ptr, ptr, ptr) -> void        signature of `entry`, must be the kernel's
+///  ;; elem: float double                  `{ELEM_TY}`s the template is written for
+///  ;; align: 32                           alignment (bytes) the operands need
+///  ;; require: M * ELEM_BYTES % 32 == 0   shape constraint, one per line
+///  ;; fallback: default                   `default` template or `error` (the default)
+///                                         when a requirement isn't met
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TemplateHeader {
+    pub(crate) entry: Option<String>,
+    pub(crate) abi: Option<String>,
+    pub(crate) elems: Vec<String>,
+    pub(crate) align: Option<usize>,
+    // (line, constraint)
+    requires: Vec<(usize, String)>,
+    pub(crate) fallback: bool,
+}
+
+impl TemplateHeader {
+    /// Whether the template declares no `elem:` or lists `ty`.
+    pub(crate) fn supports_elem(&self, ty: &str) -> bool {
+        self.elems.is_empty() || self.elems.iter().any(|elem| elem == ty)
+    }
+
+    /// Whether `abi` is the declared one, or none is declared.
+    pub(crate) fn supports_abi(&self, abi: &str) -> bool {
+        let normalized = |abi: &str| abi.split_whitespace().collect::<String>();
+        self.abi
+            .as_deref()
+            .is_none_or(|declared| normalized(declared) == normalized(abi))
+    }
+
+    /// The first `require:` the instantiation with `bindings` doesn't meet.
+    pub(crate) fn unmet(&self, bindings: &Bindings) -> Result<Option<&str>, TemplateError> {
+        for (line, requirement) in &self.requires {
+            let met =
+                check_requirement(requirement, bindings).map_err(|e| e.at(requirement, *line))?;
+            if !met {
+                return Ok(Some(requirement));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Reads the header of `template`, the `;;` lines before anything else.
+pub(crate) fn parse_header(template: &str) -> Result<TemplateHeader, TemplateError> {
+    let mut header = TemplateHeader::default();
+    for (i, text) in template.lines().enumerate() {
+        let Some(field) = text.strip_prefix(";;") else {
+            break;
+        };
+        let line = i + 1;
+        let bad = |reason: String| TemplateError::BadHeader { line, reason };
+        let (key, value) = field
+            .split_once(':')
+            .ok_or_else(|| bad("expected `;; key: value`".to_string()))?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(bad(format!("`{}` has no value", key.trim())));
+        }
+        match key.trim() {
+            "entry" => header.entry = Some(value.to_string()),
+            "abi" => header.abi = Some(value.to_string()),
+            "elem" => header.elems = value.split_whitespace().map(str::to_string).collect(),
+            "align" => {
+                let align = value
+                    .parse()
+                    .ok()
+                    .filter(|align: &usize| align.is_power_of_two());
+                header.align =
+                    Some(align.ok_or_else(|| bad(format!("`{}` isn't a power of two", value)))?);
+            }
+            "require" => header.requires.push((line, value.to_string())),
+            "fallback" => {
+                header.fallback = match value {
+                    "default" => true,
+                    "error" => false,
+                    _ => return Err(bad(format!("`{}` isn't `default` or `error`", value))),
+                }
+            }
+            key => return Err(bad(format!("unknown key `{}`", key))),
+        }
+    }
+    Ok(header)
+}
+
+// `lhs op rhs`, op one of == != <= >= < >
+fn check_requirement(requirement: &str, bindings: &Bindings) -> Result<bool, EvalError> {
+    let at = requirement
+        .find(['=', '!', '<', '>'])
+        .ok_or_else(|| EvalError::Bad("no comparison".to_string()))?;
+    let op = if requirement[at + 1..].starts_with('=') {
+        &requirement[at..at + 2]
+    } else {
+        &requirement[at..at + 1]
+    };
+    let side = |expr: &str| match eval(expr.trim(), bindings, &mut BTreeSet::new())? {
+        Value::Int(value) => Ok(value),
+        Value::Str(_) => Err(EvalError::Bad(format!("`{}` isn't a number", expr.trim()))),
+    };
+    let lhs = side(&requirement[..at])?;
+    let rhs = side(&requirement[at + op.len()..])?;
+    match op {
+        "==" => Ok(lhs == rhs),
+        "!=" => Ok(lhs != rhs),
+        "<=" => Ok(lhs <= rhs),
+        ">=" => Ok(lhs >= rhs),
+        "<" => Ok(lhs < rhs),
+        ">" => Ok(lhs > rhs),
+        _ => Err(EvalError::Bad(format!("`{}` isn't a comparison", op))),
+    }
+}
+
+fn find_placeholder(s: &str) -> Option<usize> {
+    s.match_indices('{').map(|(i, _)| i).find(|&i| {
+        s[i + 1..].starts_with(|c: char| {
+            c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_' || c == '('
+        })
+    })
+}
+
+enum EvalError {
+    Unknown(String),
+    Bad(String),
+}
+
+impl EvalError {
+    fn at(self, expr: &str, line: usize) -> TemplateError {
+        match self {
+            EvalError::Unknown(name) => TemplateError::UnknownPlaceholder { name, line },
+            EvalError::Bad(reason) => TemplateError::BadExpression {
+                expr: expr.to_string(),
+                line,
+                reason,
+            },
+        }
+    }
+}
+
+// `{NAME}` is any placeholder, in an expression only the integer ones are allowed
+fn eval(
+    expr: &str,
+    bindings: &Bindings,
+    used: &mut BTreeSet<&'static str>,
+) -> Result<Value, EvalError> {
+    let mut parser = Parser {
+        src: expr.as_bytes(),
+        pos: 0,
+        bindings,
+        used,
+    };
+    if is_ident(expr) {
+        return parser.lookup(expr);
+    }
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    match parser.src.get(parser.pos) {
+        None => Ok(Value::Int(value)),
+        Some(&c) => Err(EvalError::Bad(format!("unexpected `{}`", c as char))),
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_uppercase() || c == '_')
+        && s.chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+// sum := product (('+' | '-') product)*
+// product := atom (('*' | '/' | '%') atom)*
+// atom := number | NAME | '(' sum ')'
+struct Parser<'a, 'u> {
+    src: &'a [u8],
+    pos: usize,
+    bindings: &'a Bindings,
+    used: &'u mut BTreeSet<&'static str>,
+}
+
+impl Parser<'_, '_> {
+    fn lookup(&mut self, name: &str) -> Result<Value, EvalError> {
+        let (name, value, dims) = self
+            .bindings
+            .lookup(name)
+            .ok_or_else(|| EvalError::Unknown(name.to_string()))?;
+        self.used.insert(name);
+        self.used.extend(dims);
+        Ok(value)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.src.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn next_op(&mut self, ops: &[u8]) -> Option<u8> {
+        self.skip_spaces();
+        let op = *self.src.get(self.pos)?;
+        ops.contains(&op).then(|| {
+            self.pos += 1;
+            op
+        })
+    }
+
+    fn sum(&mut self) -> Result<usize, EvalError> {
+        let mut value = self.product()?;
+        while let Some(op) = self.next_op(b"+-") {
+            let rhs = self.product()?;
+            value = match op {
+                b'+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            }
+            .ok_or_else(|| EvalError::Bad("overflows or goes below 0".to_string()))?;
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<usize, EvalError> {
+        let mut value = self.atom()?;
+        while let Some(op) = self.next_op(b"*/%") {
+            let rhs = self.atom()?;
+            value = match op {
+                b'*' => value
+                    .checked_mul(rhs)
+                    .ok_or_else(|| EvalError::Bad("overflows".to_string()))?,
+                _ if rhs == 0 => return Err(EvalError::Bad("division by zero".to_string())),
+                b'/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn atom(&mut self) -> Result<usize, EvalError> {
+        self.skip_spaces();
+        let start = self.pos;
+        match self.src.get(self.pos) {
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                self.skip_spaces();
+                if self.src.get(self.pos) != Some(&b')') {
+                    return Err(EvalError::Bad("missing `)`".to_string()));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while self.src.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                let digits = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                digits
+                    .parse()
+                    .map_err(|_| EvalError::Bad(format!("`{}` is too large", digits)))
+            }
+            Some(c) if c.is_ascii_uppercase() || *c == b'_' => {
+                while self
+                    .src
+                    .get(self.pos)
+                    .is_some_and(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == b'_')
+                {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                match self.lookup(name)? {
+                    Value::Int(value) => Ok(value),
+                    Value::Str(_) => Err(EvalError::Bad(format!("`{}` isn't a number", name))),
+                }
+            }
+            Some(&c) => Err(EvalError::Bad(format!("unexpected `{}`", c as char))),
+            None => Err(EvalError::Bad("missing operand".to_string())),
+        }
+    }
+}
+
+/// Largest power of two up to `MAX_TILE` dividing `dim`,
+/// so the tiles cover the matrix without a remainder loop.
+pub(crate) fn tile_size(dim: usize) -> usize {
+    const MAX_TILE: usize = 8;
+    let mut tile = MAX_TILE;
+    while !dim.is_multiple_of(tile) {
+        tile /= 2;
+    }
+    tile
+}
+
+/// FNV-1a, unlike `DefaultHasher` it's stable across Rust releases and processes.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const F32: IrType = IrType {
+        ty: "float",
+        suffix: "f32",
+        bytes: 4,
+    };
+
+    fn run(template: &str) -> Result<Instantiated, TemplateError> {
+        instantiate(template, &Bindings::plain((4, 6, 16), F32))
+    }
+
+    #[test]
+    fn test_instantiate_expressions() {
+        let ir = run("{M} {N} {K} {M*K} {K/8} {K%3} {(M+2)*N} { M - 1 } {VEC_A_SIZE/ELEM_BYTES}")
+            .unwrap()
+            .ir;
+        assert_eq!(ir, "4 6 16 64 2 1 36 { M - 1 } 16");
+        assert_eq!(run("{ M - 1 }").unwrap().ir, "{ M - 1 }");
+        assert_eq!(run("{M - 1}").unwrap().ir, "3");
+    }
+
+    #[test]
+    fn test_instantiate_leaves_llvm_braces() {
+        let template = "%r = type { {ELEM_TY}, i1 }\n%s = type {i32, i1}\n\
+                        define void @f() {\n  ret void\n}\n!0 = !{!1}\n";
+        let ir = run(template).unwrap().ir;
+        assert_eq!(
+            ir,
+            "%r = type { float, i1 }\n%s = type {i32, i1}\n\
+             define void @f() {\n  ret void\n}\n!0 = !{!1}\n"
+        );
+    }
+
+    #[test]
+    fn test_instantiate_unknown_placeholder() {
+        assert_eq!(
+            run("{M}\n  %x = {VEC_A_SZE}\n").unwrap_err(),
+            TemplateError::UnknownPlaceholder {
+                name: "VEC_A_SZE".to_string(),
+                line: 2
+            }
+        );
+        assert_eq!(
+            run("{M*VEC_A_SZE}").unwrap_err(),
+            TemplateError::UnknownPlaceholder {
+                name: "VEC_A_SZE".to_string(),
+                line: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_instantiate_bad_expressions() {
+        for (template, reason) in [
+            ("{M*}", "missing operand"),
+            ("{K/0}", "division by zero"),
+            ("{M-K}", "overflows or goes below 0"),
+            ("{ELEM_TY*2}", "`ELEM_TY` isn't a number"),
+            ("{(M+1}", "missing `)`"),
+            ("{M K}", "unexpected `K`"),
+            ("{M\n}", "no closing `}` on the line"),
+        ] {
+            match run(template) {
+                Err(TemplateError::BadExpression {
+                    line: 1, reason: r, ..
+                }) => {
+                    assert_eq!(r, reason, "{}", template)
+                }
+                other => panic!("{}: {:?}", template, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let template = ";; entry: my_kernel\n;; abi: (ptr, ptr,ptr) -> void\n;; elem: float double\n\
+                        ;; align: 32\n;; require: M % 4 == 0\n;; require: K >= 8\n;; fallback: default\n\
+                        ;; require: N < 2\ndefine void @my_kernel() {\n";
+        let header = parse_header(template).unwrap();
+        assert_eq!(header.entry.as_deref(), Some("my_kernel"));
+        assert!(header.supports_abi(MATMUL_ABI));
+        assert!(!header.supports_abi("(ptr, ptr, ptr) -> i32"));
+        assert!(header.supports_elem("double") && !header.supports_elem("half"));
+        assert_eq!(header.align, Some(32));
+        assert!(header.fallback);
+        let bindings = Bindings::plain((4, 6, 16), F32);
+        assert_eq!(header.unmet(&bindings).unwrap(), Some("N < 2"));
+        let bindings = Bindings::plain((6, 1, 16), F32);
+        assert_eq!(header.unmet(&bindings).unwrap(), Some("M % 4 == 0"));
+
+        // the header ends at the first other line
+        let header = parse_header("; entry: a\n;; entry: b\n").unwrap();
+        assert_eq!(header, TemplateHeader::default());
+        assert!(header.supports_abi(MATMUL_ABI) && header.supports_elem("half"));
+    }
+
+    #[test]
+    fn test_parse_header_errors() {
+        for (template, line) in [
+            (";; entry a\n", 1),
+            (";; entry:\n", 1),
+            (";; entry: a\n;; align: 24\n", 2),
+            (";; fallback: maybe\n", 1),
+            (";; entry: a\n;; elem: float\n;; aling: 32\n", 3),
+        ] {
+            match parse_header(template) {
+                Err(TemplateError::BadHeader { line: l, .. }) => {
+                    assert_eq!(l, line, "{}", template)
+                }
+                other => panic!("{}: {:?}", template, other),
+            }
+        }
+        let bindings = Bindings::plain((4, 6, 16), F32);
+        let unmet = |requirement: &str| {
+            parse_header(&format!(";; entry: a\n;; require: {}\n", requirement))
+                .unwrap()
+                .unmet(&bindings)
+                .map(|unmet| unmet.map(str::to_string))
+        };
+        assert!(matches!(
+            unmet("M % 8 = 0"),
+            Err(TemplateError::BadExpression { line: 2, .. })
+        ));
+        assert!(matches!(
+            unmet("M % 8"),
+            Err(TemplateError::BadExpression { line: 2, .. })
+        ));
+        assert!(matches!(
+            unmet("ELEM_TY == 4"),
+            Err(TemplateError::BadExpression { line: 2, .. })
+        ));
+        assert!(matches!(
+            unmet("MM == 4"),
+            Err(TemplateError::UnknownPlaceholder { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_instantiate_used_placeholders() {
+        let instantiated = run("{VEC_A_SIZE} {ELEM_TY}").unwrap();
+        // derived placeholders use their dimensions
+        assert!(instantiated.uses("M") && instantiated.uses("K"));
+        assert!(instantiated.uses("ELEM_TY"));
+        assert_eq!(
+            instantiated.unused(&["M", "N", "K", "TRANS_A"]),
+            ["N", "TRANS_A"]
+        );
+        assert!(run("{M*N*K}").unwrap().unused(&["M", "N", "K"]).is_empty());
+    }
+}
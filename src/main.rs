@@ -6,10 +6,100 @@ use llvm_intrinsic_with_rust::llvm::gpu::ll_matmul_gpu_compiled;
 #[cfg(feature = "gpu")]
 use llvm_intrinsic_with_rust::llvm::gpu::ll_matmul_gpu_jit;
 use llvm_intrinsic_with_rust::llvm::ll_matmul_jit_with_template;
+use llvm_intrinsic_with_rust::llvm::{
+    JitOptions, KernelInspection, MatrixLayout, inspect_matmul_jit,
+};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+const INSPECT_USAGE: &str = "usage: inspect <m> <k> <n> [--f64] [--column-major] [--template FILE] \
+[--function NAME] [--passes PIPELINE] [--cpu CPU] [--features FEATURES] [--out DIR]";
+
+// `inspect m k n`: IR and assembly of the (m×k) * (k×n) kernel, on stdout or in --out
+fn inspect(args: &[String]) -> Result<(), String> {
+    let mut dims = Vec::new();
+    let mut f64_kernel = false;
+    let mut layout = MatrixLayout::RowMajor;
+    let mut options = JitOptions::new();
+    let mut out_dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--f64" => f64_kernel = true,
+            "--column-major" => layout = MatrixLayout::ColumnMajor,
+            "--template" => options = options.template_file(value()?),
+            "--function" => options = options.function_name(value()?),
+            "--passes" => options = options.passes(value()?),
+            "--cpu" => options = options.target_cpu(value()?),
+            "--features" => options = options.target_features(value()?),
+            "--out" => out_dir = Some(PathBuf::from(value()?)),
+            dim => dims.push(
+                dim.parse::<usize>()
+                    .map_err(|_| format!("unexpected argument `{}`", dim))?,
+            ),
+        }
+    }
+    let &[m, k, n] = dims.as_slice() else {
+        return Err("expected the 3 dimensions m k n".to_string());
+    };
+
+    let inspection = if f64_kernel {
+        inspect_matmul_jit::<f64>(m, n, k, layout, &options)
+    } else {
+        inspect_matmul_jit::<f32>(m, n, k, layout, &options)
+    }
+    .map_err(|e| e.to_string())?;
+    let KernelInspection {
+        instantiated_ir,
+        lowered_ir,
+        optimized_ir,
+        assembly,
+    } = inspection;
+    let stages = [
+        ("instantiated.ll", instantiated_ir),
+        ("lowered.ll", lowered_ir),
+        ("optimized.ll", optimized_ir),
+        ("kernel.s", assembly),
+    ];
+
+    match out_dir {
+        Some(dir) => {
+            fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            for (file, text) in stages {
+                let path = dir.join(file);
+                fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+                println!("{}", path.display());
+            }
+        }
+        None => {
+            for (file, text) in stages {
+                println!("; ---------------- {} ----------------", file);
+                println!("{}", text);
+            }
+        }
+    }
+    Ok(())
+}
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("inspect") {
+        if let Err(e) = inspect(&args[1..]) {
+            eprintln!("{}\n{}", e, INSPECT_USAGE);
+            process::exit(1);
+        }
+        return;
+    }
+
     // 2x3 * 3x4 = (2x4)
-    let a = [1., 2., 3., 4., 5., 6.];
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
     let a_shape = (2, 3);
     let b = [1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0.];
     let b_shape = (3, 4);
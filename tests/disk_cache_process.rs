@@ -0,0 +1,69 @@
+// a disk cache hit has to link in a process that compiled nothing yet: the test runs the
+// kernel in fresh processes (this binary again, as a child) sharing one cache directory
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::SystemTime;
+
+use llvm_intrinsic_with_rust::common::{
+    JIT_CACHE_DIR_ENV, assert_vec_eq, generate_random_matrix, native_matmul,
+};
+use llvm_intrinsic_with_rust::llvm::{JitOptions, try_ll_matmul_jit_with_options};
+
+const CHILD_ENV: &str = "LL_MATMUL_TEST_DISK_CACHE_CHILD";
+
+#[test]
+fn child_matmul() {
+    if env::var_os(CHILD_ENV).is_none() {
+        return;
+    }
+    let (m, k, n) = (9, 7, 10);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    let result =
+        unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &JitOptions::new()) };
+    assert_vec_eq(&result.unwrap(), &expected, max * 1e-5);
+}
+
+fn cache_files(dir: &Path) -> Vec<(String, u64, SystemTime)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let meta = entry.metadata().unwrap();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, meta.len(), meta.modified().unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_disk_cache_hit_in_a_fresh_process() {
+    let dir = env::temp_dir().join(format!("llmm-disk-process-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let run_child = || {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "child_matmul", "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .env(JIT_CACHE_DIR_ENV, &dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+
+    // the first process compiles the kernel and stores it
+    run_child();
+    let stored = cache_files(&dir);
+    assert!(!stored.is_empty());
+
+    // the second one loads and links it: nothing compiled again, nothing rewritten
+    run_child();
+    assert_eq!(cache_files(&dir), stored);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,43 @@
+// set_jit_options changes every kernel compiled afterwards, in every thread: its tests get a binary
+// of their own and run as one test, so no other test compiles a kernel under their default
+use llvm_intrinsic_with_rust::common::{
+    UNROLLED_IR_TEMPLATE_JIT_CPU, assert_vec_eq, generate_random_matrix, native_matmul,
+};
+use llvm_intrinsic_with_rust::llvm::{
+    JitOptions, MatmulError, set_jit_options, try_ll_matmul_jit_with_options,
+};
+
+#[test]
+fn test_set_jit_options_layers() {
+    let (m, k, n) = (9, 7, 10);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    let matmul = |options: &JitOptions| unsafe {
+        try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), options)
+    };
+
+    // the process default applies to the calls that leave it unset
+    set_jit_options(
+        JitOptions::new()
+            .template(UNROLLED_IR_TEMPLATE_JIT_CPU)
+            .function_name("not_in_the_template"),
+    );
+    assert!(matches!(
+        matmul(&JitOptions::new()),
+        Err(MatmulError::SymbolNotFound { .. })
+    ));
+    // the call's options win over it
+    let call = JitOptions::new().function_name("ll_matmul_cpu_jit");
+    assert_vec_eq(&matmul(&call).unwrap(), &expected, max * 1e-5);
+
+    // and a new default is picked up by the next call
+    #[cfg(target_arch = "x86_64")]
+    {
+        set_jit_options(JitOptions::new().target_cpu("x86-64"));
+        assert_vec_eq(&matmul(&JitOptions::new()).unwrap(), &expected, max * 1e-5);
+    }
+    set_jit_options(JitOptions::new());
+    assert_vec_eq(&matmul(&JitOptions::new()).unwrap(), &expected, max * 1e-5);
+}
@@ -1,7 +1,34 @@
+use half::{bf16, f16};
+use llvm_intrinsic_with_rust::common::UNROLLED_IR_TEMPLATE_JIT_CPU;
 use llvm_intrinsic_with_rust::common::assert_vec_eq;
+use llvm_intrinsic_with_rust::common::{
+    HalfFormat, IntScalar, Overflow, Scalar, UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU,
+    UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU, UNROLLED_IR_TEMPLATE_HALF_JIT_CPU, generate_random_matrix,
+    native_matmul, native_matmul_int,
+};
+use llvm_intrinsic_with_rust::common::{NAIVE_TEMPLATE_MAX_DIM, TILED_IR_TEMPLATE_JIT_CPU};
 use llvm_intrinsic_with_rust::llvm::ll_matmul_4x4;
+use llvm_intrinsic_with_rust::llvm::ll_matmul_4x4_f64;
 use llvm_intrinsic_with_rust::llvm::ll_matmul_4x4_unrolled;
 use llvm_intrinsic_with_rust::llvm::ll_matmul_jit_with_template;
+use llvm_intrinsic_with_rust::llvm::{
+    BatchLayout, ll_matmul_jit_batched, try_ll_matmul_jit_batched,
+};
+use llvm_intrinsic_with_rust::llvm::{
+    JitOptions, OptimizationLevel, Specialization, compile_matmul_jit_dynamic, jit_cache,
+    ll_matmul_jit_into_with,
+};
+use llvm_intrinsic_with_rust::llvm::{MatmulError, try_ll_matmul_jit_with_template};
+use llvm_intrinsic_with_rust::llvm::{
+    MatrixLayout, col_major_to_row_major, compile_matmul_jit_with_layout, row_major_to_col_major,
+};
+use llvm_intrinsic_with_rust::llvm::{Trans, ll_gemm_jit, try_ll_gemm_jit};
+use llvm_intrinsic_with_rust::llvm::{
+    inspect_matmul_jit, try_compile_matmul_jit_with_options, try_ll_matmul_jit_with_options,
+};
+use llvm_intrinsic_with_rust::llvm::{ll_matmul_jit_half, try_ll_matmul_jit_half};
+use llvm_intrinsic_with_rust::llvm::{ll_matmul_jit_int, try_ll_matmul_jit_int};
+use llvm_intrinsic_with_rust::llvm::{ll_matmul_jit_into, try_ll_matmul_jit_into};
 use ndarray::Array2;
 
 fn test_4x4_vs_ndarray(matmul_fn: unsafe extern "C" fn(*const f32, *const f32, *mut f32)) {
@@ -363,9 +390,9 @@ fn test_ll_matmul_jit_with_template_matrix_not_commutative() {
 }
 
 #[test]
-#[should_panic(expected = "shapes dosn't match")]
+#[should_panic(expected = "shapes doesn't match")]
 fn test_ll_matmul_jit_with_template_invalid_dimension_mismatch() {
-    let a = [1., 2., 3., 4., 5., 6.];
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
     let b = [1., 2., 3., 4., 5., 6.];
     let _result = unsafe { ll_matmul_jit_with_template(&a, (2, 3), &b, (2, 3), None) };
 }
@@ -377,3 +404,1328 @@ fn test_ll_matmul_jit_with_template_empty_array() {
     let b: [f32; 0] = [];
     let _result = unsafe { ll_matmul_jit_with_template(&a, (0, 0), &b, (0, 0), None) };
 }
+
+// Tests for try_ll_matmul_jit_with_template
+#[test]
+fn test_try_ll_matmul_jit_with_template_matches_panicking_version() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let b = [7., 8., 9., 10., 11., 12., 13., 14., 15., 16.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (3, 2), &b, (2, 5), None) }
+        .expect("valid shapes must not fail");
+    let expected = unsafe { ll_matmul_jit_with_template(&a, (3, 2), &b, (2, 5), None) };
+    assert_vec_eq(&result, &expected, 1e-4);
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_shape_mismatch() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let b = [1., 2., 3., 4., 5., 6.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 3), &b, (2, 3), None) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &b, (2, 3), None) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+
+    let empty: [f32; 0] = [];
+    let result = unsafe { try_ll_matmul_jit_with_template(&empty, (0, 0), &empty, (0, 0), None) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_missing_placeholder() {
+    let template =
+        "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\n  ret void\n}";
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(template)) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TemplateMissingPlaceholder)
+    ));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_ir_parse_error() {
+    let template = "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\nentry:\n  %x = bogus i32 {M*N*K}\n  ret void\n}";
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(template)) };
+    match result {
+        Err(MatmulError::IrParse { line, .. }) => assert_eq!(line, Some(3)),
+        other => panic!("expected an IR parse error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_symbol_not_found() {
+    let template = "define void @not_the_entry_point_{M}_{N}_{K}(float* %a, float* %b, float* %result) {\nentry:\n  ret void\n}";
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(template)) };
+    assert!(matches!(result, Err(MatmulError::SymbolNotFound { .. })));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_placeholder_errors() {
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let typo = "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\nentry:\n  ; {M} {N} {K}\n  ; {VEC_A_SZE}\n  ret void\n}";
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(typo)) };
+    match result {
+        Err(MatmulError::TemplateUnknownPlaceholder { name, line }) => {
+            assert_eq!((name.as_str(), line), ("VEC_A_SZE", 4))
+        }
+        other => panic!("expected an unknown placeholder, got {:?}", other),
+    }
+
+    let no_k = "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\nentry:\n  ; {M} {N}\n  ret void\n}";
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(no_k)) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TemplateUnusedPlaceholders { names }) if names == ["K"]
+    ));
+
+    let bad = "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\nentry:\n  ; {M} {N} {K/(M-2)}\n  ret void\n}";
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(bad)) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TemplateExpression { line: 3, .. })
+    ));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_broken_modules() {
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let run = |template: &str| unsafe {
+        try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(template))
+    };
+
+    // parses, but %x is used before it's defined
+    let result = run(
+        "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\n\
+         entry:\n  ; {M} {N} {K}\n  %y = add i32 %x, 1\n  %x = add i32 %y, 1\n  ret void\n}",
+    );
+    assert!(matches!(
+        result,
+        Err(MatmulError::IrVerify {
+            stage: "instantiated",
+            ..
+        })
+    ));
+
+    // a signature the kernel doesn't have would be called as `fn(*const f32, *const f32, *mut f32)`
+    let result = run("define void @ll_matmul_cpu_jit(float* %a, float* %b) {\n\
+         entry:\n  ; {M} {N} {K}\n  ret void\n}");
+    match result {
+        Err(MatmulError::SignatureMismatch {
+            name,
+            expected,
+            found,
+        }) => {
+            assert_eq!(name, "ll_matmul_cpu_jit");
+            assert_eq!(expected, "void (ptr, ptr, ptr)");
+            assert_eq!(found, "void (ptr, ptr)");
+        }
+        other => panic!("expected a signature mismatch, got {:?}", other),
+    }
+    let result = run(
+        "define i32 @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\n\
+         entry:\n  ; {M} {N} {K}\n  ret i32 0\n}",
+    );
+    assert!(matches!(result, Err(MatmulError::SignatureMismatch { .. })));
+    let result = run(
+        "define void @ll_matmul_cpu_jit(float* %a, float* %b, i64 %result) {\n\
+         entry:\n  ; {M} {N} {K}\n  ret void\n}",
+    );
+    assert!(matches!(result, Err(MatmulError::SignatureMismatch { .. })));
+
+    // declared, never defined
+    let result = run("; {M} {N} {K}\ndeclare void @ll_matmul_cpu_jit(float*, float*, float*)\n");
+    assert!(matches!(result, Err(MatmulError::SymbolNotFound { .. })));
+
+    // the int kernel has to return its overflow flag
+    let ints: [i32; 4] = [1, 2, 3, 4];
+    let result = unsafe {
+        try_ll_matmul_jit_int(
+            Overflow::Wrapping,
+            &ints,
+            (2, 2),
+            &ints,
+            (2, 2),
+            Some(
+                "define void @ll_matmul_int_cpu_jit(i32* %a, i32* %b, i64* %result) {\n\
+                 entry:\n  ; {M} {N} {K}\n  ret void\n}",
+            ),
+        )
+    };
+    match result {
+        Err(MatmulError::SignatureMismatch {
+            expected, found, ..
+        }) => {
+            assert_eq!(expected, "i32 (ptr, ptr, ptr)");
+            assert_eq!(found, "void (ptr, ptr, ptr)");
+        }
+        other => panic!("expected a signature mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_jit_cache_warm_and_remove() {
+    // a shape no other test uses, the cache is shared by the whole test binary
+    let shape = (13, 11, 7);
+    let cache = jit_cache();
+    cache
+        .warm::<f64>(&[shape], &JitOptions::new())
+        .expect("Failed to warm the cache");
+    let warmed = cache
+        .stats()
+        .kernels
+        .into_iter()
+        .find(|kernel| kernel.shape == shape)
+        .expect("warmed kernel not in the stats");
+    assert!(warmed.kernel.contains("f64"));
+
+    let (m, n, k) = shape;
+    let a: Vec<f64> = generate_random_matrix(m, k, 1);
+    let b: Vec<f64> = generate_random_matrix(k, n, 2);
+    let result =
+        unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &JitOptions::new()) };
+    assert_vec_eq(
+        &result.unwrap(),
+        &native_matmul(&a, (m, k), &b, (k, n)),
+        1e-10,
+    );
+
+    assert!(cache.remove(shape) >= 1);
+    assert!(
+        cache
+            .stats()
+            .kernels
+            .iter()
+            .all(|kernel| kernel.shape != shape)
+    );
+}
+
+// Tests for ll_matmul_jit_into
+#[test]
+fn test_ll_matmul_jit_into_matches_allocating_version() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let b = [7., 8., 9., 10., 11., 12., 13., 14., 15., 16.];
+    let expected = unsafe { ll_matmul_jit_with_template(&a, (3, 2), &b, (2, 5), None) };
+
+    let mut out = [f32::NAN; 15];
+    unsafe { ll_matmul_jit_into(&a, (3, 2), &b, (2, 5), &mut out) };
+    assert_vec_eq(&out, &expected, 1e-4);
+}
+
+#[test]
+fn test_ll_matmul_jit_into_across_shapes() {
+    // a bigger shape, then a smaller one: each call gets the kernel of its own shape
+    // and writes the whole of `out` in place
+    let a: [f32; 16] = [
+        1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12., 13., 14., 15., 16.,
+    ];
+    let mut out = [0.0f32; 16];
+    unsafe { ll_matmul_jit_into(&a, (4, 4), &a, (4, 4), &mut out) };
+    let expected = unsafe { ll_matmul_jit_with_template(&a, (4, 4), &a, (4, 4), None) };
+    assert_vec_eq(&out, &expected, 1e-4);
+
+    let small_a: [f32; 4] = [1., 2., 3., 4.];
+    let small_b = [5., 6., 7., 8.];
+    let mut small_out = [0.0f32; 4];
+    unsafe { ll_matmul_jit_into(&small_a, (2, 2), &small_b, (2, 2), &mut small_out) };
+    assert_vec_eq(&small_out, &[19., 22., 43., 50.], 1e-4);
+}
+
+#[test]
+fn test_try_ll_matmul_jit_into_wrong_output_length() {
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let mut out = [0.0f32; 3];
+    let result = unsafe { try_ll_matmul_jit_into(&a, (2, 2), &a, (2, 2), &mut out) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+// Tests for compile_matmul_jit_with_layout
+#[test]
+fn test_compile_matmul_jit_with_layout_row_major_vs_column_major() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let b = [7., 8., 9., 10., 11., 12., 13., 14., 15., 16.];
+    let (m, k, n) = (3, 2, 5);
+
+    let row_major =
+        unsafe { compile_matmul_jit_with_layout(m, n, k, None, MatrixLayout::RowMajor) }
+            .expect("Failed to compile row-major kernel");
+    let mut result_row_major = [0.0f32; 15];
+    unsafe { row_major.call(a.as_ptr(), b.as_ptr(), result_row_major.as_mut_ptr()) };
+
+    let col_major =
+        unsafe { compile_matmul_jit_with_layout(m, n, k, None, MatrixLayout::ColumnMajor) }
+            .expect("Failed to compile column-major kernel");
+    let a_col_major = row_major_to_col_major(&a, m, k);
+    let b_col_major = row_major_to_col_major(&b, k, n);
+    let mut result_col_major = [0.0f32; 15];
+    unsafe {
+        col_major.call(
+            a_col_major.as_ptr(),
+            b_col_major.as_ptr(),
+            result_col_major.as_mut_ptr(),
+        )
+    };
+
+    let a_ndarray = Array2::from_shape_vec((m, k), a.to_vec()).unwrap();
+    let b_ndarray = Array2::from_shape_vec((k, n), b.to_vec()).unwrap();
+    let expected = a_ndarray.dot(&b_ndarray);
+    let expected = expected.as_slice().unwrap();
+
+    assert_vec_eq(&result_row_major, expected, 1e-4);
+    assert_vec_eq(
+        &col_major_to_row_major(&result_col_major, m, n),
+        expected,
+        1e-4,
+    );
+}
+
+// Tests for ll_gemm_jit
+// alpha * A * B + beta * C, with the same shortcut as the kernels for beta == 0
+fn gemm_reference(
+    alpha: f32,
+    a: &[f32],
+    a_shape: (usize, usize),
+    b: &[f32],
+    b_shape: (usize, usize),
+    beta: f32,
+    c: &[f32],
+) -> Vec<f32> {
+    native_matmul(a, a_shape, b, b_shape)
+        .iter()
+        .zip(c)
+        .map(|(ab, c)| {
+            if beta == 0.0 {
+                alpha * ab
+            } else {
+                alpha * ab + beta * c
+            }
+        })
+        .collect()
+}
+
+fn test_gemm_vs_native(ir_template: Option<&str>, alpha: f32, beta: f32) {
+    // m = 9, n = 10: the unrolled kernel runs both its 8-wide and its scalar tail
+    let (m, k, n) = (9, 7, 10);
+    let a = generate_random_matrix(m, k, 1);
+    let b = generate_random_matrix(k, n, 2);
+    let mut c = generate_random_matrix(m, n, 3);
+    let expected = gemm_reference(alpha, &a, (m, k), &b, (k, n), beta, &c);
+
+    unsafe {
+        ll_gemm_jit(
+            Trans::No,
+            Trans::No,
+            alpha,
+            &a,
+            (m, k),
+            &b,
+            (k, n),
+            beta,
+            &mut c,
+            ir_template,
+        )
+    };
+    // values are in [1, 255), compare relative to the magnitude of the result
+    let epsilon = expected.iter().fold(0.0f32, |acc, e| acc.max(e.abs())) * 1e-5;
+    assert_vec_eq(&c, &expected, epsilon);
+}
+
+#[test]
+fn test_ll_gemm_jit_naive_vs_native() {
+    test_gemm_vs_native(None, 1.0, 0.0);
+    test_gemm_vs_native(None, 2.0, -0.5);
+    test_gemm_vs_native(None, -1.5, 1.0);
+}
+
+#[test]
+fn test_ll_gemm_jit_unrolled_vs_native() {
+    let template = Some(UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU);
+    test_gemm_vs_native(template, 1.0, 0.0);
+    test_gemm_vs_native(template, 2.0, -0.5);
+    test_gemm_vs_native(template, -1.5, 1.0);
+}
+
+#[test]
+fn test_ll_gemm_jit_beta_zero_ignores_c() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let b = [7., 8., 9., 10., 11., 12., 13., 14., 15., 16.];
+    let expected = unsafe { ll_matmul_jit_with_template(&a, (3, 2), &b, (2, 5), None) };
+
+    for template in [None, Some(UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU)] {
+        let mut c = [f32::NAN; 15];
+        unsafe {
+            ll_gemm_jit(
+                Trans::No,
+                Trans::No,
+                1.0,
+                &a,
+                (3, 2),
+                &b,
+                (2, 5),
+                0.0,
+                &mut c,
+                template,
+            )
+        };
+        assert_vec_eq(&c, &expected, 1e-4);
+    }
+}
+
+#[test]
+fn test_ll_gemm_jit_accumulates_into_c() {
+    let a: [f32; 4] = [1., 2., 3., 4.];
+    let b = [5., 6., 7., 8.];
+    let mut c = [0.0f32; 4];
+    // C += A * B, twice
+    unsafe {
+        ll_gemm_jit(
+            Trans::No,
+            Trans::No,
+            1.0,
+            &a,
+            (2, 2),
+            &b,
+            (2, 2),
+            1.0,
+            &mut c,
+            None,
+        )
+    };
+    unsafe {
+        ll_gemm_jit(
+            Trans::No,
+            Trans::No,
+            1.0,
+            &a,
+            (2, 2),
+            &b,
+            (2, 2),
+            1.0,
+            &mut c,
+            None,
+        )
+    };
+    assert_vec_eq(&c, &[38., 44., 86., 100.], 1e-4);
+}
+
+#[test]
+fn test_try_ll_gemm_jit_shape_mismatch() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let mut c = [0.0f32; 4];
+    let result = unsafe {
+        try_ll_gemm_jit(
+            Trans::No,
+            Trans::No,
+            1.0,
+            &a,
+            (2, 3),
+            &a,
+            (2, 3),
+            0.0,
+            &mut c,
+            None,
+        )
+    };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+
+    let mut c = [0.0f32; 3];
+    let result = unsafe {
+        try_ll_gemm_jit(
+            Trans::No,
+            Trans::No,
+            1.0,
+            &a,
+            (2, 3),
+            &a,
+            (3, 2),
+            0.0,
+            &mut c,
+            None,
+        )
+    };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+fn test_gemm_trans_vs_native(trans_a: Trans, trans_b: Trans) {
+    let (m, k, n) = (3, 4, 5);
+    let a = generate_random_matrix(m, k, 1);
+    let b = generate_random_matrix(k, n, 2);
+    let mut c = generate_random_matrix(m, n, 3);
+    let expected = gemm_reference(2.0, &a, (m, k), &b, (k, n), 0.5, &c);
+
+    // store the operands transposed, the kernel has to undo it
+    let (a_stored, a_shape) = match trans_a {
+        Trans::No => (a, (m, k)),
+        Trans::Yes => (row_major_to_col_major(&a, m, k), (k, m)),
+    };
+    let (b_stored, b_shape) = match trans_b {
+        Trans::No => (b, (k, n)),
+        Trans::Yes => (row_major_to_col_major(&b, k, n), (n, k)),
+    };
+    unsafe {
+        ll_gemm_jit(
+            trans_a, trans_b, 2.0, &a_stored, a_shape, &b_stored, b_shape, 0.5, &mut c, None,
+        )
+    };
+    let epsilon = expected.iter().fold(0.0f32, |acc, e| acc.max(e.abs())) * 1e-5;
+    assert_vec_eq(&c, &expected, epsilon);
+}
+
+#[test]
+fn test_ll_gemm_jit_no_trans() {
+    test_gemm_trans_vs_native(Trans::No, Trans::No);
+}
+
+#[test]
+fn test_ll_gemm_jit_trans_a() {
+    test_gemm_trans_vs_native(Trans::Yes, Trans::No);
+}
+
+#[test]
+fn test_ll_gemm_jit_trans_b() {
+    test_gemm_trans_vs_native(Trans::No, Trans::Yes);
+}
+
+#[test]
+fn test_ll_gemm_jit_trans_a_trans_b() {
+    test_gemm_trans_vs_native(Trans::Yes, Trans::Yes);
+}
+
+#[test]
+fn test_ll_gemm_jit_gram_matrix() {
+    // A^T * A of a 3x2 matrix, from the same buffer
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let mut c = [0.0f32; 4];
+    unsafe {
+        ll_gemm_jit(
+            Trans::Yes,
+            Trans::No,
+            1.0,
+            &a,
+            (3, 2),
+            &a,
+            (3, 2),
+            0.0,
+            &mut c,
+            None,
+        )
+    };
+    assert_vec_eq(&c, &[35., 44., 44., 56.], 1e-4);
+}
+
+#[test]
+fn test_try_ll_gemm_jit_trans_shape_mismatch() {
+    // op(A) is 3x2, op(B) is 3x2: inner dimensions disagree only once A is transposed
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let mut c = [0.0f32; 6];
+    let result = unsafe {
+        try_ll_gemm_jit(
+            Trans::Yes,
+            Trans::No,
+            1.0,
+            &a,
+            (2, 3),
+            &a,
+            (3, 2),
+            0.0,
+            &mut c,
+            None,
+        )
+    };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+#[test]
+fn test_try_ll_gemm_jit_unrolled_trans_unsupported() {
+    let a: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let mut c = [0.0f32; 4];
+    let result = unsafe {
+        try_ll_gemm_jit(
+            Trans::Yes,
+            Trans::No,
+            1.0,
+            &a,
+            (3, 2),
+            &a,
+            (3, 2),
+            0.0,
+            &mut c,
+            Some(UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU),
+        )
+    };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TransposeUnsupported { .. })
+    ));
+}
+
+// Tests for f64
+#[test]
+fn test_ll_matmul_4x4_f64_vs_ndarray() {
+    let a: [f64; 16] = [
+        1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12., 13., 14., 15., 16.,
+    ];
+    let b: [f64; 16] = [
+        16., 15., 14., 13., 12., 11., 10., 9., 8., 7., 6., 5., 4., 3., 2., 1.,
+    ];
+    let mut result = [0.0f64; 16];
+
+    unsafe { ll_matmul_4x4_f64(a.as_ptr(), b.as_ptr(), result.as_mut_ptr()) };
+
+    let a_ndarray = Array2::from_shape_vec((4, 4), a.to_vec()).unwrap();
+    let b_ndarray = Array2::from_shape_vec((4, 4), b.to_vec()).unwrap();
+    let expected = a_ndarray.dot(&b_ndarray);
+    let expected = expected.as_slice().unwrap();
+
+    assert_vec_eq(&result, expected, 1e-12);
+}
+
+fn test_f64_vs_native(ir_template: Option<&str>) {
+    let (m, k, n) = (9, 7, 10);
+    let a: Vec<f64> = generate_random_matrix(m, k, 1);
+    let b: Vec<f64> = generate_random_matrix(k, n, 2);
+    let result = unsafe { ll_matmul_jit_with_template(&a, (m, k), &b, (k, n), ir_template) };
+    assert_vec_eq(&result, &native_matmul(&a, (m, k), &b, (k, n)), 1e-6);
+}
+
+#[test]
+fn test_ll_matmul_jit_with_template_f64_naive() {
+    test_f64_vs_native(None);
+}
+
+#[test]
+fn test_ll_matmul_jit_with_template_f64_unrolled() {
+    test_f64_vs_native(Some(UNROLLED_IR_TEMPLATE_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_f32_and_f64_same_shape() {
+    // same shape and template, the cache must hand out one kernel per element type
+    let a32: [f32; 6] = [1., 2., 3., 4., 5., 6.];
+    let a64: [f64; 6] = [1., 2., 3., 4., 5., 6.];
+    let r32 = unsafe { ll_matmul_jit_with_template(&a32, (2, 3), &a32, (3, 2), None) };
+    let r64 = unsafe { ll_matmul_jit_with_template(&a64, (2, 3), &a64, (3, 2), None) };
+    assert_vec_eq(&r32, &[22., 28., 49., 64.], 1e-4);
+    assert_vec_eq(&r64, &[22., 28., 49., 64.], 1e-12);
+}
+
+#[test]
+fn test_ll_matmul_jit_into_f64() {
+    let a: [f64; 4] = [1., 2., 3., 4.];
+    let b: [f64; 4] = [5., 6., 7., 8.];
+    let mut out = [f64::NAN; 4];
+    unsafe { ll_matmul_jit_into(&a, (2, 2), &b, (2, 2), &mut out) };
+    assert_vec_eq(&out, &[19., 22., 43., 50.], 1e-12);
+}
+
+#[test]
+fn test_ll_gemm_jit_f64() {
+    let (m, k, n) = (3, 4, 5);
+    let a: Vec<f64> = generate_random_matrix(m, k, 1);
+    let b: Vec<f64> = generate_random_matrix(k, n, 2);
+    let mut c: Vec<f64> = generate_random_matrix(m, n, 3);
+    let expected: Vec<f64> = native_matmul(&a, (m, k), &b, (k, n))
+        .iter()
+        .zip(&c)
+        .map(|(ab, c)| 2.0 * ab - c)
+        .collect();
+
+    let a_t = row_major_to_col_major(&a, m, k);
+    unsafe {
+        ll_gemm_jit(
+            Trans::Yes,
+            Trans::No,
+            2.0,
+            &a_t,
+            (k, m),
+            &b,
+            (k, n),
+            -1.0,
+            &mut c,
+            None,
+        )
+    };
+    assert_vec_eq(&c, &expected, 1e-6);
+}
+
+#[test]
+fn test_try_ll_matmul_jit_with_template_f64_float_only_template() {
+    let template = "define void @ll_matmul_cpu_jit(float* %a, float* %b, float* %result) {\nentry:\n  ; {M} {N} {K}\n  ret void\n}";
+    let a: [f64; 4] = [1., 2., 3., 4.];
+    let result = unsafe { try_ll_matmul_jit_with_template(&a, (2, 2), &a, (2, 2), Some(template)) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::ElemTypeUnsupported { elem: "double" })
+    ));
+}
+
+// Tests for half-precision storage
+fn to_half_bits(format: HalfFormat, v: f32) -> u16 {
+    match format {
+        HalfFormat::F16 => f16::from_f32(v).to_bits(),
+        HalfFormat::Bf16 => bf16::from_f32(v).to_bits(),
+    }
+}
+
+fn from_half_bits(format: HalfFormat, bits: u16) -> f32 {
+    match format {
+        HalfFormat::F16 => f16::from_bits(bits).to_f32(),
+        HalfFormat::Bf16 => bf16::from_bits(bits).to_f32(),
+    }
+}
+
+// scaled down so the sums stay well below f16::MAX
+fn half_operand(format: HalfFormat, rows: usize, cols: usize, seed: u64) -> Vec<u16> {
+    generate_random_matrix::<f32>(rows, cols, seed)
+        .iter()
+        .map(|v| to_half_bits(format, v / 64.0))
+        .collect()
+}
+
+fn test_half_vs_native(format: HalfFormat, ir_template: Option<&str>) {
+    let (m, k, n) = (9, 7, 10);
+    let a = half_operand(format, m, k, 1);
+    let b = half_operand(format, k, n, 2);
+    let widen = |x: &[u16]| -> Vec<f32> { x.iter().map(|&v| from_half_bits(format, v)).collect() };
+    // the kernel widens exactly, so the f32 reference sees the same inputs
+    let expected = native_matmul(&widen(&a), (m, k), &widen(&b), (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+
+    let result: Vec<f32> =
+        unsafe { ll_matmul_jit_half(format, &a, (m, k), &b, (k, n), ir_template) };
+    assert_vec_eq(&result, &expected, max * 1e-5);
+
+    // truncated back to `format`, allow one ulp of the input format
+    let ulp = match format {
+        HalfFormat::F16 => 2f32.powi(-10),
+        HalfFormat::Bf16 => 2f32.powi(-7),
+    };
+    let result: Vec<u16> =
+        unsafe { ll_matmul_jit_half(format, &a, (m, k), &b, (k, n), ir_template) };
+    assert_vec_eq(&widen(&result), &expected, max * ulp);
+}
+
+#[test]
+fn test_ll_matmul_jit_half_f16_naive() {
+    test_half_vs_native(HalfFormat::F16, None);
+}
+
+#[test]
+fn test_ll_matmul_jit_half_f16_unrolled() {
+    test_half_vs_native(HalfFormat::F16, Some(UNROLLED_IR_TEMPLATE_HALF_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_half_bf16_naive() {
+    test_half_vs_native(HalfFormat::Bf16, None);
+}
+
+#[test]
+fn test_ll_matmul_jit_half_bf16_unrolled() {
+    test_half_vs_native(HalfFormat::Bf16, Some(UNROLLED_IR_TEMPLATE_HALF_JIT_CPU));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_half_plain_template() {
+    // the f32 template has no {ACC_TY}/{OUT_TY}, it would sum in half precision
+    let a = half_operand(HalfFormat::F16, 2, 2, 1);
+    let result = unsafe {
+        try_ll_matmul_jit_half::<f32>(
+            HalfFormat::F16,
+            &a,
+            (2, 2),
+            &a,
+            (2, 2),
+            Some(UNROLLED_IR_TEMPLATE_JIT_CPU),
+        )
+    };
+    assert!(matches!(
+        result,
+        Err(MatmulError::MixedPrecisionUnsupported {
+            elem: "half",
+            out: "float"
+        })
+    ));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_half_shape_mismatch() {
+    let a = half_operand(HalfFormat::Bf16, 2, 3, 1);
+    let result =
+        unsafe { try_ll_matmul_jit_half::<u16>(HalfFormat::Bf16, &a, (2, 3), &a, (2, 3), None) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+// Tests for integer kernels
+fn int_operand<I: IntScalar>(rows: usize, cols: usize, seed: u64, to_int: fn(f64) -> I) -> Vec<I> {
+    generate_random_matrix::<f64>(rows, cols, seed)
+        .into_iter()
+        .map(to_int)
+        .collect()
+}
+
+fn test_int_vs_native<I: IntScalar>(to_int: fn(f64) -> I) {
+    let (m, k, n) = (9, 7, 10);
+    let a = int_operand(m, k, 1, to_int);
+    let b = int_operand(k, n, 2, to_int);
+    for overflow in [Overflow::Wrapping, Overflow::Checked] {
+        let expected = native_matmul_int(&a, (m, k), &b, (k, n), overflow)
+            .expect("operands are small enough not to overflow");
+        let result = unsafe { ll_matmul_jit_int(overflow, &a, (m, k), &b, (k, n), None) };
+        assert_eq!(result, expected, "{:?}", overflow);
+    }
+}
+
+#[test]
+fn test_ll_matmul_jit_int_i8() {
+    test_int_vs_native(|v| (v as i32 - 128) as i8);
+}
+
+#[test]
+fn test_ll_matmul_jit_int_u8() {
+    // above i8::MAX, so a sign extension would show up
+    test_int_vs_native(|v| v as u8);
+}
+
+#[test]
+fn test_ll_matmul_jit_int_i32() {
+    test_int_vs_native(|v| (v as i32 - 128) * 10);
+}
+
+#[test]
+fn test_ll_matmul_jit_int_i64() {
+    // the products don't fit in an i32
+    test_int_vs_native(|v| (v as i64 - 128) * 1_000_000);
+}
+
+#[test]
+fn test_ll_matmul_jit_int_wrapping_overflow() {
+    let a: [i32; 4] = [i32::MAX, 2, 3, 4];
+    let b: [i32; 4] = [2, 0, 1, 1];
+    let expected = native_matmul_int(&a, (2, 2), &b, (2, 2), Overflow::Wrapping).unwrap();
+    assert_eq!(expected[0], i32::MAX.wrapping_mul(2).wrapping_add(2));
+    let result = unsafe { ll_matmul_jit_int(Overflow::Wrapping, &a, (2, 2), &b, (2, 2), None) };
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_try_ll_matmul_jit_int_checked_overflow() {
+    // the product overflows for i32, the sum for i64
+    let a: [i32; 4] = [i32::MAX, 2, 3, 4];
+    let b: [i32; 4] = [2, 0, 1, 1];
+    assert!(native_matmul_int(&a, (2, 2), &b, (2, 2), Overflow::Checked).is_none());
+    let result = unsafe { try_ll_matmul_jit_int(Overflow::Checked, &a, (2, 2), &b, (2, 2), None) };
+    assert!(matches!(result, Err(MatmulError::IntegerOverflow)));
+
+    let a: [i64; 4] = [i64::MAX, 1, 3, 4];
+    let b: [i64; 4] = [1, 0, 1, 1];
+    assert!(native_matmul_int(&a, (2, 2), &b, (2, 2), Overflow::Checked).is_none());
+    let result = unsafe { try_ll_matmul_jit_int(Overflow::Checked, &a, (2, 2), &b, (2, 2), None) };
+    assert!(matches!(result, Err(MatmulError::IntegerOverflow)));
+}
+
+#[test]
+fn test_try_ll_matmul_jit_int_shape_mismatch() {
+    let a: [i8; 6] = [1, 2, 3, 4, 5, 6];
+    let result = unsafe { try_ll_matmul_jit_int(Overflow::Wrapping, &a, (2, 3), &a, (2, 3), None) };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+// Tests for batched matmul
+fn test_batched_vs_native(ir_template: Option<&str>) {
+    let (m, k, n) = (9, 7, 10);
+    let batch = 5;
+    let a: Vec<f32> = generate_random_matrix(batch * m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(batch * k, n, 2);
+    let mut out = vec![0.0f32; batch * m * n];
+
+    unsafe {
+        ll_matmul_jit_batched(
+            &a,
+            &b,
+            batch,
+            ((m, k), (k, n)),
+            &mut out,
+            BatchLayout::Contiguous,
+            ir_template,
+        )
+    };
+
+    for i in 0..batch {
+        let expected = native_matmul(
+            &a[i * m * k..(i + 1) * m * k],
+            (m, k),
+            &b[i * k * n..(i + 1) * k * n],
+            (k, n),
+        );
+        let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+        assert_vec_eq(&out[i * m * n..(i + 1) * m * n], &expected, max * 1e-5);
+    }
+}
+
+#[test]
+fn test_ll_matmul_jit_batched_naive() {
+    test_batched_vs_native(None);
+}
+
+#[test]
+fn test_ll_matmul_jit_batched_unrolled() {
+    test_batched_vs_native(Some(UNROLLED_IR_TEMPLATE_BATCHED_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_batched_strided() {
+    // padded a and out items, the same b for every item
+    let (m, k, n) = (3, 4, 5);
+    let batch = 4;
+    let (a_stride, out_stride) = (m * k + 2, m * n + 3);
+    let a: Vec<f32> = generate_random_matrix(batch, a_stride, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let mut out = vec![f32::NAN; (batch - 1) * out_stride + m * n];
+
+    unsafe {
+        ll_matmul_jit_batched(
+            &a,
+            &b,
+            batch,
+            ((m, k), (k, n)),
+            &mut out,
+            BatchLayout::Strided {
+                a: a_stride,
+                b: 0,
+                out: out_stride,
+            },
+            None,
+        )
+    };
+
+    for i in 0..batch {
+        let item = &a[i * a_stride..i * a_stride + m * k];
+        let expected = native_matmul(item, (m, k), &b, (k, n));
+        let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+        let result = &out[i * out_stride..i * out_stride + m * n];
+        assert_vec_eq(result, &expected, max * 1e-5);
+        // the padding between items is left alone
+        if i + 1 < batch {
+            assert!(
+                out[i * out_stride + m * n..(i + 1) * out_stride]
+                    .iter()
+                    .all(|v| v.is_nan())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_ll_matmul_jit_batched_empty_batch() {
+    let a: [f32; 0] = [];
+    let mut out: [f32; 0] = [];
+    unsafe {
+        ll_matmul_jit_batched(
+            &a,
+            &a,
+            0,
+            ((2, 2), (2, 2)),
+            &mut out,
+            BatchLayout::Contiguous,
+            None,
+        )
+    };
+}
+
+#[test]
+fn test_try_ll_matmul_jit_batched_shape_mismatch() {
+    let a = [1.0f32; 8];
+    let mut out = [0.0f32; 8];
+    // 3 items of 2x2 don't fit in 8 elements
+    let result = unsafe {
+        try_ll_matmul_jit_batched(
+            &a,
+            &a,
+            3,
+            ((2, 2), (2, 2)),
+            &mut out,
+            BatchLayout::Contiguous,
+            None,
+        )
+    };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+
+    // items written on top of each other
+    let result = unsafe {
+        try_ll_matmul_jit_batched(
+            &a,
+            &a,
+            2,
+            ((2, 2), (2, 2)),
+            &mut out,
+            BatchLayout::Strided { a: 4, b: 4, out: 2 },
+            None,
+        )
+    };
+    assert!(matches!(result, Err(MatmulError::ShapeMismatch { .. })));
+}
+
+// Tests for the tiled template
+fn test_tiled_vs_native<T: Scalar>((m, k, n): (usize, usize, usize), ir_template: Option<&str>) {
+    let a: Vec<T> = generate_random_matrix(m, k, 1);
+    let b: Vec<T> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected
+        .iter()
+        .fold(0f64, |acc, v| acc.max(v.to_f64().abs()));
+
+    let result = unsafe { ll_matmul_jit_with_template(&a, (m, k), &b, (k, n), ir_template) };
+    assert_vec_eq(&result, &expected, T::from_f64(max * 1e-5));
+}
+
+#[test]
+fn test_ll_matmul_jit_tiled() {
+    test_tiled_vs_native::<f32>((40, 24, 16), Some(TILED_IR_TEMPLATE_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_tiled_odd_shape() {
+    // no power of two divides 9 and 7, the tiles shrink to 1
+    test_tiled_vs_native::<f32>((9, 7, 10), Some(TILED_IR_TEMPLATE_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_tiled_f64() {
+    test_tiled_vs_native::<f64>((64, 48, 40), Some(TILED_IR_TEMPLATE_JIT_CPU));
+}
+
+#[test]
+fn test_ll_matmul_jit_default_past_naive_threshold() {
+    // the naive template would blow up here, the default switches to the tiled one
+    let dim = NAIVE_TEMPLATE_MAX_DIM * 4;
+    test_tiled_vs_native::<f32>((dim, dim, dim), None);
+}
+
+// Tests for the shape-generic kernel
+fn test_dynamic_vs_native<T: Scalar>(layout: MatrixLayout) {
+    let kernel = unsafe { compile_matmul_jit_dynamic::<T>(None, layout) }
+        .expect("Failed to compile the dynamic kernel");
+    // one kernel for every shape, including the remainder of the 8-wide loop
+    for (m, k, n) in [(1, 1, 1), (2, 3, 4), (9, 7, 10), (16, 5, 3), (33, 17, 40)] {
+        let a: Vec<T> = generate_random_matrix(m, k, 1);
+        let b: Vec<T> = generate_random_matrix(k, n, 2);
+        let expected = native_matmul(&a, (m, k), &b, (k, n));
+        let max = expected
+            .iter()
+            .fold(0f64, |acc, v| acc.max(v.to_f64().abs()));
+
+        let (a_in, b_in) = match layout {
+            MatrixLayout::RowMajor => (a.clone(), b.clone()),
+            MatrixLayout::ColumnMajor => (
+                row_major_to_col_major(&a, m, k),
+                row_major_to_col_major(&b, k, n),
+            ),
+        };
+        let mut result = vec![T::ZERO; m * n];
+        unsafe { kernel.call(a_in.as_ptr(), b_in.as_ptr(), result.as_mut_ptr(), (m, n, k)) };
+        if layout == MatrixLayout::ColumnMajor {
+            result = col_major_to_row_major(&result, m, n);
+        }
+        assert_vec_eq(&result, &expected, T::from_f64(max * 1e-5));
+    }
+}
+
+#[test]
+fn test_compile_matmul_jit_dynamic_row_major() {
+    test_dynamic_vs_native::<f32>(MatrixLayout::RowMajor);
+}
+
+#[test]
+fn test_compile_matmul_jit_dynamic_column_major() {
+    test_dynamic_vs_native::<f32>(MatrixLayout::ColumnMajor);
+}
+
+#[test]
+fn test_compile_matmul_jit_dynamic_f64() {
+    test_dynamic_vs_native::<f64>(MatrixLayout::RowMajor);
+}
+
+#[test]
+fn test_compile_matmul_jit_dynamic_strided() {
+    // multiply the top-left 3x2 and 2x3 blocks of 4x5 matrices into a 3x3 block of a 4x6 one
+    let kernel = unsafe { compile_matmul_jit_dynamic::<f32>(None, MatrixLayout::RowMajor) }
+        .expect("Failed to compile the dynamic kernel");
+    let a: Vec<f32> = (0..20).map(|v| v as f32).collect();
+    let b: Vec<f32> = (0..20).map(|v| (v % 7) as f32).collect();
+    let mut out = vec![f32::NAN; 24];
+    unsafe { kernel.call_strided(a.as_ptr(), 5, b.as_ptr(), 5, out.as_mut_ptr(), 6, (3, 3, 2)) };
+
+    for i in 0..4 {
+        for j in 0..6 {
+            let v = out[i * 6 + j];
+            if i < 3 && j < 3 {
+                let expected: f32 = (0..2).map(|p| a[i * 5 + p] * b[p * 5 + j]).sum();
+                assert_eq!(v, expected, "out[{}][{}]", i, j);
+            } else {
+                assert!(
+                    v.is_nan(),
+                    "out[{}][{}] outside the block was written",
+                    i,
+                    j
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_ll_matmul_jit_into_with_specialization() {
+    // the same shape runs the dynamic kernel, then the specialized one, with the same result
+    let (m, k, n) = (5, 6, 7);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+    for policy in [
+        Specialization::Never,
+        Specialization::AfterCalls(2),
+        Specialization::Always,
+    ] {
+        for _ in 0..3 {
+            let mut out = vec![f32::NAN; m * n];
+            unsafe { ll_matmul_jit_into_with(&a, (m, k), &b, (k, n), &mut out, policy) };
+            assert_vec_eq(&out, &expected, max * 1e-5);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_jit_options_baseline_cpu() {
+    // kernels for the x86-64 baseline (SSE2, no AVX/FMA) still compute the right thing
+    let (m, k, n) = (9, 7, 10);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+
+    // per call, the process default is left to the tests in jit_options_global.rs
+    let options = JitOptions::new()
+        .template(UNROLLED_IR_TEMPLATE_JIT_CPU)
+        .target_cpu("x86-64");
+    let baseline = unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &options) };
+    assert_vec_eq(&baseline.unwrap(), &expected, max * 1e-5);
+}
+
+#[test]
+fn test_ll_matmul_jit_with_options() {
+    let (m, k, n) = (6, 5, 7);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+
+    for options in [
+        JitOptions::new().template(UNROLLED_IR_TEMPLATE_JIT_CPU),
+        JitOptions::new().template_file("src/llvm/matmul_unrolled.tmpl"),
+        JitOptions::new()
+            .opt_level(OptimizationLevel::None)
+            .vectorize(false)
+            .unroll(false),
+        JitOptions::new()
+            .passes("lower-matrix-intrinsics,instcombine,simplifycfg")
+            .verify_each(true),
+    ] {
+        let result = unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &options) };
+        assert_vec_eq(&result.unwrap(), &expected, max * 1e-5);
+    }
+}
+
+fn test_matmul_threads_bitwise<T: Scalar>(eps: f64) {
+    // large enough to be split: 6 blocks of 32 rows and one of 8
+    let (m, k, n) = (200, 130, 97);
+    let a: Vec<T> = generate_random_matrix(m, k, 1);
+    let b: Vec<T> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected
+        .iter()
+        .fold(0f64, |acc, v| acc.max(v.to_f64().abs()));
+    let bits = |v: &[T]| v.iter().map(|x| x.to_f64().to_bits()).collect::<Vec<_>>();
+
+    let options = JitOptions::new().template(TILED_IR_TEMPLATE_JIT_CPU);
+    let single = unsafe {
+        try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &options.clone().threads(1))
+    }
+    .unwrap();
+    assert_vec_eq(&single, &expected, T::from_f64(max * eps));
+    for threads in [2, 3, 7, 64] {
+        let result = unsafe {
+            try_ll_matmul_jit_with_options(
+                &a,
+                (m, k),
+                &b,
+                (k, n),
+                &options.clone().threads(threads),
+            )
+        }
+        .unwrap();
+        assert_eq!(bits(&result), bits(&single), "{threads} threads");
+    }
+
+    // the shape-generic kernel is split the same way
+    let mut out = vec![T::ZERO; m * n];
+    unsafe { ll_matmul_jit_into_with(&a, (m, k), &b, (k, n), &mut out, Specialization::Never) };
+    assert_vec_eq(&out, &expected, T::from_f64(max * eps));
+}
+
+#[test]
+fn test_matmul_threads() {
+    test_matmul_threads_bitwise::<f32>(1e-5);
+    test_matmul_threads_bitwise::<f64>(1e-12);
+}
+
+#[test]
+fn test_matmul_threads_default_template() {
+    // no template: the blocks are past NAIVE_TEMPLATE_MAX_DIM, so the default picks the tiled
+    // template for the 32-row blocks and for the shorter last one alike
+    for (m, k, n, tail) in [(200, 130, 97, 8), (100, 64, 400, 4)] {
+        let a: Vec<f32> = generate_random_matrix(m, k, 1);
+        let b: Vec<f32> = generate_random_matrix(k, n, 2);
+        let expected = native_matmul(&a, (m, k), &b, (k, n));
+        let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+        let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+
+        let run = |options: &JitOptions| {
+            unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), options) }.unwrap()
+        };
+        let single = run(&JitOptions::new().threads(1));
+        assert_vec_eq(&single, &expected, max * 1e-5);
+        assert_eq!(bits(&run(&JitOptions::new())), bits(&single), "{m}x{k}x{n}");
+
+        let shapes: Vec<_> = jit_cache()
+            .stats()
+            .kernels
+            .iter()
+            .map(|kernel| kernel.shape)
+            .collect();
+        assert!(shapes.contains(&(32, n, k)) && shapes.contains(&(tail, n, k)));
+    }
+}
+
+#[test]
+fn test_jit_options_errors() {
+    let options = JitOptions::new().function_name("no_such_function");
+    let result = unsafe {
+        try_compile_matmul_jit_with_options::<f32>(3, 3, 3, MatrixLayout::RowMajor, &options)
+    };
+    assert!(matches!(result, Err(MatmulError::SymbolNotFound { .. })));
+
+    let options = JitOptions::new().passes("no-such-pass");
+    let result = unsafe {
+        try_compile_matmul_jit_with_options::<f32>(3, 3, 3, MatrixLayout::RowMajor, &options)
+    };
+    assert!(matches!(result, Err(MatmulError::PassPipeline(_))));
+
+    let options = JitOptions::new().template_file("no/such/template.tmpl");
+    let result = unsafe {
+        try_compile_matmul_jit_with_options::<f32>(3, 3, 3, MatrixLayout::RowMajor, &options)
+    };
+    assert!(matches!(result, Err(MatmulError::TemplateIo { .. })));
+}
+
+#[repr(C, align(32))]
+struct Aligned<const N: usize>([f32; N]);
+
+#[test]
+fn test_template_header() {
+    // n = 8: the row-major kernel's M, the unrolled template needs 32-byte columns
+    let (m, k, n) = (3, 5, 8);
+    let a: Vec<f32> = generate_random_matrix(m, k, 1);
+    let b: Vec<f32> = generate_random_matrix(k, n, 2);
+    let expected = native_matmul(&a, (m, k), &b, (k, n));
+    let max = expected.iter().fold(0f32, |acc, v| acc.max(v.abs()));
+
+    let unrolled = JitOptions::new().template(UNROLLED_IR_TEMPLATE_JIT_CPU);
+    let entry = unsafe {
+        try_compile_matmul_jit_with_options::<f32>(m, n, k, MatrixLayout::RowMajor, &unrolled)
+    }
+    .unwrap();
+    assert_eq!(entry.align, 32);
+    let (mut a_buf, mut b_buf, mut out) = (Aligned([0.; 16]), Aligned([0.; 48]), Aligned([0.; 24]));
+    a_buf.0[..m * k].copy_from_slice(&a);
+    b_buf.0[..k * n].copy_from_slice(&b);
+    unsafe { entry.call(a_buf.0.as_ptr(), b_buf.0.as_ptr(), out.0.as_mut_ptr()) };
+    assert_vec_eq(&out.0, &expected, max * 1e-5);
+
+    // misaligned `a`: the unrolled template falls back to the default one
+    a_buf.0[1..=m * k].copy_from_slice(&a);
+    let misaligned = &a_buf.0[1..=m * k];
+    let result =
+        unsafe { try_ll_matmul_jit_with_options(misaligned, (m, k), &b, (k, n), &unrolled) };
+    assert_vec_eq(&result.unwrap(), &expected, max * 1e-5);
+    // a shape it doesn't support too
+    let result = unsafe { try_ll_matmul_jit_with_options(&b, (n, k), &a, (k, m), &unrolled) };
+    assert_vec_eq(
+        &result.unwrap(),
+        &native_matmul(&b, (n, k), &a, (k, m)),
+        max * 1e-5,
+    );
+
+    // without `fallback: default` both are errors, and the entry point comes from the header
+    let strict = JitOptions::new().template(
+        ";; entry: my_matmul\n;; align: 32\n;; require: M % 8 == 0\n\
+         define void @my_matmul(float* %a, float* %b, float* %result) {\n\
+         entry:\n  ; {M} {N} {K}\n  ret void\n}",
+    );
+    let result = unsafe { try_ll_matmul_jit_with_options(misaligned, (m, k), &b, (k, n), &strict) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::MisalignedOperand { align: 32 })
+    ));
+    let result = unsafe { try_ll_matmul_jit_with_options(&b, (n, k), &a, (k, m), &strict) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TemplateRequirement {
+            shape: (3, 8, 5),
+            ..
+        })
+    ));
+    let entry = unsafe {
+        try_compile_matmul_jit_with_options::<f32>(m, n, k, MatrixLayout::RowMajor, &strict)
+    };
+    assert_eq!(entry.unwrap().align, 32);
+
+    let gemm = JitOptions::new().template(UNROLLED_IR_TEMPLATE_GEMM_JIT_CPU);
+    let result = unsafe { try_ll_matmul_jit_with_options(&a, (m, k), &b, (k, n), &gemm) };
+    assert!(matches!(
+        result,
+        Err(MatmulError::TemplateAbiMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_inspect_matmul_jit() {
+    let inspection =
+        inspect_matmul_jit::<f32>(4, 3, 2, MatrixLayout::RowMajor, &JitOptions::new()).unwrap();
+    // row major: instantiated as the column-major (n, m, k) kernel
+    assert!(
+        inspection
+            .instantiated_ir
+            .contains("llvm.matrix.multiply.v12f32.v6f32.v8f32")
+    );
+    // the intrinsic declarations stay, the calls are gone
+    assert!(
+        !inspection
+            .lowered_ir
+            .contains("call <12 x float> @llvm.matrix.multiply")
+    );
+    // the default pipeline is the lowering alone
+    assert_eq!(inspection.lowered_ir, inspection.optimized_ir);
+    assert!(inspection.assembly.contains("ll_matmul_cpu_jit"));
+
+    let options = JitOptions::new()
+        .template(UNROLLED_IR_TEMPLATE_JIT_CPU)
+        .passes("lower-matrix-intrinsics,instcombine,simplifycfg");
+    let unrolled = inspect_matmul_jit::<f64>(4, 3, 2, MatrixLayout::RowMajor, &options).unwrap();
+    assert!(unrolled.instantiated_ir.contains("double"));
+    assert_ne!(unrolled.instantiated_ir, inspection.instantiated_ir);
+    assert!(!unrolled.assembly.is_empty());
+}